@@ -0,0 +1,359 @@
+use crate::{
+   create_output, DecryptingReader, DecryptionKeys, Encryptor, Overwrite, PrecryptError,
+   RecryptionKeys, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use umbral_pre::*;
+
+// Last bytes of an archive's plaintext, right after the length of the index
+const ARCHIVE_MAGIC: &[u8; 8] = b"PCARCHIV";
+// index length u64 + magic
+const TRAILER_LEN: u64 = 16;
+
+/// A file or directory stored in an archive.
+///
+/// The plaintext of an archive is the contents of every file back to back, followed by
+/// the index of entries and a trailer:
+///
+/// ```text
+/// contents | index (CBOR) | index length u64 | magic "PCARCHIV"
+/// ```
+///
+/// The index is encrypted along with the contents, so the paths are as private as the files.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveEntry {
+   /// Path relative to the archived directory, with `/` separators.
+   pub path: String,
+   pub directory: bool,
+   /// Length of the contents, zero for directories.
+   pub size: u64,
+   /// Unix permission bits.
+   pub permissions: u32,
+   /// Position of the contents in the archive's plaintext.
+   pub offset: u64,
+   /// First chunk holding the contents.
+   pub first_chunk: usize,
+   /// Number of chunks the contents span, zero for empty files and directories.
+   pub chunk_count: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveIndex {
+   entries: Vec<ArchiveEntry>,
+}
+
+impl Encryptor<'_> {
+   /// Encrypts every file and directory under `input_dir` into a container at `output_file`.
+   ///
+   /// The result is a single ciphertext, so one grant gives access to the whole archive.
+   /// Open it with `Archive` to extract everything or a single entry.
+   pub fn encrypt_archive(
+      &mut self,
+      input_dir: &str,
+      owner_secret: SecretKey,
+      output_file: &str,
+   ) -> Result<RecryptionKeys> {
+      self.encrypt_archive_from(input_dir, owner_secret, output_file, false)
+   }
+
+   /// Continues an interrupted `encrypt_archive` from its last checkpoint.
+   pub fn resume_archive(
+      &mut self,
+      input_dir: &str,
+      owner_secret: SecretKey,
+      output_file: &str,
+   ) -> Result<RecryptionKeys> {
+      self.encrypt_archive_from(input_dir, owner_secret, output_file, true)
+   }
+
+   fn encrypt_archive_from(
+      &mut self,
+      input_dir: &str,
+      owner_secret: SecretKey,
+      output_file: &str,
+      resume: bool,
+   ) -> Result<RecryptionKeys> {
+      let root = Path::new(input_dir);
+      let mut entries = Vec::new();
+      let mut contents_len = 0;
+      collect_entries(root, root, &mut entries, &mut contents_len)?;
+      let chunk_size = self.chunk_size() as u64;
+      for entry in &mut entries {
+         entry.first_chunk = (entry.offset / chunk_size) as usize;
+         if entry.size > 0 {
+            let last_chunk = ((entry.offset + entry.size - 1) / chunk_size) as usize;
+            entry.chunk_count = last_chunk - entry.first_chunk + 1;
+         }
+      }
+
+      let mut trailer = Vec::new();
+      let index = ArchiveIndex { entries };
+      ciborium::ser::into_writer(&index, &mut trailer).map_err(|err| invalid(&err.to_string()))?;
+      trailer.extend_from_slice(&(trailer.len() as u64).to_le_bytes());
+      trailer.extend_from_slice(ARCHIVE_MAGIC);
+      let input_len = contents_len + trailer.len() as u64;
+      let input = ArchiveReader {
+         root: root.to_path_buf(),
+         entries: index.entries.into_iter(),
+         current: None,
+         trailer: Cursor::new(trailer),
+      };
+      let owner_public = owner_secret.public_key();
+      let manifest = self.encrypt_input(
+         input,
+         input_len,
+         &owner_public,
+         Some(&owner_secret),
+         output_file,
+         resume,
+      )?;
+      RecryptionKeys::from_manifest(&manifest, &owner_secret)
+   }
+}
+
+// Lists everything under `dir`, every directory followed by its contents in name order
+fn collect_entries(
+   root: &Path,
+   dir: &Path,
+   entries: &mut Vec<ArchiveEntry>,
+   offset: &mut u64,
+) -> Result<()> {
+   let mut children = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+   children.sort_by_key(|child| child.file_name());
+   for child in children {
+      let path = child.path();
+      let metadata = fs::symlink_metadata(&path)?;
+      let name = relative_path(root, &path)?;
+      if !metadata.is_dir() && !metadata.is_file() {
+         return Err(invalid(&format!("{} is neither a file nor a directory", name)));
+      }
+      let size = match metadata.is_file() {
+         true => metadata.len(),
+         false => 0,
+      };
+      entries.push(ArchiveEntry {
+         path: name,
+         directory: metadata.is_dir(),
+         size,
+         permissions: permissions(&metadata),
+         offset: *offset,
+         first_chunk: 0,
+         chunk_count: 0,
+      });
+      *offset += size;
+      if metadata.is_dir() {
+         collect_entries(root, &path, entries, offset)?;
+      }
+   }
+   Ok(())
+}
+
+fn relative_path(root: &Path, path: &Path) -> Result<String> {
+   let relative = path.strip_prefix(root).unwrap();
+   let names = relative
+      .components()
+      .map(|component| component.as_os_str().to_str())
+      .collect::<Option<Vec<_>>>();
+   match names {
+      Some(names) => Ok(names.join("/")),
+      None => Err(invalid(&format!("{} is not a UTF-8 path", path.display()))),
+   }
+}
+
+// Reads the contents of every file in index order, then the index and trailer
+struct ArchiveReader {
+   root: PathBuf,
+   entries: std::vec::IntoIter<ArchiveEntry>,
+   current: Option<(io::Take<File>, String)>,
+   trailer: Cursor<Vec<u8>>,
+}
+
+impl Read for ArchiveReader {
+   fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      loop {
+         if let Some((file, path)) = &mut self.current {
+            let read = file.read(buf)?;
+            if read > 0 || buf.is_empty() {
+               return Ok(read);
+            }
+            // The index already holds the sizes, so a file must not shrink meanwhile
+            if file.limit() > 0 {
+               return Err(io::Error::new(
+                  io::ErrorKind::UnexpectedEof,
+                  format!("{} got shorter while it was encrypted", path),
+               ));
+            }
+            self.current = None;
+         }
+         match self.entries.next() {
+            Some(entry) if entry.directory => continue,
+            Some(entry) => {
+               let file = File::open(self.root.join(&entry.path))?;
+               self.current = Some((file.take(entry.size), entry.path));
+            }
+            None => return self.trailer.read(buf),
+         }
+      }
+   }
+}
+
+/// An encrypted archive, whose entries are extracted by decrypting only the chunks they span.
+pub struct Archive<R: Read + Seek> {
+   reader: DecryptingReader<R>,
+   entries: Vec<ArchiveEntry>,
+}
+
+impl Archive<File> {
+   /// Opens the archive at `input_path`, reading its index.
+   pub fn open(
+      input_path: &str,
+      receiver_key: SecretKey,
+      decryption_keys: impl Into<Arc<DecryptionKeys>>,
+   ) -> Result<Self> {
+      Archive::new(File::open(input_path)?, receiver_key, decryption_keys)
+   }
+}
+
+impl<R: Read + Seek> Archive<R> {
+   pub fn new(
+      input: R,
+      receiver_key: SecretKey,
+      decryption_keys: impl Into<Arc<DecryptionKeys>>,
+   ) -> Result<Self> {
+      let decryption_keys = decryption_keys.into();
+      // The index is at the end, past any part of the archive granted on its own
+      if decryption_keys.is_partial() {
+         return Err(invalid("keys only grant part of the archive"));
+      }
+      let mut reader = DecryptingReader::new(input, receiver_key, decryption_keys)?;
+      let plaintext_len = reader.plaintext_len();
+      if plaintext_len < TRAILER_LEN {
+         return Err(invalid("ciphertext is too short to be an archive"));
+      }
+      let mut trailer = [0u8; TRAILER_LEN as usize];
+      reader.seek(SeekFrom::Start(plaintext_len - TRAILER_LEN))?;
+      reader.read_exact(&mut trailer)?;
+      if &trailer[8..] != ARCHIVE_MAGIC {
+         return Err(invalid("ciphertext is not an archive"));
+      }
+      let index_len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+      let contents_len = (plaintext_len - TRAILER_LEN)
+         .checked_sub(index_len)
+         .ok_or_else(|| invalid("index is longer than the archive"))?;
+
+      reader.seek(SeekFrom::Start(contents_len))?;
+      let index: ArchiveIndex = ciborium::de::from_reader((&mut reader).take(index_len))
+         .map_err(|err| invalid(&err.to_string()))?;
+      for entry in &index.entries {
+         check_path(&entry.path)?;
+         if entry.offset.checked_add(entry.size).is_none_or(|end| end > contents_len) {
+            return Err(invalid(&format!("{} lies outside the archive", entry.path)));
+         }
+      }
+      Ok(Archive {
+         reader,
+         entries: index.entries,
+      })
+   }
+
+   /// Every file and directory, each directory before its contents.
+   pub fn entries(&self) -> &[ArchiveEntry] {
+      &self.entries
+   }
+
+   /// Writes the contents of the file at `path` to `output`.
+   pub fn extract_entry<W: Write>(&mut self, path: &str, mut output: W) -> Result<()> {
+      let entry = self
+         .entries
+         .iter()
+         .find(|entry| entry.path == path && !entry.directory)
+         .cloned()
+         .ok_or_else(|| invalid(&format!("no file {} in the archive", path)))?;
+      self.copy_contents(&entry, &mut output)?;
+      output.flush()?;
+      Ok(())
+   }
+
+   /// Recreates every file and directory of the archive under `output_dir`.
+   pub fn extract_all(&mut self, output_dir: &str, overwrite: Overwrite) -> Result<()> {
+      let root = Path::new(output_dir);
+      fs::create_dir_all(root)?;
+      for entry in self.entries.clone() {
+         let path = root.join(&entry.path);
+         if entry.directory {
+            fs::create_dir_all(&path)?;
+            continue;
+         }
+         if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+         }
+         let mut output = create_output(&path, overwrite)?;
+         self.copy_contents(&entry, &mut output)?;
+         output.flush()?;
+         set_permissions(&path, entry.permissions)?;
+      }
+      // Innermost first, so a read-only directory doesn't stop its contents being written
+      for entry in self.entries.iter().rev().filter(|entry| entry.directory) {
+         set_permissions(&root.join(&entry.path), entry.permissions)?;
+      }
+      Ok(())
+   }
+
+   fn copy_contents<W: Write>(&mut self, entry: &ArchiveEntry, output: &mut W) -> Result<()> {
+      self.reader.seek(SeekFrom::Start(entry.offset))?;
+      io::copy(&mut (&mut self.reader).take(entry.size), output)?;
+      Ok(())
+   }
+}
+
+// Paths come from the ciphertext, so they must not lead out of the output directory
+fn check_path(path: &str) -> Result<()> {
+   let mut components = Path::new(path).components().peekable();
+   let relative = components.peek().is_some()
+      && components.all(|component| matches!(component, Component::Normal(_)));
+   match relative {
+      true => Ok(()),
+      false => Err(invalid(&format!("entry path {} is not a relative path", path))),
+   }
+}
+
+#[cfg(unix)]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+   use std::os::unix::fs::PermissionsExt;
+   metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn permissions(metadata: &fs::Metadata) -> u32 {
+   match (metadata.is_dir(), metadata.permissions().readonly()) {
+      (true, _) => 0o755,
+      (false, true) => 0o444,
+      (false, false) => 0o644,
+   }
+}
+
+// Setuid, setgid and sticky bits from the ciphertext are never restored
+#[cfg(unix)]
+fn set_permissions(path: &Path, permissions: u32) -> Result<()> {
+   use std::os::unix::fs::PermissionsExt;
+   fs::set_permissions(path, fs::Permissions::from_mode(permissions & 0o777))?;
+   Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_permissions(path: &Path, permissions: u32) -> Result<()> {
+   let mut current = fs::metadata(path)?.permissions();
+   current.set_readonly(permissions & 0o222 == 0);
+   fs::set_permissions(path, current)?;
+   Ok(())
+}
+
+fn invalid(msg: &str) -> PrecryptError {
+   PrecryptError::InvalidArchive(msg.to_string())
+}
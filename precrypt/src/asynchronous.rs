@@ -0,0 +1,174 @@
+use crate::{DecryptionKeys, Decryptor, Encryptor, PrecryptError, RecryptionKeys, Result};
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use umbral_pre::*;
+use zeroize::Zeroizing;
+
+// Bytes read from the async input at once
+const READ_SIZE: usize = 1 << 16;
+// Reads and writes buffered between the async side and the blocking side
+const BUFFERED: usize = 16;
+
+// Plaintext passes through the channels in one direction or the other, so every buffer
+// is wiped once it's been handed on
+type Buffer = Zeroizing<Vec<u8>>;
+
+impl Encryptor<'static> {
+   /// Encrypts everything read from `input` like `encrypt_stream`, without blocking the runtime.
+   ///
+   /// The chunks are encrypted on tokio's blocking pool while this future reads `input` and
+   /// writes `output`. Dropping the future cancels the encryption: the blocking side stops
+   /// at its next read or write.
+   ///
+   /// The keys can be shared with other decryptions through an `Arc`.
+   pub async fn encrypt_async<R, W>(
+      mut self,
+      input: R,
+      owner_secret: SecretKey,
+      output: W,
+   ) -> Result<RecryptionKeys>
+   where
+      R: AsyncRead + Unpin,
+      W: AsyncWrite + Unpin,
+   {
+      run_blocking(input, output, move |reader, writer| {
+         self.encrypt_stream(reader, owner_secret, writer)
+      })
+      .await
+   }
+}
+
+impl Decryptor<'static> {
+   /// Decrypts the ciphertext read from `input` like `decrypt_stream`, without blocking the
+   /// runtime.
+   ///
+   /// The chunks are decrypted on tokio's blocking pool while this future reads `input` and
+   /// writes `output`. Dropping the future cancels the decryption: the blocking side stops
+   /// at its next read or write.
+   ///
+   /// The keys can be shared with other decryptions through an `Arc`.
+   pub async fn decrypt_async<R, W>(
+      mut self,
+      input: R,
+      output: W,
+      receiver_key: SecretKey,
+      decryption_keys: impl Into<Arc<DecryptionKeys>>,
+   ) -> Result<()>
+   where
+      R: AsyncRead + Unpin,
+      W: AsyncWrite + Unpin,
+   {
+      let decryption_keys = decryption_keys.into();
+      run_blocking(input, output, move |reader, writer| {
+         self.decrypt_stream(reader, writer, receiver_key, &decryption_keys)
+      })
+      .await
+   }
+}
+
+// Runs `job` on the blocking pool, feeding it `input` and copying what it writes to `output`
+async fn run_blocking<R, W, T, F>(mut input: R, mut output: W, job: F) -> Result<T>
+where
+   R: AsyncRead + Unpin,
+   W: AsyncWrite + Unpin,
+   T: Send + 'static,
+   F: FnOnce(ChannelReader, ChannelWriter) -> Result<T> + Send + 'static,
+{
+   let (input_tx, input_rx) = mpsc::channel::<io::Result<Buffer>>(BUFFERED);
+   let (output_tx, mut output_rx) = mpsc::channel::<Buffer>(BUFFERED);
+   let task = tokio::task::spawn_blocking(move || {
+      let reader = ChannelReader {
+         chunks: input_rx,
+         current: Buffer::default(),
+         position: 0,
+         ended: false,
+      };
+      job(reader, ChannelWriter { chunks: output_tx })
+   });
+
+   let feed = async move {
+      loop {
+         let mut buffer = Buffer::new(vec![0u8; READ_SIZE]);
+         let read = match input.read(&mut buffer).await {
+            Ok(read) => read,
+            Err(err) => {
+               let _ = input_tx.send(Err(err)).await;
+               return;
+            }
+         };
+         buffer.truncate(read);
+         // An empty read marks the end of the input, the job may also have stopped early
+         if input_tx.send(Ok(buffer)).await.is_err() || read == 0 {
+            return;
+         }
+      }
+   };
+   let drain = async move {
+      while let Some(bytes) = output_rx.recv().await {
+         output.write_all(&bytes).await?;
+      }
+      output.flush().await
+   };
+   let ((), drained, finished) = tokio::join!(feed, drain, task);
+
+   // A failed write makes the job fail too, report the write itself
+   drained?;
+   match finished {
+      Ok(result) => result,
+      Err(err) => Err(PrecryptError::Io(io::Error::other(err))),
+   }
+}
+
+// Reads the bytes the async side sends, the input ends at the first empty read
+struct ChannelReader {
+   chunks: mpsc::Receiver<io::Result<Buffer>>,
+   current: Buffer,
+   position: usize,
+   ended: bool,
+}
+
+impl Read for ChannelReader {
+   fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      while self.position == self.current.len() && !self.ended {
+         match self.chunks.blocking_recv() {
+            Some(Ok(chunk)) => {
+               self.ended = chunk.is_empty();
+               self.current = chunk;
+               self.position = 0;
+            }
+            Some(Err(err)) => return Err(err),
+            // Closing without an empty read means the future was dropped
+            None => return Err(cancelled()),
+         }
+      }
+      let available = &self.current[self.position..];
+      let read = available.len().min(buf.len());
+      buf[..read].copy_from_slice(&available[..read]);
+      self.position += read;
+      Ok(read)
+   }
+}
+
+// Sends everything written to the async side
+struct ChannelWriter {
+   chunks: mpsc::Sender<Buffer>,
+}
+
+impl Write for ChannelWriter {
+   fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.chunks.blocking_send(Buffer::new(buf.to_vec())).map_err(|_| cancelled())?;
+      Ok(buf.len())
+   }
+
+   fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+   }
+}
+
+fn cancelled() -> io::Error {
+   io::Error::new(io::ErrorKind::BrokenPipe, "async encryption or decryption was dropped")
+}
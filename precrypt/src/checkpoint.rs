@@ -0,0 +1,284 @@
+use crate::file_key::FileKey;
+use crate::manifest::{hash, PlaintextHash};
+use crate::{parse_capsule, read_chunk, CapsuleMode, DecryptionKeys, PrecryptError, Progress, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use umbral_pre::*;
+
+// Saving the capsules of every chunk gets slow for many small chunks, so batches
+// finishing quicker than this are only saved together
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far an encryption or decryption got, saved next to its output after every batch.
+///
+/// Only whole chunks are recorded, so the output is always exactly as long as the
+/// chunks written so far and anything after that is left over from an interrupted batch.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+   // Size of the input when the run started, a changed input can't be resumed
+   pub input_len: u64,
+   // Size of every encrypted chunk
+   pub chunk_size: usize,
+   // Chunks fully written to the output
+   pub chunks: usize,
+   // Encrypting: capsule of every chunk written, or the file's only capsule
+   #[serde(default, with = "crate::encoding::byte_list")]
+   pub capsules: Vec<Vec<u8>>,
+   // Encrypting: hash of every chunk written, for the manifest
+   #[serde(default, with = "crate::encoding::byte_list")]
+   pub chunk_hashes: Vec<Vec<u8>>,
+   // Encrypting: file key sealed to the owner, for files with a single capsule
+   #[serde(
+      default,
+      skip_serializing_if = "Option::is_none",
+      with = "crate::encoding::optional_bytes"
+   )]
+   pub sealed_file_key: Option<Vec<u8>>,
+   // Decrypting: hash of the keys' capsules, so other keys can't resume
+   #[serde(default, with = "crate::encoding::bytes")]
+   pub keys_hash: Vec<u8>,
+   // Rebuilt from the plaintext written or read so far when resuming
+   #[serde(skip)]
+   pub plaintext_hash: PlaintextHash,
+   // Encrypting: key of the single capsule, never saved so it has to be opened again to resume
+   #[serde(skip)]
+   pub file_key: Option<FileKey>,
+}
+
+impl Checkpoint {
+   pub fn new_encryption(
+      input_len: u64,
+      chunk_size: usize,
+      mode: CapsuleMode,
+      owner_pubkey: &PublicKey,
+   ) -> Result<Checkpoint> {
+      let (capsules, sealed_file_key, file_key) = match mode {
+         CapsuleMode::PerChunk => (Vec::new(), None, None),
+         CapsuleMode::PerFile => {
+            let file_key = FileKey::random()?;
+            let (capsule, sealed_file_key) = file_key.seal(owner_pubkey)?;
+            (vec![capsule.to_array().to_vec()], Some(sealed_file_key), Some(file_key))
+         }
+      };
+      Ok(Checkpoint {
+         input_len,
+         chunk_size,
+         chunks: 0,
+         capsules,
+         chunk_hashes: Vec::new(),
+         sealed_file_key,
+         keys_hash: Vec::new(),
+         plaintext_hash: PlaintextHash::default(),
+         file_key,
+      })
+   }
+
+   pub fn new_decryption(input_len: u64, decryption_keys: &DecryptionKeys) -> Checkpoint {
+      Checkpoint {
+         input_len,
+         chunk_size: decryption_keys.chunk_size,
+         chunks: 0,
+         capsules: Vec::new(),
+         chunk_hashes: Vec::new(),
+         sealed_file_key: None,
+         keys_hash: hash(&decryption_keys.capsules.concat()),
+         plaintext_hash: PlaintextHash::new(decryption_keys.segment_starts()),
+         file_key: None,
+      }
+   }
+
+   pub fn mode(&self) -> CapsuleMode {
+      match self.sealed_file_key {
+         Some(_) => CapsuleMode::PerFile,
+         None => CapsuleMode::PerChunk,
+      }
+   }
+
+   /// Reads the checkpoint saved at `path`, if there is one.
+   pub fn load(path: &Path) -> Result<Option<Checkpoint>> {
+      if !path.exists() {
+         return Ok(None);
+      }
+      let bytes = std::fs::read(path)?;
+      let checkpoint = serde_json::from_slice(&bytes)
+         .map_err(|err| invalid(&format!("checkpoint can't be read: {}", err)))?;
+      Ok(Some(checkpoint))
+   }
+
+   // Replaces the saved checkpoint in one step so a crash can't leave half of it
+   fn save(&self, path: &Path) -> Result<()> {
+      let json = serde_json::to_vec(self)
+         .map_err(|err| invalid(&format!("checkpoint can't be written: {}", err)))?;
+      let temp_path = path.with_extension("checkpoint.tmp");
+      let mut temp_file = File::create(&temp_path)?;
+      temp_file.write_all(&json)?;
+      // On disk before it replaces the last one, or a crash could leave an empty checkpoint
+      temp_file.sync_all()?;
+      std::fs::rename(temp_path, path)?;
+      Ok(())
+   }
+
+   /// Checks that the checkpoint belongs to an encryption of this input with these settings.
+   pub fn check_encryption(&self, input_len: u64, chunk_size: usize, mode: CapsuleMode) -> Result<()> {
+      if self.input_len != input_len {
+         return Err(invalid("input changed size since the checkpoint"));
+      }
+      if self.chunk_size != chunk_size || self.mode() != mode {
+         return Err(invalid("checkpoint was made with a different chunk size or mode"));
+      }
+      Ok(())
+   }
+
+   /// Checks that the checkpoint belongs to a decryption of this input with these keys.
+   pub fn check_decryption(&self, input_len: u64, decryption_keys: &DecryptionKeys) -> Result<()> {
+      if self.input_len != input_len {
+         return Err(invalid("input changed size since the checkpoint"));
+      }
+      if self.chunk_size != decryption_keys.chunk_size
+         || self.keys_hash != hash(&decryption_keys.capsules.concat())
+      {
+         return Err(invalid("checkpoint was made with different keys"));
+      }
+      Ok(())
+   }
+
+   /// Opens the file key of a saved single capsule encryption, which takes the owner secret.
+   pub fn open_file_key(&mut self, owner_secret: Option<&SecretKey>) -> Result<()> {
+      let sealed_file_key = match &self.sealed_file_key {
+         Some(sealed_file_key) if self.file_key.is_none() => sealed_file_key,
+         _ => return Ok(()),
+      };
+      let owner_secret = owner_secret.ok_or_else(|| {
+         invalid("a file with a single capsule can only be resumed with the owner secret")
+      })?;
+      let capsule = self.capsules.first().ok_or_else(|| invalid("capsule is missing"))?;
+      let capsule = parse_capsule(0, capsule)?;
+      let file_key = FileKey::open_original(owner_secret, &capsule, sealed_file_key)
+         .ok_or_else(|| invalid("file key can't be opened with the owner secret"))?;
+      self.file_key = Some(file_key);
+      Ok(())
+   }
+
+   /// Skips the plaintext of the chunks already encrypted, hashing it for the manifest.
+   pub fn skip_input<R: Read>(&mut self, input: &mut R, plaintext_chunk_size: usize) -> Result<()> {
+      let mut remaining = self.input_len;
+      for _ in 0..self.chunks {
+         let buffer = read_chunk(input, plaintext_chunk_size)?;
+         // Only the last chunk is shorter
+         let expected = remaining.min(plaintext_chunk_size as u64);
+         if buffer.len() as u64 != expected {
+            return Err(invalid("input is shorter than the checkpoint"));
+         }
+         remaining -= expected;
+         self.plaintext_hash.update(&buffer);
+      }
+      Ok(())
+   }
+}
+
+/// Opens the output of an interrupted run, dropping anything written after the checkpoint.
+///
+/// When `hash_from` is given, the plaintext already written from that offset on is hashed
+/// into `checkpoint` so the final digest covers the whole output.
+pub(crate) fn open_partial_output(
+   output_file: &str,
+   len: u64,
+   checkpoint: &mut Checkpoint,
+   hash_from: Option<u64>,
+) -> Result<File> {
+   let mut out = OpenOptions::new().read(true).write(true).open(output_file)?;
+   if out.metadata()?.len() < len {
+      return Err(invalid("output is shorter than the checkpoint"));
+   }
+   out.set_len(len)?;
+   if let Some(offset) = hash_from {
+      out.seek(SeekFrom::Start(offset))?;
+      let mut buffer = vec![0u8; 1 << 16];
+      loop {
+         let read = out.read(&mut buffer)?;
+         if read == 0 {
+            break;
+         }
+         checkpoint.plaintext_hash.update(&buffer[..read]);
+      }
+   }
+   out.seek(SeekFrom::End(0))?;
+   Ok(out)
+}
+
+pub(crate) fn checkpoint_path(output_file: &str) -> PathBuf {
+   PathBuf::from(format!("{}.checkpoint", output_file))
+}
+
+/// Reports finished chunks to the progress observer and saves a checkpoint after every batch.
+pub(crate) struct Checkpointer<'a> {
+   pub checkpoint: Checkpoint,
+   // Chunks that were already done when the run started
+   pub start: usize,
+   // Where checkpoints are saved and a handle to the output file, none for streams
+   saving: Option<(PathBuf, File)>,
+   batch: usize,
+   last_saved: Option<Instant>,
+   progress: &'a mut dyn Progress,
+}
+
+impl<'a> Checkpointer<'a> {
+   /// Saves checkpoints to the path in `saving` if there is one, after syncing the output
+   /// file to disk through the handle that comes with it.
+   pub fn new(
+      checkpoint: Checkpoint,
+      saving: Option<(PathBuf, File)>,
+      batch: usize,
+      progress: &'a mut dyn Progress,
+   ) -> Self {
+      Checkpointer {
+         start: checkpoint.chunks,
+         checkpoint,
+         saving,
+         batch,
+         last_saved: None,
+         progress,
+      }
+   }
+
+   /// Records a chunk of `plaintext_len` bytes written to `output`, saving a checkpoint
+   /// if it completes a batch and none was saved in the last second.
+   pub fn chunk_written<W: Write>(&mut self, output: &mut W, plaintext_len: usize) -> Result<()> {
+      self.progress.bytes_processed(plaintext_len as u64);
+      self.progress.chunk_done(self.checkpoint.chunks);
+      self.checkpoint.chunks += 1;
+      if let Some((path, output_file)) = &self.saving {
+         let due = self.last_saved.is_none_or(|saved| saved.elapsed() >= SAVE_INTERVAL);
+         if self.checkpoint.chunks.is_multiple_of(self.batch) && due {
+            // The checkpoint must never get ahead of the output, even after a crash
+            output.flush()?;
+            output_file.sync_data()?;
+            self.checkpoint.save(path)?;
+            self.last_saved = Some(Instant::now());
+         }
+      }
+      Ok(())
+   }
+
+   /// Removes the saved checkpoint once the run is complete.
+   pub fn finish(self) -> Result<Checkpoint> {
+      self.progress.finish();
+      if let Some((path, _)) = &self.saving {
+         if path.exists() {
+            std::fs::remove_file(path)?;
+         }
+      }
+      Ok(self.checkpoint)
+   }
+}
+
+fn invalid(msg: &str) -> PrecryptError {
+   PrecryptError::InvalidCheckpoint(msg.to_string())
+}
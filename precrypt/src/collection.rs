@@ -0,0 +1,289 @@
+use crate::{
+   owner_signer, parse_capsule, pipeline, CapsuleManifest, DecryptionKeys, Manifest, PrecryptError,
+   Progress, RecryptionKeys, Result, SealedSecret,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use umbral_pre::*;
+use zeroize::Zeroizing;
+
+/// Files encrypted to the same owner key, granted all at once.
+///
+/// Granting a collection generates a single key fragment for the receiver and translates
+/// the capsules of every file with it, instead of one delegation per file. Files are keyed
+/// by the hex file ID of their manifest.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Collection {
+   // Empty while the secret is sealed with a passphrase, wiped when the collection is dropped
+   #[serde(default, skip_serializing_if = "Vec::is_empty", with = "crate::encoding::bytes")]
+   owner_secret: Zeroizing<Vec<u8>>,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   sealed_owner_secret: Option<SealedSecret>,
+   files: BTreeMap<String, CollectionFile>,
+}
+
+// What's needed to translate one file of a collection, its keys without the owner secret
+#[derive(Serialize, Deserialize, Clone)]
+struct CollectionFile {
+   #[serde(with = "crate::encoding::byte_list")]
+   capsules: Vec<Vec<u8>>,
+   chunk_size: usize,
+   #[serde(
+      default,
+      skip_serializing_if = "Option::is_none",
+      with = "crate::encoding::optional_bytes"
+   )]
+   sealed_file_key: Option<Vec<u8>>,
+   manifest: Manifest,
+}
+
+impl Collection {
+   /// Empty collection of files encrypted to the public key of `owner_secret`.
+   pub fn new(owner_secret: &SecretKey) -> Self {
+      let secret_box = owner_secret.to_secret_array();
+      Collection {
+         owner_secret: Zeroizing::new(secret_box.as_secret().to_vec()),
+         sealed_owner_secret: None,
+         files: BTreeMap::new(),
+      }
+   }
+
+   /// Adds the file of `recryption_keys`, returning its file ID.
+   ///
+   /// The keys must have the collection's owner secret and a manifest, which holds the
+   /// file ID. Adding a file again replaces it.
+   pub fn add(&mut self, recryption_keys: &RecryptionKeys) -> Result<String> {
+      let owner_secret = self.owner_secret()?;
+      if recryption_keys.owner_secret()?.public_key() != owner_secret.public_key() {
+         return Err(PrecryptError::InvalidConfig(
+            "file was encrypted with another owner secret than the collection's".to_string(),
+         ));
+      }
+      let manifest = match &recryption_keys.manifest {
+         Some(manifest) if manifest.granted_chunks().is_none() => manifest.clone(),
+         _ => {
+            return Err(PrecryptError::InvalidConfig(
+               "only keys of a whole file with a manifest can be added to a collection"
+                  .to_string(),
+            ))
+         }
+      };
+      let file_id = to_hex(manifest.file_id());
+      self.files.insert(
+         file_id.clone(),
+         CollectionFile {
+            capsules: recryption_keys.capsules.clone(),
+            chunk_size: recryption_keys.chunk_size,
+            sealed_file_key: recryption_keys.sealed_file_key.clone(),
+            manifest,
+         },
+      );
+      Ok(file_id)
+   }
+
+   /// Adds a file encrypted to the owner's public key, signing its manifest.
+   pub fn add_manifest(&mut self, manifest: &CapsuleManifest) -> Result<String> {
+      let recryption_keys = RecryptionKeys::from_manifest(manifest, &self.owner_secret()?)?;
+      self.add(&recryption_keys)
+   }
+
+   /// Public key the files of the collection are encrypted to.
+   pub fn owner_pubkey(&self) -> Result<PublicKey> {
+      Ok(self.owner_secret()?.public_key())
+   }
+
+   /// Removes a file from the collection, returning whether it was in it.
+   pub fn remove(&mut self, file_id: &str) -> bool {
+      self.files.remove(file_id).is_some()
+   }
+
+   /// IDs of the files in the collection, in order.
+   pub fn file_ids(&self) -> impl Iterator<Item = &str> {
+      self.files.keys().map(String::as_str)
+   }
+
+   pub fn len(&self) -> usize {
+      self.files.len()
+   }
+
+   pub fn is_empty(&self) -> bool {
+      self.files.is_empty()
+   }
+
+   /// Whether the owner secret is sealed, so adding or granting files needs the passphrase.
+   pub fn is_sealed(&self) -> bool {
+      self.sealed_owner_secret.is_some()
+   }
+
+   /// Seals the owner secret like `RecryptionKeys::seal`.
+   pub fn seal(&mut self, passphrase: &[u8]) -> Result<()> {
+      if self.is_sealed() {
+         return Err(PrecryptError::InvalidConfig("owner secret is already sealed".to_string()));
+      }
+      if passphrase.is_empty() {
+         return Err(PrecryptError::InvalidConfig("passphrase must not be empty".to_string()));
+      }
+      self.sealed_owner_secret = Some(SealedSecret::seal(&self.owner_secret, passphrase)?);
+      self.owner_secret = Zeroizing::default();
+      Ok(())
+   }
+
+   /// Returns a copy of the collection with the owner secret opened with `passphrase`.
+   pub fn unlock(&self, passphrase: &[u8]) -> Result<Collection> {
+      let sealed = self.sealed_owner_secret.as_ref().ok_or_else(|| {
+         PrecryptError::InvalidConfig("owner secret is not sealed".to_string())
+      })?;
+      let mut collection = self.clone();
+      collection.owner_secret = sealed.open(passphrase)?;
+      collection.sealed_owner_secret = None;
+      Ok(collection)
+   }
+
+   // Parses the owner secret, failing if it's still sealed
+   fn owner_secret(&self) -> Result<SecretKey> {
+      if self.is_sealed() {
+         return Err(PrecryptError::PassphraseRequired);
+      }
+      SecretKey::from_bytes(&self.owner_secret).map_err(PrecryptError::InvalidKey)
+   }
+}
+
+// Never prints the owner secret, sealed or not
+impl fmt::Debug for Collection {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("Collection")
+         .field("owner_secret", &"[redacted]")
+         .field("sealed", &self.is_sealed())
+         .field("files", &self.files.keys().collect::<Vec<_>>())
+         .finish()
+   }
+}
+
+/// Decryption keys of every file in a collection, for one receiver.
+///
+/// Get the `DecryptionKeys` of a file by its file ID to decrypt it like any other.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CollectionKeys {
+   #[serde(with = "crate::encoding::bytes")]
+   owner_pubkey: Vec<u8>,
+   // Key of the owner's signer, the same for every file
+   #[serde(with = "crate::encoding::bytes")]
+   verifying_key: Vec<u8>,
+   files: BTreeMap<String, CollectionFileKeys>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct CollectionFileKeys {
+   #[serde(with = "crate::encoding::byte_list")]
+   capsules: Vec<Vec<u8>>,
+   #[serde(with = "crate::encoding::byte_list")]
+   translated_keys: Vec<Vec<u8>>,
+   chunk_size: usize,
+   #[serde(
+      default,
+      skip_serializing_if = "Option::is_none",
+      with = "crate::encoding::optional_bytes"
+   )]
+   sealed_file_key: Option<Vec<u8>>,
+   manifest: Manifest,
+}
+
+impl CollectionKeys {
+   /// Keys of the file with this file ID, if it's in the collection.
+   pub fn file(&self, file_id: &str) -> Option<DecryptionKeys> {
+      let file = self.files.get(file_id)?;
+      Some(DecryptionKeys {
+         owner_pubkey: self.owner_pubkey.clone(),
+         capsules: file.capsules.clone(),
+         translated_keys: file.translated_keys.clone(),
+         chunk_size: file.chunk_size,
+         sealed_file_key: file.sealed_file_key.clone(),
+         threshold: 1,
+         verifying_key: self.verifying_key.clone(),
+         manifest: Some(file.manifest.clone()),
+      })
+   }
+
+   /// IDs of the files in the collection, in order.
+   pub fn file_ids(&self) -> impl Iterator<Item = &str> {
+      self.files.keys().map(String::as_str)
+   }
+
+   pub fn len(&self) -> usize {
+      self.files.len()
+   }
+
+   pub fn is_empty(&self) -> bool {
+      self.files.is_empty()
+   }
+}
+
+/// Translates the capsules of every file in the collection to `receiver_public`.
+///
+/// A single key fragment is generated for the receiver. The capsules of all the files are
+/// translated on `workers` threads, and reported to `progress` as one run.
+pub(crate) fn translate_collection(
+   collection: &Collection,
+   receiver_public: PublicKey,
+   workers: usize,
+   progress: &mut dyn Progress,
+) -> Result<CollectionKeys> {
+   let owner_secret = collection.owner_secret()?;
+   let signer = owner_signer(&owner_secret);
+   // Same as `split_recryption_keys` with a single share
+   let translation_keys =
+      generate_kfrags(&owner_secret, &receiver_public, &signer, 1, 1, true, true);
+   let translation_key = &translation_keys[0];
+
+   // Every capsule of every file, in the order of the files
+   let capsules: Vec<(&String, usize, &Vec<u8>)> = collection
+      .files
+      .iter()
+      .flat_map(|(file_id, file)| {
+         file.capsules.iter().enumerate().map(move |(index, capsule)| (file_id, index, capsule))
+      })
+      .collect();
+   let mut translated: BTreeMap<&String, Vec<Vec<u8>>> = BTreeMap::new();
+   progress.start(capsules.len(), 0);
+   pipeline::run(
+      workers.min(capsules.len()).max(1),
+      |index| Ok(capsules.get(index)),
+      |_, (file_id, index, capsule_vec)| {
+         let capsule = parse_capsule(*index, capsule_vec)?;
+         let translated_key = reencrypt(&capsule, translation_key.clone());
+         Ok((*file_id, translated_key.to_array().to_vec()))
+      },
+      |index, (file_id, translated_key)| {
+         translated.entry(file_id).or_default().push(translated_key);
+         progress.chunk_done(index);
+         Ok(())
+      },
+   )?;
+   progress.finish();
+
+   let files = collection
+      .files
+      .iter()
+      .map(|(file_id, file)| {
+         let translated_keys = translated.remove(file_id).unwrap_or_default();
+         let keys = CollectionFileKeys {
+            capsules: file.capsules.clone(),
+            translated_keys,
+            chunk_size: file.chunk_size,
+            sealed_file_key: file.sealed_file_key.clone(),
+            manifest: file.manifest.clone(),
+         };
+         (file_id.clone(), keys)
+      })
+      .collect();
+   Ok(CollectionKeys {
+      owner_pubkey: owner_secret.public_key().to_array().to_vec(),
+      verifying_key: signer.verifying_key().to_array().to_vec(),
+      files,
+   })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+   bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,966 @@
+use crate::checkpoint::{checkpoint_path, open_partial_output, Checkpoint, Checkpointer};
+use crate::container::{count_chunks, detect_header};
+use crate::manifest::PlaintextHash;
+use crate::{
+   collection, create_output, decrypt_chunks, owner_signer, precrypt_chunks, proxy, read_chunk,
+   split_recryption_keys, CapsuleManifest, CapsuleMode, Collection, CollectionKeys,
+   DecryptionKeys, Header, KeyEncoding, Manifest, NoProgress, PrecryptError, Progress, ProxyKeys,
+   RecryptionKeys, Result,
+};
+use std::fs::File;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::ops::Range;
+use umbral_pre::*;
+use zeroize::Zeroizing;
+
+/// Plaintext bytes per chunk unless set otherwise, what the CLI used to default to.
+pub const DEFAULT_CHUNK_SIZE: usize = 5_000_000;
+
+/// What to do when the output file already exists.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overwrite {
+   /// Replace the existing file.
+   #[default]
+   Replace,
+   /// Fail with `PrecryptError::OutputExists` and leave the existing file alone.
+   Refuse,
+}
+
+/// Options for encrypting files and streams, checked all at once by `build`.
+pub struct EncryptorBuilder<'a> {
+   chunk_size: usize,
+   workers: Option<usize>,
+   memory_budget: Option<usize>,
+   mode: CapsuleMode,
+   overwrite: Overwrite,
+   progress: Box<dyn Progress + Send + 'a>,
+}
+
+impl<'a> EncryptorBuilder<'a> {
+   /// Plaintext bytes encrypted as one chunk, `DEFAULT_CHUNK_SIZE` by default.
+   ///
+   /// Only the last chunk of a file is shorter.
+   pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+      self.chunk_size = chunk_size;
+      self
+   }
+
+   /// Chunks encrypted in parallel, the number of available cores by default.
+   pub fn workers(mut self, workers: usize) -> Self {
+      self.workers = Some(workers);
+      self
+   }
+
+   /// Most bytes of plaintext held in memory at once, unlimited by default.
+   ///
+   /// Fewer workers are used if they would hold more chunks than fit in the budget.
+   pub fn memory_budget(mut self, memory_budget: usize) -> Self {
+      self.memory_budget = Some(memory_budget);
+      self
+   }
+
+   pub fn mode(mut self, mode: CapsuleMode) -> Self {
+      self.mode = mode;
+      self
+   }
+
+   pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+      self.overwrite = overwrite;
+      self
+   }
+
+   /// Observer told about every chunk encrypted, nothing is reported by default.
+   pub fn progress(mut self, progress: impl Progress + Send + 'a) -> Self {
+      self.progress = Box::new(progress);
+      self
+   }
+
+   /// Checks the options before anything is read or written.
+   pub fn build(self) -> Result<Encryptor<'a>> {
+      if self.chunk_size == 0 {
+         return Err(PrecryptError::InvalidConfig(
+            "'chunk_size' must be greater than zero".to_string(),
+         ));
+      }
+      let workers = self.workers.unwrap_or_else(default_workers);
+      let workers = limit_workers(workers, self.memory_budget, self.chunk_size)?;
+      Ok(Encryptor {
+         chunk_size: self.chunk_size,
+         workers,
+         mode: self.mode,
+         overwrite: self.overwrite,
+         progress: self.progress,
+      })
+   }
+}
+
+/// Encrypts files and streams in chunks on a pool of workers.
+///
+/// ```no_run
+/// # use precrypt::{CapsuleMode, Encryptor};
+/// # use umbral_pre::SecretKey;
+/// let recryption_keys = Encryptor::builder()
+///    .chunk_size(1 << 20)
+///    .mode(CapsuleMode::PerFile)
+///    .build()?
+///    .encrypt_file("secret.txt", SecretKey::random(), "secret.precrypt")?;
+/// # Ok::<(), precrypt::PrecryptError>(())
+/// ```
+pub struct Encryptor<'a> {
+   chunk_size: usize,
+   workers: usize,
+   mode: CapsuleMode,
+   overwrite: Overwrite,
+   progress: Box<dyn Progress + Send + 'a>,
+}
+
+impl<'a> Encryptor<'a> {
+   pub fn builder() -> EncryptorBuilder<'a> {
+      EncryptorBuilder {
+         chunk_size: DEFAULT_CHUNK_SIZE,
+         workers: None,
+         memory_budget: None,
+         mode: CapsuleMode::default(),
+         overwrite: Overwrite::default(),
+         progress: Box::new(NoProgress),
+      }
+   }
+
+   pub fn chunk_size(&self) -> usize {
+      self.chunk_size
+   }
+
+   /// Chunks encrypted in parallel, after fitting them into the memory budget.
+   pub fn workers(&self) -> usize {
+      self.workers
+   }
+
+   /// Encrypts the file at `input_path` into a container at `output_file`.
+   ///
+   /// Progress is saved to `<output_file>.checkpoint` after every batch of chunks
+   /// and removed once the whole file is encrypted.
+   pub fn encrypt_file(
+      &mut self,
+      input_path: &str,
+      owner_secret: SecretKey,
+      output_file: &str,
+   ) -> Result<RecryptionKeys> {
+      self.encrypt_file_from(input_path, owner_secret, output_file, false)
+   }
+
+   /// Continues an interrupted `encrypt_file` from its last checkpoint.
+   ///
+   /// Chunks already written are kept along with their capsules, so the keys are the same
+   /// as if the run had never stopped. Starts from scratch if there is no checkpoint.
+   pub fn resume_file(
+      &mut self,
+      input_path: &str,
+      owner_secret: SecretKey,
+      output_file: &str,
+   ) -> Result<RecryptionKeys> {
+      self.encrypt_file_from(input_path, owner_secret, output_file, true)
+   }
+
+   /// Encrypts the file at `input_path` to the owner's public key, without their secret.
+   ///
+   /// The `CapsuleManifest` returned holds no secret, so it can be handed to the owner
+   /// openly to grant access with `recrypt_manifest`.
+   pub fn encrypt_file_for(
+      &mut self,
+      input_path: &str,
+      owner_public: PublicKey,
+      output_file: &str,
+   ) -> Result<CapsuleManifest> {
+      self.encrypt_path(input_path, &owner_public, None, output_file, false)
+   }
+
+   /// Continues an interrupted `encrypt_file_for` from its last checkpoint.
+   ///
+   /// The file key of a file with a single capsule is never saved, so only files with a
+   /// capsule per chunk can be resumed without the owner secret.
+   pub fn resume_file_for(
+      &mut self,
+      input_path: &str,
+      owner_public: PublicKey,
+      output_file: &str,
+   ) -> Result<CapsuleManifest> {
+      self.encrypt_path(input_path, &owner_public, None, output_file, true)
+   }
+
+   /// Encrypts everything read from `input` and writes the ciphertext to `output`.
+   ///
+   /// Only the chunks are written since the header can't be known before the end of `input`;
+   /// write `Header::from(&recryption_keys)` in front of them to get a self-describing container.
+   /// The progress observer is not told about the start since the length of `input` is unknown.
+   pub fn encrypt_stream<R: Read, W: Write>(
+      &mut self,
+      input: R,
+      owner_secret: SecretKey,
+      output: W,
+   ) -> Result<RecryptionKeys> {
+      let manifest = self.encrypt_stream_for(input, owner_secret.public_key(), output)?;
+      RecryptionKeys::from_manifest(&manifest, &owner_secret)
+   }
+
+   /// Encrypts everything read from `input` to the owner's public key, like `encrypt_stream`.
+   ///
+   /// Write `Header::from(&manifest)` in front of the chunks to get a self-describing container.
+   pub fn encrypt_stream_for<R: Read, W: Write>(
+      &mut self,
+      input: R,
+      owner_public: PublicKey,
+      output: W,
+   ) -> Result<CapsuleManifest> {
+      let chunk_size = self.chunk_size + self.mode.chunk_overhead();
+      // Streams can't be resumed, so no checkpoints are saved
+      let checkpoint = Checkpoint::new_encryption(0, chunk_size, self.mode, &owner_public)?;
+      let mut checkpointer = Checkpointer::new(checkpoint, None, self.workers, &mut *self.progress);
+      let manifest =
+         precrypt_chunks(input, output, &owner_public, self.workers, &mut checkpointer)?;
+      checkpointer.finish()?;
+      Ok(manifest)
+   }
+
+   /// Encrypts the file at `input_path` as new chunks at the end of the container at
+   /// `ciphertext_file`, and updates `recryption_keys` to cover them.
+   ///
+   /// The chunks already there are left as they are: a last chunk that isn't full is padded
+   /// to the chunk size and the new data starts at the next chunk, so keys granted before
+   /// keep decrypting them. Returns the chunks written: grant them with
+   /// `Recryptor::recrypt_range` to receivers holding keys of the rest. The container is
+   /// rewritten behind a larger header and replaces the old one once complete. The chunk size
+   /// comes from the keys, and only files with a capsule per chunk and a manifest can be
+   /// appended to.
+   pub fn append_file(
+      &mut self,
+      input_path: &str,
+      ciphertext_file: &str,
+      recryption_keys: &mut RecryptionKeys,
+   ) -> Result<Range<usize>> {
+      appendable_manifest(recryption_keys)?;
+      let input = File::open(input_path)?;
+      let input_len = input.metadata()?.len();
+      let mut ciphertext = File::open(ciphertext_file)?;
+      let header = match detect_header(&mut ciphertext)? {
+         (Some(header), _) => header,
+         (None, _) => {
+            return Err(PrecryptError::InvalidHeader(
+               "only containers with a header can be appended to".to_string(),
+            ))
+         }
+      };
+      if header != Header::from(&*recryption_keys) {
+         return Err(PrecryptError::InvalidHeader(
+            "header does not match the keys' capsules".to_string(),
+         ));
+      }
+      let body_start = header.encoded_len() as u64;
+      header.check_body_len(ciphertext.metadata()?.len() - body_start)?;
+
+      let chunk_size = header.chunk_size;
+      let mut last_chunk: Vec<u8> = Vec::new();
+      if header.chunk_count > 0 {
+         let last_start = body_start + ((header.chunk_count - 1) * chunk_size) as u64;
+         ciphertext.seek(SeekFrom::Start(last_start))?;
+         ciphertext.read_to_end(&mut last_chunk)?;
+      }
+      let overhead = CapsuleMode::PerChunk.chunk_overhead();
+      let chunks = header.chunk_count + count_chunks(input_len, chunk_size - overhead);
+      if chunks == header.chunk_count {
+         return Ok(chunks..chunks);
+      }
+
+      // Written next to the container so it's only replaced once the new one is complete
+      let temp_file = TempFile::new(format!("{}.append", ciphertext_file));
+      let mut out = create_output(&temp_file.path, Overwrite::Replace)?;
+      out.write_all(&vec![0u8; Header::reserved_len(CapsuleMode::PerChunk, chunks)])?;
+      ciphertext.seek(SeekFrom::Start(body_start))?;
+      std::io::copy(&mut ciphertext, &mut out)?;
+
+      self.progress.start(chunks, header.chunk_count);
+      let mut appended_keys = recryption_keys.clone();
+      let appended = self.append_chunks(&last_chunk, input, &mut out, &mut appended_keys)?;
+      // The input changed size while it was being read
+      if appended.end != chunks {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks,
+            capsules: appended.end,
+         });
+      }
+      out.seek(SeekFrom::Start(0))?;
+      Header::from(&appended_keys).write_to(&mut out)?;
+      out.flush()?;
+      temp_file.persist(ciphertext_file)?;
+      *recryption_keys = appended_keys;
+      Ok(appended)
+   }
+
+   /// Encrypts everything read from `input` as new chunks after those of `recryption_keys`,
+   /// like `append_file`.
+   ///
+   /// `last_chunk` is the ciphertext of the file's last chunk, empty if it has none. The
+   /// padding of that chunk and the new chunks are written to `output`, to follow the
+   /// ciphertext already there; write `Header::from(&recryption_keys)` in front of all the
+   /// chunks to get a self-describing container.
+   pub fn append_stream<R: Read, W: Write>(
+      &mut self,
+      last_chunk: &[u8],
+      input: R,
+      output: W,
+      recryption_keys: &mut RecryptionKeys,
+   ) -> Result<Range<usize>> {
+      self.append_chunks(last_chunk, input, output, recryption_keys)
+   }
+
+   fn encrypt_file_from(
+      &mut self,
+      input_path: &str,
+      owner_secret: SecretKey,
+      output_file: &str,
+      resume: bool,
+   ) -> Result<RecryptionKeys> {
+      let owner_public = owner_secret.public_key();
+      let manifest =
+         self.encrypt_path(input_path, &owner_public, Some(&owner_secret), output_file, resume)?;
+      RecryptionKeys::from_manifest(&manifest, &owner_secret)
+   }
+
+   fn encrypt_path(
+      &mut self,
+      input_path: &str,
+      owner_public: &PublicKey,
+      owner_secret: Option<&SecretKey>,
+      output_file: &str,
+      resume: bool,
+   ) -> Result<CapsuleManifest> {
+      let f = File::open(input_path)?;
+      let file_size = f.metadata()?.len();
+      self.encrypt_input(f, file_size, owner_public, owner_secret, output_file, resume)
+   }
+
+   // Encrypts the `file_size` bytes of `f` into a container at `output_file`, the owner
+   // secret is only needed to resume a file with a single capsule
+   pub(crate) fn encrypt_input<R: Read>(
+      &mut self,
+      mut f: R,
+      file_size: u64,
+      owner_public: &PublicKey,
+      owner_secret: Option<&SecretKey>,
+      output_file: &str,
+      resume: bool,
+   ) -> Result<CapsuleManifest> {
+      let plaintext_chunk_size = self.chunk_size;
+      let chunk_size = plaintext_chunk_size + self.mode.chunk_overhead();
+      let mut chunks = count_chunks(file_size, plaintext_chunk_size);
+      if self.mode == CapsuleMode::PerFile {
+         // An empty file still gets a final chunk marking its end
+         chunks = chunks.max(1);
+      }
+      // Reserve room for the header, the capsules are only known once every chunk is encrypted
+      let header_len = Header::reserved_len(self.mode, chunks);
+
+      let path = checkpoint_path(output_file);
+      let saved = match resume {
+         true => Checkpoint::load(&path)?,
+         false => None,
+      };
+      let (mut out, checkpoint) = match saved {
+         Some(mut checkpoint) => {
+            checkpoint.check_encryption(file_size, chunk_size, self.mode)?;
+            checkpoint.open_file_key(owner_secret)?;
+            // Only the last chunk is shorter, it may be among those already written
+            let plaintext_len = ((checkpoint.chunks * plaintext_chunk_size) as u64).min(file_size);
+            let overhead = (checkpoint.chunks * self.mode.chunk_overhead()) as u64;
+            let written = header_len as u64 + plaintext_len + overhead;
+            let out = open_partial_output(output_file, written, &mut checkpoint, None)?;
+            checkpoint.skip_input(&mut f, plaintext_chunk_size)?;
+            (out, checkpoint)
+         }
+         None => {
+            let mut out = create_output(output_file, self.overwrite)?;
+            out.write_all(&vec![0u8; header_len])?;
+            let checkpoint =
+               Checkpoint::new_encryption(file_size, chunk_size, self.mode, owner_public)?;
+            (out, checkpoint)
+         }
+      };
+
+      self.progress.start(chunks, checkpoint.chunks);
+      let saving = Some((path, out.try_clone()?));
+      let mut checkpointer =
+         Checkpointer::new(checkpoint, saving, self.workers, &mut *self.progress);
+      let manifest = precrypt_chunks(f, &mut out, owner_public, self.workers, &mut checkpointer)?;
+      // The input changed size while it was being read
+      let encrypted_chunks = checkpointer.checkpoint.chunks;
+      if encrypted_chunks != chunks {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks,
+            capsules: encrypted_chunks,
+         });
+      }
+
+      let header = Header::from(&manifest);
+      out.seek(SeekFrom::Start(0))?;
+      header.write_to(&mut out)?;
+      out.flush()?;
+      checkpointer.finish()?;
+      Ok(manifest)
+   }
+
+   // Encrypts `input` into chunks following those of the keys, after padding the last
+   // chunk to the chunk size, and signs them into the manifest as an appended segment
+   fn append_chunks<R: Read, W: Write>(
+      &mut self,
+      last_chunk: &[u8],
+      mut input: R,
+      mut output: W,
+      recryption_keys: &mut RecryptionKeys,
+   ) -> Result<Range<usize>> {
+      let manifest = appendable_manifest(recryption_keys)?;
+      let owner_secret = recryption_keys.owner_secret()?;
+      let owner_public = owner_secret.public_key();
+      let chunk_size = recryption_keys.chunk_size;
+      let chunks = recryption_keys.capsules.len();
+      let padding = match chunks {
+         0 if last_chunk.is_empty() => 0,
+         0 => return Err(PrecryptError::ChunkMismatch { index: 0 }),
+         _ => {
+            if last_chunk.len() > chunk_size {
+               return Err(PrecryptError::ChunkMismatch { index: chunks - 1 });
+            }
+            manifest.check_chunk(chunks - 1, last_chunk)?;
+            // A chunk holding no more than its overhead was cut off
+            if last_chunk.len() <= CapsuleMode::PerChunk.chunk_overhead() {
+               return Err(PrecryptError::Truncated);
+            }
+            chunk_size - last_chunk.len()
+         }
+      };
+
+      // Nothing to append, the file and its keys stay as they are
+      let mut first = Zeroizing::new(Vec::new());
+      (&mut input).take(1).read_to_end(&mut first)?;
+      if first.is_empty() {
+         return Ok(chunks..chunks);
+      }
+      output.write_all(&vec![0u8; padding])?;
+
+      // Counts on from the chunks already there, like resuming an encryption
+      let mut checkpoint =
+         Checkpoint::new_encryption(0, chunk_size, CapsuleMode::PerChunk, &owner_public)?;
+      checkpoint.chunks = chunks;
+      let mut checkpointer = Checkpointer::new(checkpoint, None, self.workers, &mut *self.progress);
+      let input = first.as_slice().chain(input);
+      let appended =
+         precrypt_chunks(input, &mut output, &owner_public, self.workers, &mut checkpointer)?;
+      checkpointer.finish()?;
+
+      let signer = owner_signer(&owner_secret);
+      let manifest = manifest.append(
+         &signer,
+         &appended.chunk_hashes,
+         &appended.capsules,
+         padding,
+         appended.plaintext_hash,
+      );
+      recryption_keys.capsules.extend(appended.capsules);
+      recryption_keys.manifest = Some(manifest);
+      Ok(chunks..recryption_keys.capsules.len())
+   }
+}
+
+// Manifest of keys that can be appended to, whose chunks each have their own capsule
+fn appendable_manifest(recryption_keys: &RecryptionKeys) -> Result<Manifest> {
+   let overhead = CapsuleMode::PerChunk.chunk_overhead();
+   if recryption_keys.chunk_size <= overhead {
+      return Err(PrecryptError::InvalidConfig(format!(
+         "chunk size must be larger than {}",
+         overhead
+      )));
+   }
+   match (recryption_keys.mode(), recryption_keys.manifest()) {
+      (CapsuleMode::PerChunk, Some(manifest)) if manifest.granted_chunks().is_none() => {
+         Ok(manifest.clone())
+      }
+      _ => Err(PrecryptError::InvalidConfig(
+         "only keys of a file with a capsule per chunk and a manifest can be appended to"
+            .to_string(),
+      )),
+   }
+}
+
+// Removes a file written next to its destination unless it was moved into place
+struct TempFile {
+   path: String,
+   persisted: bool,
+}
+
+impl TempFile {
+   fn new(path: String) -> Self {
+      TempFile {
+         path,
+         persisted: false,
+      }
+   }
+
+   fn persist(mut self, destination: &str) -> Result<()> {
+      std::fs::rename(&self.path, destination)?;
+      self.persisted = true;
+      Ok(())
+   }
+}
+
+impl Drop for TempFile {
+   fn drop(&mut self) {
+      if !self.persisted {
+         // The error that got us here is the one worth reporting
+         let _ = std::fs::remove_file(&self.path);
+      }
+   }
+}
+
+/// Options for decrypting files and streams, checked all at once by `build`.
+///
+/// The chunk size is not an option since it is fixed when the file is encrypted.
+pub struct DecryptorBuilder<'a> {
+   workers: Option<usize>,
+   memory_budget: Option<usize>,
+   overwrite: Overwrite,
+   verifying_key: Option<PublicKey>,
+   progress: Box<dyn Progress + Send + 'a>,
+}
+
+impl<'a> DecryptorBuilder<'a> {
+   /// Chunks decrypted in parallel, the number of available cores by default.
+   pub fn workers(mut self, workers: usize) -> Self {
+      self.workers = Some(workers);
+      self
+   }
+
+   /// Most bytes of ciphertext held in memory at once, unlimited by default.
+   ///
+   /// Fewer workers are used if they would hold more chunks than fit in the budget.
+   pub fn memory_budget(mut self, memory_budget: usize) -> Self {
+      self.memory_budget = Some(memory_budget);
+      self
+   }
+
+   pub fn overwrite(mut self, overwrite: Overwrite) -> Self {
+      self.overwrite = overwrite;
+      self
+   }
+
+   /// Verifying key the owner published, keys signed with any other are refused.
+   ///
+   /// See `DecryptionKeys::check_verifying_key`, not checked by default.
+   pub fn verifying_key(mut self, verifying_key: PublicKey) -> Self {
+      self.verifying_key = Some(verifying_key);
+      self
+   }
+
+   /// Observer told about every chunk decrypted, nothing is reported by default.
+   pub fn progress(mut self, progress: impl Progress + Send + 'a) -> Self {
+      self.progress = Box::new(progress);
+      self
+   }
+
+   /// Checks the options before anything is read or written.
+   ///
+   /// Whether a chunk fits in the memory budget is only known from the keys, so that is
+   /// checked when decrypting.
+   pub fn build(self) -> Result<Decryptor<'a>> {
+      let workers = self.workers.unwrap_or_else(default_workers);
+      if workers == 0 {
+         return Err(zero_workers());
+      }
+      if self.memory_budget == Some(0) {
+         return Err(PrecryptError::InvalidConfig(
+            "'memory_budget' must be greater than zero".to_string(),
+         ));
+      }
+      Ok(Decryptor {
+         workers,
+         memory_budget: self.memory_budget,
+         overwrite: self.overwrite,
+         verifying_key: self.verifying_key,
+         progress: self.progress,
+      })
+   }
+}
+
+/// Decrypts files and streams in chunks on a pool of workers.
+pub struct Decryptor<'a> {
+   workers: usize,
+   memory_budget: Option<usize>,
+   overwrite: Overwrite,
+   verifying_key: Option<PublicKey>,
+   progress: Box<dyn Progress + Send + 'a>,
+}
+
+impl<'a> Decryptor<'a> {
+   pub fn builder() -> DecryptorBuilder<'a> {
+      DecryptorBuilder {
+         workers: None,
+         memory_budget: None,
+         overwrite: Overwrite::default(),
+         verifying_key: None,
+         progress: Box::new(NoProgress),
+      }
+   }
+
+   pub fn workers(&self) -> usize {
+      self.workers
+   }
+
+   /// Decrypts the file at `input_path` to `output_file`.
+   ///
+   /// Progress is saved to `<output_file>.checkpoint` after every batch of chunks
+   /// and removed once the whole file is decrypted.
+   pub fn decrypt_file(
+      &mut self,
+      input_path: &str,
+      output_file: &str,
+      receiver_key: SecretKey,
+      decryption_keys: &DecryptionKeys,
+   ) -> Result<()> {
+      self.decrypt_file_from(input_path, output_file, receiver_key, decryption_keys, false)
+   }
+
+   /// Continues an interrupted `decrypt_file` from its last checkpoint.
+   ///
+   /// Starts from scratch if there is no checkpoint.
+   pub fn resume_file(
+      &mut self,
+      input_path: &str,
+      output_file: &str,
+      receiver_key: SecretKey,
+      decryption_keys: &DecryptionKeys,
+   ) -> Result<()> {
+      self.decrypt_file_from(input_path, output_file, receiver_key, decryption_keys, true)
+   }
+
+   /// Decrypts the ciphertext read from `input` and writes the plaintext to `output`.
+   ///
+   /// Accepts both containers and legacy headerless ciphertexts. A container header must
+   /// describe the same chunk size and capsules as `decryption_keys`. The progress observer
+   /// is not told about the start since the length of `input` is unknown.
+   ///
+   /// Keys granting part of a file decrypt the granted chunks of a container, or a
+   /// ciphertext without header starting at the first granted chunk, like the bytes of
+   /// their `ciphertext_range`.
+   pub fn decrypt_stream<R: Read, W: Write>(
+      &mut self,
+      mut input: R,
+      output: W,
+      receiver_key: SecretKey,
+      decryption_keys: &DecryptionKeys,
+   ) -> Result<()> {
+      self.check_owner(decryption_keys)?;
+      let workers = limit_workers(self.workers, self.memory_budget, decryption_keys.chunk_size)?;
+      let (header, peeked) = detect_header(&mut input)?;
+      let mut granted_len = u64::MAX;
+      if let Some(header) = header {
+         header.check_keys(decryption_keys)?;
+         // Skip to the chunks of the keys, the granted ones or those from before an append
+         if let Some(body) = header.keys_body(decryption_keys) {
+            std::io::copy(&mut (&mut input).take(body.start), &mut std::io::sink())?;
+            granted_len = body.end - body.start;
+         }
+      }
+      let input = Cursor::new(peeked).chain(input).take(granted_len);
+      // Streams can't be resumed, so no checkpoints are saved
+      let checkpoint = Checkpoint::new_decryption(0, decryption_keys);
+      let mut checkpointer = Checkpointer::new(checkpoint, None, workers, &mut *self.progress);
+      decrypt_chunks(input, output, receiver_key, decryption_keys, workers, &mut checkpointer)?;
+      checkpointer.finish()?;
+      Ok(())
+   }
+
+   fn decrypt_file_from(
+      &mut self,
+      input_path: &str,
+      output_file: &str,
+      receiver_key: SecretKey,
+      decryption_keys: &DecryptionKeys,
+      resume: bool,
+   ) -> Result<()> {
+      self.check_owner(decryption_keys)?;
+      let workers = limit_workers(self.workers, self.memory_budget, decryption_keys.chunk_size)?;
+      // Read input file
+      let mut f = File::open(input_path)?;
+      let file_size = f.metadata()?.len();
+      let (header, mut peeked) = detect_header(&mut f)?;
+      let mut body_offset = header.as_ref().map_or(0, |header| header.encoded_len() as u64);
+      let mut granted_len = u64::MAX;
+      let chunks = match &header {
+         Some(header) => {
+            header.check_body_len(file_size - body_offset)?;
+            header.check_keys(decryption_keys)?;
+            match header.keys_body(decryption_keys) {
+               // Only the chunks of the keys are decrypted, the granted ones or those from
+               // before an append
+               Some(body) => {
+                  body_offset += body.start;
+                  granted_len = body.end - body.start;
+                  f.seek(SeekFrom::Start(body_offset))?;
+                  decryption_keys.capsules.len()
+               }
+               None => header.chunk_count,
+            }
+         }
+         None => count_chunks(file_size, decryption_keys.chunk_size),
+      };
+      // Every chunk needs exactly one capsule and one translated key
+      let capsules = decryption_keys.capsules.len();
+      if decryption_keys.mode() == CapsuleMode::PerChunk && chunks != capsules {
+         return Err(PrecryptError::ChunkCountMismatch { chunks, capsules });
+      }
+      if decryption_keys.translated_keys.len() != capsules {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks: capsules,
+            capsules: decryption_keys.translated_keys.len(),
+         });
+      }
+
+      let path = checkpoint_path(output_file);
+      let saved = match resume {
+         true => Checkpoint::load(&path)?,
+         false => None,
+      };
+      let (out, checkpoint) = match saved {
+         Some(mut checkpoint) => {
+            checkpoint.check_decryption(file_size, decryption_keys)?;
+            let chunk_size = decryption_keys.chunk_size;
+            let written = match checkpoint.chunks {
+               0 => 0,
+               // The last chunk written may be shorter than its slot, or padded in it
+               chunks => {
+                  let last_start = ((chunks - 1) * chunk_size) as u64;
+                  f.seek(SeekFrom::Start(body_offset + last_start))?;
+                  let slot = read_chunk(&mut (&mut f).take(granted_len - last_start), chunk_size)?;
+                  let last_len = decryption_keys.unpadded_len(chunks - 1, &slot)?;
+                  let overhead = decryption_keys.mode().chunk_overhead();
+                  decryption_keys.plaintext_offset(chunks - 1)
+                     + last_len.saturating_sub(overhead) as u64
+               }
+            };
+            // Rehashed from the start, split where the appended segments start
+            checkpoint.plaintext_hash = PlaintextHash::new(decryption_keys.segment_starts());
+            let out = open_partial_output(output_file, written, &mut checkpoint, Some(0))?;
+            // Continue reading right after the last chunk written
+            let read = (checkpoint.chunks * chunk_size) as u64;
+            f.seek(SeekFrom::Start(body_offset + read))?;
+            granted_len = granted_len.saturating_sub(read);
+            peeked.clear();
+            (out, checkpoint)
+         }
+         None => (
+            create_output(output_file, self.overwrite)?,
+            Checkpoint::new_decryption(file_size, decryption_keys),
+         ),
+      };
+
+      self.progress.start(chunks, checkpoint.chunks);
+      let saving = Some((path, out.try_clone()?));
+      let mut checkpointer = Checkpointer::new(checkpoint, saving, workers, &mut *self.progress);
+      let input = Cursor::new(peeked).chain(f).take(granted_len);
+      decrypt_chunks(input, out, receiver_key, decryption_keys, workers, &mut checkpointer)?;
+      checkpointer.finish()?;
+      Ok(())
+   }
+
+   // Refuses keys signed by another owner than the expected one, if one is
+   fn check_owner(&self, decryption_keys: &DecryptionKeys) -> Result<()> {
+      match &self.verifying_key {
+         Some(verifying_key) => decryption_keys.check_verifying_key(verifying_key),
+         None => Ok(()),
+      }
+   }
+}
+
+/// Options for translating capsules to a receiver, checked all at once by `build`.
+pub struct RecryptorBuilder<'a> {
+   workers: Option<usize>,
+   progress: Box<dyn Progress + Send + 'a>,
+}
+
+impl<'a> RecryptorBuilder<'a> {
+   /// Capsules translated in parallel, the number of available cores by default.
+   pub fn workers(mut self, workers: usize) -> Self {
+      self.workers = Some(workers);
+      self
+   }
+
+   /// Observer told about every capsule translated, nothing is reported by default.
+   pub fn progress(mut self, progress: impl Progress + Send + 'a) -> Self {
+      self.progress = Box::new(progress);
+      self
+   }
+
+   pub fn build(self) -> Result<Recryptor<'a>> {
+      let workers = self.workers.unwrap_or_else(default_workers);
+      if workers == 0 {
+         return Err(zero_workers());
+      }
+      Ok(Recryptor {
+         workers,
+         progress: self.progress,
+      })
+   }
+}
+
+/// Translates the capsules of a file to a receiver on a pool of workers.
+///
+/// The translated keys come out in capsule order whatever the number of workers. Files
+/// with a capsule per chunk can have millions of them, so the `_to` methods write the
+/// keys to an output as they're translated instead of holding them all in memory.
+///
+/// ```no_run
+/// # use precrypt::{KeyEncoding, RecryptionKeys, Recryptor};
+/// # use umbral_pre::SecretKey;
+/// # let recryption_keys: RecryptionKeys = unimplemented!();
+/// let receiver_public = SecretKey::random().public_key();
+/// let output = std::fs::File::create("decryption.keys")?;
+/// Recryptor::builder().workers(8).build()?.recrypt_to(
+///    &recryption_keys,
+///    receiver_public,
+///    output,
+///    KeyEncoding::Cbor,
+/// )?;
+/// # Ok::<(), precrypt::PrecryptError>(())
+/// ```
+pub struct Recryptor<'a> {
+   workers: usize,
+   progress: Box<dyn Progress + Send + 'a>,
+}
+
+impl<'a> Recryptor<'a> {
+   pub fn builder() -> RecryptorBuilder<'a> {
+      RecryptorBuilder {
+         workers: None,
+         progress: Box::new(NoProgress),
+      }
+   }
+
+   pub fn workers(&self) -> usize {
+      self.workers
+   }
+
+   /// Translates the keys to `receiver_public`, acting as the only re-encryption proxy.
+   pub fn recrypt(
+      &mut self,
+      recryption_keys: &RecryptionKeys,
+      receiver_public: PublicKey,
+   ) -> Result<DecryptionKeys> {
+      let proxy_keys = split_recryption_keys(recryption_keys, receiver_public, 1, 1)?;
+      self.proxy_recrypt(&proxy_keys[0])
+   }
+
+   /// Translates only the capsules of the chunks in `chunks` to `receiver_public`.
+   ///
+   /// The keys decrypt those chunks and no others, e.g. to sell a preview or a chapter of
+   /// a file. Use `RecryptionKeys::chunks_covering` to find the chunks of a plaintext byte
+   /// range. Only files with a capsule per chunk and a manifest can be granted in part.
+   pub fn recrypt_range(
+      &mut self,
+      recryption_keys: &RecryptionKeys,
+      receiver_public: PublicKey,
+      chunks: Range<usize>,
+   ) -> Result<DecryptionKeys> {
+      let signer = owner_signer(&recryption_keys.owner_secret()?);
+      let proxy_keys = split_recryption_keys(recryption_keys, receiver_public, 1, 1)?;
+      let proxy_keys = proxy_keys.into_iter().next().unwrap().restrict(chunks, &signer)?;
+      self.proxy_recrypt(&proxy_keys)
+   }
+
+   /// Translates the keys to every receiver in `receivers` at once.
+   ///
+   /// Much faster than a `recrypt` per receiver: the owner secret and the capsules are
+   /// only parsed once, and the workers translate different receivers in parallel. The
+   /// keys of each receiver are handed to `granted` along with its index in `receivers`,
+   /// in that order, and the progress observer is told about receivers instead of capsules.
+   pub fn recrypt_batch(
+      &mut self,
+      recryption_keys: &RecryptionKeys,
+      receivers: &[PublicKey],
+      granted: impl FnMut(usize, DecryptionKeys) -> Result<()>,
+   ) -> Result<()> {
+      proxy::translate_batch(recryption_keys, receivers, self.workers, &mut *self.progress, granted)
+   }
+
+   /// Translates the keys of every file in the collection to `receiver_public`.
+   ///
+   /// One key fragment is generated for the receiver and the capsules of all the files
+   /// are translated on the workers, the progress observer counting capsules across files.
+   pub fn recrypt_collection(
+      &mut self,
+      collection: &Collection,
+      receiver_public: PublicKey,
+   ) -> Result<CollectionKeys> {
+      collection::translate_collection(
+         collection,
+         receiver_public,
+         self.workers,
+         &mut *self.progress,
+      )
+   }
+
+   /// Translates every capsule with the proxy's key fragment, see `proxy_recrypt`.
+   pub fn proxy_recrypt(&mut self, proxy_keys: &ProxyKeys) -> Result<DecryptionKeys> {
+      proxy::translate(proxy_keys, self.workers, &mut *self.progress)
+   }
+
+   /// Like `recrypt`, writing the decryption keys to `output` in `encoding`.
+   ///
+   /// What's written reads back with `decode_keys` like keys from `encode_keys`. If the
+   /// translation fails part of the keys may already be written.
+   pub fn recrypt_to<W: Write>(
+      &mut self,
+      recryption_keys: &RecryptionKeys,
+      receiver_public: PublicKey,
+      output: W,
+      encoding: KeyEncoding,
+   ) -> Result<()> {
+      let proxy_keys = split_recryption_keys(recryption_keys, receiver_public, 1, 1)?;
+      self.proxy_recrypt_to(&proxy_keys[0], output, encoding)
+   }
+
+   /// Like `proxy_recrypt`, writing the decryption key share to `output` in `encoding`.
+   pub fn proxy_recrypt_to<W: Write>(
+      &mut self,
+      proxy_keys: &ProxyKeys,
+      output: W,
+      encoding: KeyEncoding,
+   ) -> Result<()> {
+      proxy::write_translated(proxy_keys, self.workers, &mut *self.progress, output, encoding)
+   }
+}
+
+fn default_workers() -> usize {
+   std::thread::available_parallelism().map_or(1, |cores| cores.get())
+}
+
+// Lowers the workers so the chunks they hold at once fit in the memory budget
+fn limit_workers(workers: usize, memory_budget: Option<usize>, chunk_size: usize) -> Result<usize> {
+   if workers == 0 {
+      return Err(zero_workers());
+   }
+   match memory_budget {
+      Some(memory_budget) if memory_budget < chunk_size => {
+         Err(PrecryptError::InvalidConfig(format!(
+            "'memory_budget' of {} bytes can't hold a single chunk of {} bytes",
+            memory_budget, chunk_size
+         )))
+      }
+      Some(memory_budget) => Ok(workers.min(memory_budget / chunk_size)),
+      None => Ok(workers),
+   }
+}
+
+fn zero_workers() -> PrecryptError {
+   PrecryptError::InvalidConfig("'workers' must be greater than zero".to_string())
+}
@@ -0,0 +1,312 @@
+use crate::file_key::SEALED_KEY_LEN;
+use crate::manifest::Manifest;
+use crate::{CapsuleManifest, CapsuleMode, DecryptionKeys, PrecryptError, RecryptionKeys, Result};
+use std::io::Read;
+use std::io::Write;
+use std::ops::Range;
+use umbral_pre::{Capsule, RepresentableAsArray};
+
+/// First bytes of every precrypt container.
+pub const MAGIC: &[u8; 8] = b"PRECRYPT";
+/// Latest version of the container layout written by this crate.
+pub const FORMAT_VERSION: u16 = 2;
+
+// Version of files with one capsule per chunk
+const PER_CHUNK_VERSION: u16 = 1;
+// Version of files with a single capsule protecting a file key
+const PER_FILE_VERSION: u16 = 2;
+
+// magic + version + chunk size + chunk count
+const FIXED_LEN: usize = 8 + 2 + 8 + 8;
+
+/// Layout of a precrypt ciphertext, stored in front of the encrypted chunks.
+///
+/// ```text
+/// version 1: magic "PRECRYPT" | 1 u16 | chunk_size u64 | chunk_count u64 | capsules | chunks
+/// version 2: magic "PRECRYPT" | 2 u16 | chunk_size u64 | chunk_count u64 | capsule | sealed file key | chunks
+/// ```
+///
+/// Integers are little endian and every capsule takes `Capsule::serialized_size()` bytes.
+/// Version 1 has one capsule per chunk, version 2 a single capsule and the file key it
+/// protects. The owner secret is never part of the header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+   /// Size of every encrypted chunk except possibly the last one.
+   pub chunk_size: usize,
+   pub chunk_count: usize,
+   /// One capsule per chunk in chunk order, or the file's only capsule.
+   pub capsules: Vec<Vec<u8>>,
+   /// File key encrypted under the only capsule, for files with a single capsule.
+   pub sealed_file_key: Option<Vec<u8>>,
+}
+
+impl Header {
+   /// Number of bytes this header takes up in front of the chunks.
+   pub fn encoded_len(&self) -> usize {
+      FIXED_LEN
+         + self.capsules.len() * Capsule::serialized_size()
+         + self.sealed_file_key.as_ref().map_or(0, |key| key.len())
+   }
+
+   /// Number of bytes the header of a file with `chunks` chunks will take up.
+   pub(crate) fn reserved_len(mode: CapsuleMode, chunks: usize) -> usize {
+      match mode {
+         CapsuleMode::PerChunk => FIXED_LEN + chunks * Capsule::serialized_size(),
+         CapsuleMode::PerFile => FIXED_LEN + Capsule::serialized_size() + SEALED_KEY_LEN,
+      }
+   }
+
+   pub fn mode(&self) -> CapsuleMode {
+      match self.sealed_file_key {
+         Some(_) => CapsuleMode::PerFile,
+         None => CapsuleMode::PerChunk,
+      }
+   }
+
+   pub fn to_bytes(&self) -> Vec<u8> {
+      let version = match self.mode() {
+         CapsuleMode::PerChunk => PER_CHUNK_VERSION,
+         CapsuleMode::PerFile => PER_FILE_VERSION,
+      };
+      let mut bytes = Vec::with_capacity(self.encoded_len());
+      bytes.extend_from_slice(MAGIC);
+      bytes.extend_from_slice(&version.to_le_bytes());
+      bytes.extend_from_slice(&(self.chunk_size as u64).to_le_bytes());
+      bytes.extend_from_slice(&(self.chunk_count as u64).to_le_bytes());
+      for capsule in &self.capsules {
+         bytes.extend_from_slice(capsule);
+      }
+      if let Some(sealed_file_key) = &self.sealed_file_key {
+         bytes.extend_from_slice(sealed_file_key);
+      }
+      bytes
+   }
+
+   pub fn write_to<W: Write>(&self, output: &mut W) -> Result<()> {
+      output.write_all(&self.to_bytes())?;
+      Ok(())
+   }
+
+   /// Reads a header, including the magic bytes, from the start of `input`.
+   pub fn read_from<R: Read>(input: &mut R) -> Result<Header> {
+      let mut magic = [0u8; 8];
+      read_exact(input, &mut magic)?;
+      if &magic != MAGIC {
+         return Err(invalid("missing magic bytes"));
+      }
+      Self::read_after_magic(input)
+   }
+
+   fn read_after_magic<R: Read>(input: &mut R) -> Result<Header> {
+      let mut version = [0u8; 2];
+      read_exact(input, &mut version)?;
+      let mode = version_mode(u16::from_le_bytes(version))?;
+      let chunk_size = read_u64(input)? as usize;
+      let chunk_count = read_u64(input)? as usize;
+      if chunk_size == 0 && chunk_count > 0 {
+         return Err(invalid("chunk size is zero"));
+      }
+
+      let capsule_count = match mode {
+         CapsuleMode::PerChunk => chunk_count,
+         CapsuleMode::PerFile => 1,
+      };
+      let capsule_size = Capsule::serialized_size();
+      let mut capsules: Vec<Vec<u8>> = Vec::new();
+      for _ in 0..capsule_count {
+         let mut capsule = vec![0u8; capsule_size];
+         read_exact(input, &mut capsule)?;
+         capsules.push(capsule);
+      }
+      let sealed_file_key = match mode {
+         CapsuleMode::PerChunk => None,
+         CapsuleMode::PerFile => {
+            let mut sealed_file_key = vec![0u8; SEALED_KEY_LEN];
+            read_exact(input, &mut sealed_file_key)?;
+            Some(sealed_file_key)
+         }
+      };
+      Ok(Header {
+         chunk_size,
+         chunk_count,
+         capsules,
+         sealed_file_key,
+      })
+   }
+
+   /// Checks that `body_len` bytes of ciphertext split into exactly `chunk_count` chunks.
+   pub(crate) fn check_body_len(&self, body_len: u64) -> Result<()> {
+      let chunks = count_chunks(body_len, self.chunk_size);
+      if chunks != self.chunk_count {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks,
+            capsules: self.chunk_count,
+         });
+      }
+      Ok(())
+   }
+
+   /// Checks that the keys were produced for the ciphertext this header belongs to.
+   ///
+   /// Keys granting part of the file must match the capsules of the chunks they grant, and
+   /// keys made before data was appended the capsules of the chunks from before.
+   pub(crate) fn check_keys(&self, decryption_keys: &DecryptionKeys) -> Result<()> {
+      if self.chunk_size != decryption_keys.chunk_size {
+         return Err(invalid(&format!(
+            "chunk size {} does not match the keys' chunk size {}",
+            self.chunk_size, decryption_keys.chunk_size
+         )));
+      }
+      if self.sealed_file_key != decryption_keys.sealed_file_key {
+         return Err(invalid("file key does not match the keys' file key"));
+      }
+      // Keys of the whole file signed before data was appended to it cover its first chunks,
+      // keys without a manifest to vouch for their capsules have to cover all of them
+      let first_chunk = decryption_keys.first_chunk();
+      let granted = first_chunk..first_chunk + decryption_keys.capsules.len();
+      let signed = decryption_keys.manifest.is_some();
+      let capsules = match self.capsules.get(granted.clone()) {
+         Some(capsules) if signed || granted.end == self.capsules.len() => capsules,
+         _ => {
+            return Err(PrecryptError::ChunkCountMismatch {
+               chunks: self.capsules.len(),
+               capsules: granted.end,
+            })
+         }
+      };
+      match capsules
+         .iter()
+         .zip(&decryption_keys.capsules)
+         .position(|(a, b)| a != b)
+      {
+         Some(index) => Err(invalid(&format!(
+            "capsule {} does not match the keys' capsule",
+            first_chunk + index
+         ))),
+         None => Ok(()),
+      }
+   }
+
+   /// Bytes after the header holding the chunks of the keys, if not every chunk of the file.
+   pub(crate) fn keys_body(&self, decryption_keys: &DecryptionKeys) -> Option<Range<u64>> {
+      let chunks = decryption_keys.capsules.len();
+      match decryption_keys.granted_body() {
+         Some(granted) => Some(granted),
+         // Data was appended after the keys were made
+         None if decryption_keys.mode() == CapsuleMode::PerChunk && chunks < self.chunk_count => {
+            Some(0..(chunks * decryption_keys.chunk_size) as u64)
+         }
+         None => None,
+      }
+   }
+}
+
+/// Header of a file encrypted to the owner's public key, in either capsule mode.
+impl From<&CapsuleManifest> for Header {
+   fn from(manifest: &CapsuleManifest) -> Self {
+      Header {
+         chunk_size: manifest.chunk_size,
+         chunk_count: manifest.chunk_count(),
+         capsules: manifest.capsules.clone(),
+         sealed_file_key: manifest.sealed_file_key.clone(),
+      }
+   }
+}
+
+/// Header of the file the keys belong to, as needed to convert legacy files.
+impl From<&RecryptionKeys> for Header {
+   fn from(keys: &RecryptionKeys) -> Self {
+      Header {
+         chunk_size: keys.chunk_size,
+         chunk_count: keys_chunk_count(keys.mode(), &keys.capsules, keys.manifest.as_ref()),
+         capsules: keys.capsules.clone(),
+         sealed_file_key: keys.sealed_file_key.clone(),
+      }
+   }
+}
+
+/// Header of the file the keys belong to, as needed to convert legacy files.
+impl From<&DecryptionKeys> for Header {
+   fn from(keys: &DecryptionKeys) -> Self {
+      Header {
+         chunk_size: keys.chunk_size,
+         chunk_count: keys_chunk_count(keys.mode(), &keys.capsules, keys.manifest.as_ref()),
+         capsules: keys.capsules.clone(),
+         sealed_file_key: keys.sealed_file_key.clone(),
+      }
+   }
+}
+
+// A file with a single capsule only knows its chunk count from the manifest
+fn keys_chunk_count(mode: CapsuleMode, capsules: &[Vec<u8>], manifest: Option<&Manifest>) -> usize {
+   match (mode, manifest) {
+      (CapsuleMode::PerFile, Some(manifest)) => manifest.chunk_count(),
+      _ => capsules.len(),
+   }
+}
+
+/// Reads the header if `input` starts with one.
+///
+/// Legacy headerless ciphertexts have no magic bytes, in which case the bytes read while
+/// looking for them are returned so the caller can put them back in front of `input`.
+pub(crate) fn detect_header<R: Read>(input: &mut R) -> Result<(Option<Header>, Vec<u8>)> {
+   let mut peeked = Vec::new();
+   input.take(MAGIC.len() as u64).read_to_end(&mut peeked)?;
+   if peeked != MAGIC {
+      return Ok((None, peeked));
+   }
+   let header = Header::read_after_magic(input)?;
+   Ok((Some(header), Vec::new()))
+}
+
+/// Number of bytes taken up by the header `bytes` start with, once its fixed part is there.
+///
+/// Lets callers that receive a ciphertext in pieces wait for the whole header.
+#[cfg(feature = "wasm")]
+pub(crate) fn header_len(bytes: &[u8]) -> Result<Option<usize>> {
+   if bytes.len() < FIXED_LEN {
+      return Ok(None);
+   }
+   let mode = version_mode(u16::from_le_bytes([bytes[8], bytes[9]]))?;
+   let chunk_count = u64::from_le_bytes(bytes[18..FIXED_LEN].try_into().unwrap());
+   let len = match mode {
+      CapsuleMode::PerChunk => usize::try_from(chunk_count)
+         .ok()
+         .and_then(|chunks| chunks.checked_mul(Capsule::serialized_size()))
+         .and_then(|capsules_len| capsules_len.checked_add(FIXED_LEN)),
+      CapsuleMode::PerFile => Some(Header::reserved_len(mode, 1)),
+   };
+   len.map(Some).ok_or_else(|| invalid("chunk count is too large"))
+}
+
+fn version_mode(version: u16) -> Result<CapsuleMode> {
+   match version {
+      PER_CHUNK_VERSION => Ok(CapsuleMode::PerChunk),
+      PER_FILE_VERSION => Ok(CapsuleMode::PerFile),
+      _ => Err(invalid(&format!("unsupported format version {}", version))),
+   }
+}
+
+pub(crate) fn count_chunks(body_len: u64, chunk_size: usize) -> usize {
+   if chunk_size == 0 {
+      return 0;
+   }
+   body_len.div_ceil(chunk_size as u64) as usize
+}
+
+fn read_u64<R: Read>(input: &mut R) -> Result<u64> {
+   let mut bytes = [0u8; 8];
+   read_exact(input, &mut bytes)?;
+   Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_exact<R: Read>(input: &mut R, buffer: &mut [u8]) -> Result<()> {
+   input.read_exact(buffer).map_err(|err| match err.kind() {
+      std::io::ErrorKind::UnexpectedEof => invalid("header is truncated"),
+      _ => PrecryptError::Io(err),
+   })
+}
+
+fn invalid(msg: &str) -> PrecryptError {
+   PrecryptError::InvalidHeader(msg.to_string())
+}
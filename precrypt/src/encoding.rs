@@ -0,0 +1,215 @@
+use crate::{PrecryptError, Result};
+use serde::de::{self, DeserializeOwned, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::io::{BufWriter, Write};
+use zeroize::Zeroizing;
+
+// CBOR's self-described tag, written in front of CBOR keys so they can be told apart
+const CBOR_TAG: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// How keys and manifests are written to bytes.
+///
+/// Any encoding is read back by `decode_keys`, as is the JSON with number arrays that
+/// older versions wrote.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyEncoding {
+   /// JSON with every byte string as base64, readable and easy to exchange.
+   #[default]
+   Json,
+   /// CBOR with raw byte strings, the most compact for storage.
+   Cbor,
+}
+
+impl KeyEncoding {
+   /// Encoding of keys written by `encode_keys`, to write them back the same way.
+   pub fn detect(bytes: &[u8]) -> KeyEncoding {
+      // JSON always starts with an object, possibly after whitespace
+      match bytes.iter().find(|byte| !byte.is_ascii_whitespace()) {
+         Some(b'{') => KeyEncoding::Json,
+         _ => KeyEncoding::Cbor,
+      }
+   }
+}
+
+/// Writes `RecryptionKeys`, `ProxyKeys`, `DecryptionKeys` or a `CapsuleManifest` in `encoding`.
+///
+/// The bytes are wiped when dropped, since recryption keys hold the owner secret.
+pub fn encode_keys<T: Serialize>(keys: &T, encoding: KeyEncoding) -> Result<Zeroizing<Vec<u8>>> {
+   // Sized up front so growing the buffer doesn't leave copies of the keys behind
+   let mut counter = ByteCounter(0);
+   serialize_keys(keys, &mut counter, encoding)?;
+   let mut bytes = Zeroizing::new(Vec::with_capacity(counter.0));
+   serialize_keys(keys, &mut *bytes, encoding)?;
+   Ok(bytes)
+}
+
+// Like `encode_keys`, writing to `output` as the keys are serialized
+pub(crate) fn write_keys<T: Serialize, W: Write>(
+   keys: &T,
+   output: W,
+   encoding: KeyEncoding,
+) -> Result<()> {
+   let mut output = BufWriter::new(output);
+   serialize_keys(keys, &mut output, encoding)?;
+   output.flush()?;
+   Ok(())
+}
+
+fn serialize_keys<T: Serialize, W: Write>(
+   keys: &T,
+   mut output: W,
+   encoding: KeyEncoding,
+) -> Result<()> {
+   match encoding {
+      KeyEncoding::Json => {
+         serde_json::to_writer(&mut output, keys).map_err(|err| invalid(&err.to_string()))?
+      }
+      KeyEncoding::Cbor => {
+         output.write_all(&CBOR_TAG)?;
+         ciborium::ser::into_writer(keys, &mut output).map_err(|err| invalid(&err.to_string()))?;
+      }
+   }
+   Ok(())
+}
+
+// Counts the bytes written to it
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+   fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      self.0 += buf.len();
+      Ok(buf.len())
+   }
+
+   fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+   }
+}
+
+/// Reads keys written by `encode_keys` in any encoding, or by older versions.
+pub fn decode_keys<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+   match KeyEncoding::detect(bytes) {
+      KeyEncoding::Json => serde_json::from_slice(bytes).map_err(|err| invalid(&err.to_string())),
+      KeyEncoding::Cbor => {
+         let body = bytes.strip_prefix(&CBOR_TAG[..]).unwrap_or(bytes);
+         ciborium::de::from_reader(body).map_err(|err| invalid(&err.to_string()))
+      }
+   }
+}
+
+fn invalid(msg: &str) -> PrecryptError {
+   PrecryptError::InvalidKeyEncoding(msg.to_string())
+}
+
+/// Serde helpers for byte string fields: base64 in readable formats, raw bytes otherwise.
+///
+/// Reading also accepts the number arrays of keys written by older versions, into a
+/// `Vec<u8>` or a wrapper around one like `Zeroizing`.
+pub(crate) mod bytes {
+   use super::*;
+
+   pub fn serialize<S: Serializer>(
+      bytes: &[u8],
+      serializer: S,
+   ) -> std::result::Result<S::Ok, S::Error> {
+      match serializer.is_human_readable() {
+         // Wiped once written, it may be the owner secret
+         true => serializer.serialize_str(&Zeroizing::new(base64::encode(bytes))),
+         false => serializer.serialize_bytes(bytes),
+      }
+   }
+
+   pub fn deserialize<'de, D: Deserializer<'de>, T: From<Vec<u8>>>(
+      deserializer: D,
+   ) -> std::result::Result<T, D::Error> {
+      let bytes = match deserializer.is_human_readable() {
+         true => deserializer.deserialize_any(BytesVisitor),
+         false => deserializer.deserialize_byte_buf(BytesVisitor),
+      };
+      bytes.map(T::from)
+   }
+}
+
+/// Serde helpers for lists of byte strings, see `bytes`.
+pub(crate) mod byte_list {
+   use super::*;
+
+   pub fn serialize<S: Serializer>(
+      list: &[Vec<u8>],
+      serializer: S,
+   ) -> std::result::Result<S::Ok, S::Error> {
+      serializer.collect_seq(list.iter().map(|bytes| ByteSlice(bytes)))
+   }
+
+   pub fn deserialize<'de, D: Deserializer<'de>>(
+      deserializer: D,
+   ) -> std::result::Result<Vec<Vec<u8>>, D::Error> {
+      let list = Vec::<ByteString>::deserialize(deserializer)?;
+      Ok(list.into_iter().map(|bytes| bytes.0).collect())
+   }
+}
+
+/// Serde helpers for optional byte strings, see `bytes`.
+pub(crate) mod optional_bytes {
+   use super::*;
+
+   pub fn serialize<S: Serializer, B: AsRef<[u8]>>(
+      bytes: &Option<B>,
+      serializer: S,
+   ) -> std::result::Result<S::Ok, S::Error> {
+      bytes.as_ref().map(|bytes| ByteSlice(bytes.as_ref())).serialize(serializer)
+   }
+
+   pub fn deserialize<'de, D: Deserializer<'de>>(
+      deserializer: D,
+   ) -> std::result::Result<Option<Vec<u8>>, D::Error> {
+      Ok(Option::<ByteString>::deserialize(deserializer)?.map(|bytes| bytes.0))
+   }
+}
+
+pub(crate) struct ByteSlice<'a>(pub &'a [u8]);
+
+impl Serialize for ByteSlice<'_> {
+   fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+      bytes::serialize(self.0, serializer)
+   }
+}
+
+struct ByteString(Vec<u8>);
+
+impl<'de> Deserialize<'de> for ByteString {
+   fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+      bytes::deserialize(deserializer).map(ByteString)
+   }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+   type Value = Vec<u8>;
+
+   fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      write!(f, "a base64 string, a byte string or an array of bytes")
+   }
+
+   fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<Vec<u8>, E> {
+      base64::decode(value).map_err(E::custom)
+   }
+
+   fn visit_bytes<E: de::Error>(self, value: &[u8]) -> std::result::Result<Vec<u8>, E> {
+      Ok(value.to_vec())
+   }
+
+   fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> std::result::Result<Vec<u8>, E> {
+      Ok(value)
+   }
+
+   fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Vec<u8>, A::Error> {
+      let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+      while let Some(byte) = seq.next_element::<u8>()? {
+         bytes.push(byte);
+      }
+      Ok(bytes)
+   }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+use umbral_pre::{
+   CapsuleFragVerificationError, DeserializationError, EncryptionError, KeyFragVerificationError,
+   ReencryptionError,
+};
+
+#[derive(Debug)]
+pub enum PrecryptError {
+   /// Reading the input or writing the output failed.
+   Io(std::io::Error),
+   /// The options can't be used to process a file (e.g. zero workers).
+   InvalidConfig(String),
+   /// The output file exists and may not be overwritten.
+   OutputExists(String),
+   /// The container header is malformed or doesn't belong to the given keys.
+   InvalidHeader(String),
+   /// A capsule could not be parsed from its bytes.
+   InvalidCapsule { index: usize, source: DeserializationError },
+   /// A secret key, public key or capsule fragment could not be parsed from its bytes.
+   InvalidKey(DeserializationError),
+   /// The ciphertext and the key material don't describe the same number of chunks.
+   ChunkCountMismatch { chunks: usize, capsules: usize },
+   /// A plaintext chunk could not be encrypted.
+   Encryption { index: usize, source: EncryptionError },
+   /// A ciphertext chunk could not be decrypted.
+   Decryption { index: usize, source: ReencryptionError },
+   /// A chunk encrypted with a derived key was modified, moved or used with the wrong key.
+   AuthenticationFailed { index: usize },
+   /// The ciphertext ends before its final chunk.
+   Truncated,
+   /// A proxy's translated key for a chunk was not made from the owner's signed delegation.
+   Verification { index: usize, source: CapsuleFragVerificationError },
+   /// A key fragment was not signed by the owner for this receiver.
+   KeyVerification(KeyFragVerificationError),
+   /// The manifest is not signed by the owner or doesn't describe the keys.
+   InvalidManifest(String),
+   /// A ciphertext chunk is not the one the manifest lists at its position.
+   ChunkMismatch { index: usize },
+   /// The decrypted plaintext doesn't match the digest in the manifest.
+   DigestMismatch,
+   /// Keys or a manifest can't be encoded, or decoded from bytes in any known encoding.
+   InvalidKeyEncoding(String),
+   /// The owner secret is sealed, the keys must be unlocked with the passphrase first.
+   PassphraseRequired,
+   /// The passphrase doesn't open the sealed owner secret.
+   WrongPassphrase,
+   /// The plaintext is not an archive, or its index is malformed.
+   InvalidArchive(String),
+   /// A saved checkpoint can't be used to resume with this input, output or keys.
+   InvalidCheckpoint(String),
+   /// Fewer proxies contributed translated keys than the delegation's threshold.
+   ThresholdNotMet { shares: usize, threshold: usize },
+   /// The keys were signed by another owner than the one whose verifying key was expected.
+   VerifyingKeyMismatch,
+}
+
+pub type Result<T> = std::result::Result<T, PrecryptError>;
+
+impl fmt::Display for PrecryptError {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      match self {
+         Self::Io(err) => write!(f, "I/O error: {}", err),
+         Self::InvalidConfig(msg) => write!(f, "Invalid configuration: {}", msg),
+         Self::OutputExists(path) => write!(f, "Output file {} already exists", path),
+         Self::InvalidHeader(msg) => write!(f, "Invalid header: {}", msg),
+         Self::InvalidCapsule { index, source } => {
+            write!(f, "Invalid capsule for chunk {}: {}", index, source)
+         }
+         Self::InvalidKey(err) => write!(f, "Invalid key: {}", err),
+         Self::ChunkCountMismatch { chunks, capsules } => write!(
+            f,
+            "Ciphertext has {} chunks but keys have {} capsules",
+            chunks, capsules
+         ),
+         Self::Encryption { index, source } => {
+            write!(f, "Failed to encrypt chunk {}: {}", index, source)
+         }
+         Self::Decryption { index, source } => {
+            write!(f, "Failed to decrypt chunk {}: {}", index, source)
+         }
+         Self::AuthenticationFailed { index } => write!(
+            f,
+            "Chunk {} failed authentication: it was tampered with, moved or the key is wrong",
+            index
+         ),
+         Self::Truncated => write!(f, "Ciphertext ends before its final chunk"),
+         Self::Verification { index, source } => {
+            write!(f, "Failed to verify the translated key for chunk {}: {}", index, source)
+         }
+         Self::KeyVerification(err) => write!(f, "Failed to verify the translation key: {}", err),
+         Self::InvalidManifest(msg) => write!(f, "Invalid manifest: {}", msg),
+         Self::InvalidKeyEncoding(msg) => write!(f, "Invalid key encoding: {}", msg),
+         Self::ChunkMismatch { index } => write!(
+            f,
+            "Chunk {} does not match the manifest: it was modified, moved or comes from another file",
+            index
+         ),
+         Self::DigestMismatch => write!(f, "Decrypted plaintext does not match the manifest"),
+         Self::PassphraseRequired => {
+            write!(f, "The owner secret is sealed, a passphrase is needed to use it")
+         }
+         Self::WrongPassphrase => write!(f, "Wrong passphrase for the sealed owner secret"),
+         Self::InvalidArchive(msg) => write!(f, "Invalid archive: {}", msg),
+         Self::InvalidCheckpoint(msg) => write!(f, "Can't resume: {}", msg),
+         Self::ThresholdNotMet { shares, threshold } => write!(
+            f,
+            "Translated keys from {} proxies are needed but only {} were given",
+            threshold, shares
+         ),
+         Self::VerifyingKeyMismatch => {
+            write!(f, "Keys were not signed by the owner of the expected verifying key")
+         }
+      }
+   }
+}
+
+impl std::error::Error for PrecryptError {
+   fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+      match self {
+         Self::Io(err) => Some(err),
+         _ => None,
+      }
+   }
+}
+
+impl From<std::io::Error> for PrecryptError {
+   fn from(err: std::io::Error) -> Self {
+      // Errors that were passed through a reader or writer come back out as they were
+      if err.get_ref().is_some_and(|inner| inner.is::<PrecryptError>()) {
+         return *err.into_inner().unwrap().downcast::<PrecryptError>().unwrap();
+      }
+      Self::Io(err)
+   }
+}
+
+impl From<PrecryptError> for std::io::Error {
+   fn from(err: PrecryptError) -> Self {
+      match err {
+         PrecryptError::Io(err) => err,
+         err => std::io::Error::other(err),
+      }
+   }
+}
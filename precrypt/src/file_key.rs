@@ -0,0 +1,108 @@
+use crate::{PrecryptError, Result};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use umbral_pre::*;
+use zeroize::{Zeroize, Zeroizing};
+
+const FILE_KEY_LEN: usize = 32;
+/// Length of the file key once it is encrypted under the file's capsule.
+pub const SEALED_KEY_LEN: usize = FILE_KEY_LEN + 40;
+/// Poly1305 tag added to every chunk encrypted with a derived key.
+pub const CHUNK_TAG_LEN: usize = 16;
+
+/// Symmetric key protecting every chunk of a file encrypted with a single capsule.
+///
+/// Each chunk is encrypted with its own key, derived from the file key, the file's capsule,
+/// the chunk index and whether it is the last chunk (the STREAM construction). Chunks can
+/// therefore not be reordered, swapped between files or dropped from the end unnoticed.
+pub(crate) struct FileKey([u8; FILE_KEY_LEN]);
+
+impl FileKey {
+   pub fn random() -> Result<Self> {
+      let mut key = [0u8; FILE_KEY_LEN];
+      getrandom::getrandom(&mut key)
+         .map_err(|err| PrecryptError::Io(err.into()))?;
+      Ok(FileKey(key))
+   }
+
+   /// Encrypts the file key to the owner, returning the capsule and the sealed key.
+   pub fn seal(&self, owner_pubkey: &PublicKey) -> Result<(Capsule, Vec<u8>)> {
+      let (capsule, sealed) = encrypt(owner_pubkey, &self.0)
+         .map_err(|source| PrecryptError::Encryption { index: 0, source })?;
+      Ok((capsule, sealed.to_vec()))
+   }
+
+   /// Decrypts the sealed file key with a re-encrypted capsule fragment.
+   pub fn open(
+      receiver_key: &SecretKey,
+      owner_pubkey: &PublicKey,
+      capsule: &Capsule,
+      translated_keys: impl IntoIterator<Item = VerifiedCapsuleFrag>,
+      sealed: &[u8],
+   ) -> Result<Self> {
+      let key = decrypt_reencrypted(receiver_key, owner_pubkey, capsule, translated_keys, sealed)
+         .map_err(|source| PrecryptError::Decryption { index: 0, source })?;
+      Self::from_bytes(&key)
+   }
+
+   /// Decrypts the sealed file key with the owner secret it was sealed to.
+   pub fn open_original(owner_secret: &SecretKey, capsule: &Capsule, sealed: &[u8]) -> Option<Self> {
+      let key = decrypt_original(owner_secret, capsule, sealed).ok()?;
+      Self::from_bytes(&key).ok()
+   }
+
+   fn from_bytes(bytes: &[u8]) -> Result<Self> {
+      let key: [u8; FILE_KEY_LEN] = bytes.try_into().map_err(|_| {
+         PrecryptError::InvalidKey(DeserializationError::SizeMismatch(SizeMismatchError::new(
+            bytes.len(),
+            FILE_KEY_LEN,
+         )))
+      })?;
+      Ok(FileKey(key))
+   }
+
+   fn chunk_cipher(&self, capsule: &[u8], index: usize, last: bool) -> ChaCha20Poly1305 {
+      let mut info = b"precrypt chunk key".to_vec();
+      info.extend_from_slice(&(index as u64).to_be_bytes());
+      info.push(last as u8);
+      let mut chunk_key = Zeroizing::new([0u8; 32]);
+      Hkdf::<Sha256>::new(Some(capsule), &self.0)
+         .expand(&info, chunk_key.as_mut())
+         .unwrap();
+      ChaCha20Poly1305::new(Key::from_slice(chunk_key.as_ref()))
+   }
+
+   // Every chunk has its own key so a constant nonce is never reused
+   pub fn encrypt_chunk(
+      &self,
+      capsule: &[u8],
+      index: usize,
+      last: bool,
+      plaintext: &[u8],
+   ) -> Vec<u8> {
+      self.chunk_cipher(capsule, index, last)
+         .encrypt(Nonce::from_slice(&[0u8; 12]), plaintext)
+         .unwrap()
+   }
+
+   pub fn decrypt_chunk(
+      &self,
+      capsule: &[u8],
+      index: usize,
+      last: bool,
+      ciphertext: &[u8],
+   ) -> Result<Zeroizing<Vec<u8>>> {
+      self.chunk_cipher(capsule, index, last)
+         .decrypt(Nonce::from_slice(&[0u8; 12]), ciphertext)
+         .map(Zeroizing::new)
+         .map_err(|_| PrecryptError::AuthenticationFailed { index })
+   }
+}
+
+impl Drop for FileKey {
+   fn drop(&mut self) {
+      self.0.zeroize();
+   }
+}
@@ -1,279 +1,949 @@
-use indicatif::ProgressBar;
-use indicatif::ProgressStyle;
 use serde::{Deserialize, Serialize};
-use generic_array::GenericArray;
+use std::fmt;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
-use std::sync::mpsc;
-use std::thread;
+use std::ops::Range;
 use umbral_pre::*;
 use umbral_pre::DeserializableFromArray;
+use zeroize::Zeroizing;
 
-struct EnChunkMessage {
-   bytes: Vec<u8>,
-   capsule: Vec<u8>,
-   index: usize,
+mod archive;
+pub use archive::{Archive, ArchiveEntry};
+
+#[cfg(feature = "async")]
+mod asynchronous;
+
+mod checkpoint;
+use checkpoint::Checkpointer;
+
+mod collection;
+pub use collection::{Collection, CollectionKeys};
+
+mod config;
+pub use config::{
+   Decryptor, DecryptorBuilder, Encryptor, EncryptorBuilder, Overwrite, Recryptor,
+   RecryptorBuilder, DEFAULT_CHUNK_SIZE,
+};
+
+mod container;
+pub use container::{Header, FORMAT_VERSION, MAGIC};
+use container::{count_chunks, detect_header};
+
+mod encoding;
+pub use encoding::{decode_keys, encode_keys, KeyEncoding};
+
+mod error;
+pub use error::{PrecryptError, Result};
+
+mod file_key;
+use file_key::{FileKey, CHUNK_TAG_LEN};
+
+mod manifest;
+pub use manifest::Manifest;
+use manifest::{hash, new_file_id};
+
+mod passphrase;
+use passphrase::SealedSecret;
+
+mod pipeline;
+
+mod progress;
+pub use progress::{NoProgress, Progress};
+
+mod proxy;
+pub use proxy::{
+   combine_decryption_keys, owner_signer, proxy_recrypt, split_recryption_keys, ProxyKeys,
+};
+use proxy::FragmentVerifier;
+
+mod reader;
+pub use reader::DecryptingReader;
+
+/// WebAssembly bindings for decrypting and generating keys in the browser.
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+// Nonce and authentication tag umbral adds to every encrypted chunk
+const CHUNK_OVERHEAD: usize = 40;
+
+/// How the chunks of a file are tied to umbral capsules.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CapsuleMode {
+   /// Every chunk is encrypted by umbral under its own capsule.
+   #[default]
+   PerChunk,
+   /// A single capsule protects a file key from which every chunk's key is derived, so
+   /// keys stay the same size for any file and granting access takes one re-encryption.
+   PerFile,
 }
 
-struct DeChunkMessage {
-   bytes: Vec<u8>,
-   index: usize,
+impl CapsuleMode {
+   // Bytes an encrypted chunk is larger than its plaintext
+   pub(crate) fn chunk_overhead(self) -> usize {
+      match self {
+         CapsuleMode::PerChunk => CHUNK_OVERHEAD,
+         CapsuleMode::PerFile => CHUNK_TAG_LEN,
+      }
+   }
+}
+
+/// An encrypted file's capsules and chunk hashes: everything about it but the owner secret.
+///
+/// Encrypting to the owner's public key produces this instead of `RecryptionKeys`, so a data
+/// producer or upload service can encrypt for an owner without ever holding their secret.
+/// It can be handed to the owner openly, who combines it with their secret when granting
+/// access, with `recrypt_manifest` or `RecryptionKeys::from_manifest`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CapsuleManifest {
+   #[serde(with = "crate::encoding::bytes")]
+   owner_pubkey: Vec<u8>,
+   #[serde(with = "crate::encoding::bytes")]
+   file_id: Vec<u8>,
+   #[serde(with = "crate::encoding::byte_list")]
+   capsules: Vec<Vec<u8>>,
+   chunk_size: usize,
+   #[serde(
+      default,
+      skip_serializing_if = "Option::is_none",
+      with = "crate::encoding::optional_bytes"
+   )]
+   sealed_file_key: Option<Vec<u8>>,
+   // Signed by the owner into the `Manifest` of the keys
+   #[serde(with = "crate::encoding::byte_list")]
+   chunk_hashes: Vec<Vec<u8>>,
+   #[serde(with = "crate::encoding::bytes")]
+   plaintext_hash: Vec<u8>,
+}
+
+impl CapsuleManifest {
+   pub fn mode(&self) -> CapsuleMode {
+      match self.sealed_file_key {
+         Some(_) => CapsuleMode::PerFile,
+         None => CapsuleMode::PerChunk,
+      }
+   }
+
+   /// Public key of the owner the file was encrypted to.
+   pub fn owner_pubkey(&self) -> &[u8] {
+      &self.owner_pubkey
+   }
+
+   pub fn chunk_count(&self) -> usize {
+      self.chunk_hashes.len()
+   }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RecryptionKeys {
-   owner_secret: Vec<u8>,
+   // Empty while the secret is sealed with a passphrase, wiped when the keys are dropped
+   #[serde(default, skip_serializing_if = "Vec::is_empty", with = "crate::encoding::bytes")]
+   owner_secret: Zeroizing<Vec<u8>>,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   sealed_owner_secret: Option<SealedSecret>,
+   #[serde(with = "crate::encoding::byte_list")]
    capsules: Vec<Vec<u8>>,
    chunk_size: usize,
+   #[serde(
+      default,
+      skip_serializing_if = "Option::is_none",
+      with = "crate::encoding::optional_bytes"
+   )]
+   sealed_file_key: Option<Vec<u8>>,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   manifest: Option<Manifest>,
+}
+
+impl RecryptionKeys {
+   /// Keys of a file encrypted to the public key of `owner_secret`, signing its manifest.
+   ///
+   /// The signature vouches for chunk hashes whoever encrypted the file computed, so only
+   /// take manifests from producers trusted with the plaintext. Fails with
+   /// `InvalidManifest` if the file was encrypted to another public key.
+   pub fn from_manifest(manifest: &CapsuleManifest, owner_secret: &SecretKey) -> Result<Self> {
+      if manifest.owner_pubkey != owner_secret.public_key().to_array().to_vec() {
+         return Err(PrecryptError::InvalidManifest(
+            "file was encrypted to another owner".to_string(),
+         ));
+      }
+      let signed_manifest = Manifest::sign(
+         &owner_signer(owner_secret),
+         manifest.file_id.clone(),
+         manifest.chunk_size,
+         manifest.chunk_hashes.clone(),
+         &manifest.capsules,
+         manifest.plaintext_hash.clone(),
+      );
+      let secret_box = owner_secret.to_secret_array();
+      Ok(RecryptionKeys {
+         owner_secret: Zeroizing::new(secret_box.as_secret().to_vec()),
+         sealed_owner_secret: None,
+         capsules: manifest.capsules.clone(),
+         chunk_size: manifest.chunk_size,
+         sealed_file_key: manifest.sealed_file_key.clone(),
+         manifest: Some(signed_manifest),
+      })
+   }
+
+   pub fn mode(&self) -> CapsuleMode {
+      match self.sealed_file_key {
+         Some(_) => CapsuleMode::PerFile,
+         None => CapsuleMode::PerChunk,
+      }
+   }
+
+   /// Manifest signed when the file was encrypted, missing for keys of older versions.
+   pub fn manifest(&self) -> Option<&Manifest> {
+      self.manifest.as_ref()
+   }
+
+   /// Chunks holding the plaintext bytes in `bytes`, to grant access to just those with
+   /// `Recryptor::recrypt_range`.
+   ///
+   /// Fails if the keys' chunk size can't even hold the overhead of a chunk.
+   pub fn chunks_covering(&self, bytes: Range<u64>) -> Result<Range<usize>> {
+      let overhead = self.mode().chunk_overhead();
+      if self.chunk_size <= overhead {
+         return Err(PrecryptError::InvalidConfig(format!(
+            "chunk size must be larger than {}",
+            overhead
+         )));
+      }
+      let plaintext_chunk_size = (self.chunk_size - overhead) as u64;
+      let (chunks, start, end) = match &self.manifest {
+         // Appended chunks start after the padding of the chunk before them
+         Some(manifest) => {
+            let start = manifest.chunk_at(bytes.start, overhead);
+            let end = match bytes.end > bytes.start {
+               true => manifest.chunk_at(bytes.end - 1, overhead) + 1,
+               false => start,
+            };
+            (manifest.chunk_count(), start, end)
+         }
+         None => (
+            self.capsules.len(),
+            (bytes.start / plaintext_chunk_size) as usize,
+            bytes.end.div_ceil(plaintext_chunk_size) as usize,
+         ),
+      };
+      Ok(start.min(chunks)..end.min(chunks))
+   }
+
+   /// Whether the owner secret is sealed, so translating the keys needs the passphrase.
+   pub fn is_sealed(&self) -> bool {
+      self.sealed_owner_secret.is_some()
+   }
+
+   /// Seals the owner secret with a key derived from `passphrase` by Argon2id.
+   ///
+   /// The plaintext secret is dropped from the keys, only `unlock` can bring it back.
+   pub fn seal(&mut self, passphrase: &[u8]) -> Result<()> {
+      if self.is_sealed() {
+         return Err(PrecryptError::InvalidConfig("owner secret is already sealed".to_string()));
+      }
+      if passphrase.is_empty() {
+         return Err(PrecryptError::InvalidConfig("passphrase must not be empty".to_string()));
+      }
+      self.sealed_owner_secret = Some(SealedSecret::seal(&self.owner_secret, passphrase)?);
+      self.owner_secret = Zeroizing::default();
+      Ok(())
+   }
+
+   /// Returns a copy of the keys with the owner secret opened with `passphrase`.
+   pub fn unlock(&self, passphrase: &[u8]) -> Result<RecryptionKeys> {
+      let sealed = self.sealed_owner_secret.as_ref().ok_or_else(|| {
+         PrecryptError::InvalidConfig("owner secret is not sealed".to_string())
+      })?;
+      let mut keys = self.clone();
+      keys.owner_secret = sealed.open(passphrase)?;
+      keys.sealed_owner_secret = None;
+      Ok(keys)
+   }
+
+   // Parses the owner secret, failing if it's still sealed
+   pub(crate) fn owner_secret(&self) -> Result<SecretKey> {
+      if self.is_sealed() {
+         return Err(PrecryptError::PassphraseRequired);
+      }
+      SecretKey::from_bytes(&self.owner_secret).map_err(PrecryptError::InvalidKey)
+   }
+}
+
+// Never prints the owner secret, sealed or not
+impl fmt::Debug for RecryptionKeys {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("RecryptionKeys")
+         .field("owner_secret", &"[redacted]")
+         .field("sealed", &self.is_sealed())
+         .field("mode", &self.mode())
+         .field("capsules", &self.capsules.len())
+         .field("chunk_size", &self.chunk_size)
+         .finish_non_exhaustive()
+   }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DecryptionKeys {
+   #[serde(with = "crate::encoding::bytes")]
    owner_pubkey: Vec<u8>,
+   #[serde(with = "crate::encoding::byte_list")]
    capsules: Vec<Vec<u8>>,
+   // One or more capsule fragments per capsule, one from each proxy that contributed
+   #[serde(with = "crate::encoding::byte_list")]
    translated_keys: Vec<Vec<u8>>,
    chunk_size: usize,
+   #[serde(
+      default,
+      skip_serializing_if = "Option::is_none",
+      with = "crate::encoding::optional_bytes"
+   )]
+   sealed_file_key: Option<Vec<u8>>,
+   // Number of proxies whose capsule fragments are needed to decrypt
+   #[serde(default = "default_threshold")]
+   threshold: usize,
+   // Key of the owner's signer, every capsule fragment is verified against it
+   #[serde(with = "crate::encoding::bytes")]
+   verifying_key: Vec<u8>,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   manifest: Option<Manifest>,
+}
+
+fn default_threshold() -> usize {
+   1
 }
 
 impl DecryptionKeys {
-   fn next_keys(&mut self) -> (Vec<u8>, Vec<u8>) {
-      let capsule: Vec<u8> = self.capsules.remove(0);
-      let translated_key: Vec<u8> = self.translated_keys.remove(0);
-      return (capsule, translated_key.clone());
+   pub fn mode(&self) -> CapsuleMode {
+      match self.sealed_file_key {
+         Some(_) => CapsuleMode::PerFile,
+         None => CapsuleMode::PerChunk,
+      }
+   }
+
+   /// Verifying key of the owner's signer, to compare with the one the owner published.
+   pub fn verifying_key(&self) -> &[u8] {
+      &self.verifying_key
+   }
+
+   /// Manifest signed when the file was encrypted, missing for keys of older versions.
+   pub fn manifest(&self) -> Option<&Manifest> {
+      self.manifest.as_ref()
+   }
+
+   /// Checks that the keys are signed with the verifying key the owner published.
+   ///
+   /// The translated keys and manifest are only verified against the verifying key the keys
+   /// carry, so whoever hands out the keys could sign them with a key of their own.
+   pub fn check_verifying_key(&self, verifying_key: &PublicKey) -> Result<()> {
+      match verifying_key.to_array().as_slice() == self.verifying_key.as_slice() {
+         true => Ok(()),
+         false => Err(PrecryptError::VerifyingKeyMismatch),
+      }
+   }
+
+   /// Chunk of the file the keys start at, zero unless they grant only part of it.
+   pub fn first_chunk(&self) -> usize {
+      self.granted_chunks().map_or(0, |chunks| chunks.start)
+   }
+
+   /// Whether the keys only decrypt some of the file's chunks, see `Recryptor::recrypt_range`.
+   ///
+   /// Decrypting with such keys produces the plaintext of those chunks alone, so check this
+   /// when the whole file is expected.
+   pub fn is_partial(&self) -> bool {
+      self.granted_chunks().is_some()
+   }
+
+   /// Chunks of the file the keys decrypt, as signed by the owner, if not the whole file.
+   pub fn granted_chunks(&self) -> Option<Range<usize>> {
+      self.manifest.as_ref()?.granted_chunks()
+   }
+
+   /// Bytes of the container holding the granted chunks, for keys granting part of a file.
+   ///
+   /// Lets the receiver download just those bytes, e.g. with an HTTP range request, and
+   /// decrypt them on their own. The end is past the end of the container when the last
+   /// chunk is granted, since it may be shorter than the others.
+   pub fn ciphertext_range(&self) -> Option<Range<u64>> {
+      let chunks = self.manifest.as_ref()?.chunk_count();
+      let granted = self.granted_body()?;
+      let header_len = Header::reserved_len(CapsuleMode::PerChunk, chunks) as u64;
+      Some(header_len + granted.start..header_len + granted.end)
+   }
+
+   // Plaintext bytes before the chunk at `index` of the keys
+   pub(crate) fn plaintext_offset(&self, index: usize) -> u64 {
+      let overhead = self.mode().chunk_overhead();
+      let first_chunk = self.first_chunk();
+      match &self.manifest {
+         Some(manifest) => {
+            manifest.plaintext_offset(first_chunk + index, overhead)
+               - manifest.plaintext_offset(first_chunk, overhead)
+         }
+         None => (index * (self.chunk_size - overhead)) as u64,
+      }
+   }
+
+   // Chunk of the keys holding the plaintext byte at `position`
+   pub(crate) fn chunk_at(&self, position: u64) -> usize {
+      let overhead = self.mode().chunk_overhead();
+      let first_chunk = self.first_chunk();
+      match &self.manifest {
+         Some(manifest) => {
+            let first_offset = manifest.plaintext_offset(first_chunk, overhead);
+            manifest.chunk_at(first_offset + position, overhead) - first_chunk
+         }
+         None => (position / (self.chunk_size - overhead) as u64) as usize,
+      }
+   }
+
+   // Length of the chunk at `index` of the keys read whole from its slot, without the
+   // padding that follows it once data is appended after it
+   pub(crate) fn unpadded_len(&self, index: usize, slot: &[u8]) -> Result<usize> {
+      match &self.manifest {
+         Some(manifest) => Ok(manifest.check_chunk(self.first_chunk() + index, slot)?.len()),
+         None => Ok(slot.len()),
+      }
+   }
+
+   // Plaintext offsets where the digests of the plaintext split, none for partial keys
+   pub(crate) fn segment_starts(&self) -> Vec<u64> {
+      match &self.manifest {
+         Some(manifest) if !self.is_partial() => {
+            manifest.segment_starts(self.mode().chunk_overhead())
+         }
+         _ => Vec::new(),
+      }
+   }
+
+   // Bytes of the granted chunks after the header of the whole file, for partial keys
+   fn granted_body(&self) -> Option<Range<u64>> {
+      let chunks = self.granted_chunks()?;
+      Some((chunks.start * self.chunk_size) as u64..(chunks.end * self.chunk_size) as u64)
+   }
+
+   // Returns the manifest once its signature and capsules are checked
+   fn verified_manifest(&self, verifier: &FragmentVerifier) -> Result<Option<&Manifest>> {
+      if let Some(manifest) = &self.manifest {
+         manifest.verify(&verifier.verifying_key, self.chunk_size, &self.capsules)?;
+      }
+      Ok(self.manifest.as_ref())
+   }
+
+   // Keys the capsule fragments must have been made for to be used by `receiver_key`
+   fn fragment_verifier(&self, receiver_key: &SecretKey) -> Result<FragmentVerifier> {
+      Ok(FragmentVerifier {
+         verifying_key: PublicKey::from_bytes(&self.verifying_key)
+            .map_err(PrecryptError::InvalidKey)?,
+         owner_pubkey: PublicKey::from_bytes(&self.owner_pubkey)
+            .map_err(PrecryptError::InvalidKey)?,
+         receiver_pubkey: receiver_key.public_key(),
+      })
+   }
+
+   // Opens the file key of a file encrypted with a single capsule
+   fn open_file_key(&self, receiver_key: &SecretKey, verifier: &FragmentVerifier) -> Result<FileKey> {
+      let sealed_file_key = self.sealed_file_key.as_ref().unwrap();
+      if self.capsules.len() != 1 || self.translated_keys.len() != 1 {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks: 1,
+            capsules: self.capsules.len(),
+         });
+      }
+      let capsule = parse_capsule(0, &self.capsules[0])?;
+      let translated_keys = verifier.verify(0, &capsule, &self.translated_keys[0])?;
+      FileKey::open(
+         receiver_key,
+         &verifier.owner_pubkey,
+         &capsule,
+         translated_keys,
+         sealed_file_key,
+      )
+   }
+
+   // Checks that every capsule has a capsule fragment from enough proxies
+   fn check_threshold(&self) -> Result<()> {
+      let fragment_size = CapsuleFrag::serialized_size();
+      let shares = self
+         .translated_keys
+         .iter()
+         .map(|translated_key| translated_key.len() / fragment_size)
+         .min()
+         .unwrap_or(self.threshold);
+      if shares < self.threshold {
+         return Err(PrecryptError::ThresholdNotMet {
+            shares,
+            threshold: self.threshold,
+         });
+      }
+      Ok(())
    }
+
+   // Capsule and translated key of the chunk at `index`
+   fn chunk_keys(&self, index: usize) -> Option<(&[u8], &[u8])> {
+      let capsule = self.capsules.get(index)?;
+      let translated_key = self.translated_keys.get(index)?;
+      Some((capsule, translated_key))
+   }
+}
+
+fn parse_capsule(index: usize, bytes: &[u8]) -> Result<Capsule> {
+   Capsule::from_bytes(bytes).map_err(|source| PrecryptError::InvalidCapsule { index, source })
 }
 
+// Creates a new empty file at the path, replacing any existing one if allowed
+fn create_output<P: AsRef<std::path::Path>>(output_file: P, overwrite: Overwrite) -> Result<File> {
+   let output_file = output_file.as_ref();
+   if output_file.exists() {
+      if overwrite == Overwrite::Refuse {
+         return Err(PrecryptError::OutputExists(output_file.display().to_string()));
+      }
+      std::fs::remove_file(output_file)?;
+   }
+   let out = OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .open(output_file)?;
+   Ok(out)
+}
+
+// Reads up to `size` bytes, only returning fewer at the end of `input`
+fn read_chunk<R: Read>(input: &mut R, size: usize) -> Result<Vec<u8>> {
+   let mut buffer = Vec::new();
+   input.take(size as u64).read_to_end(&mut buffer)?;
+   Ok(buffer)
+}
+
+// Reads chunk after chunk, peeking at a single byte past each one to know which is the last
+//
+// Reading a whole chunk ahead instead would hold one chunk more than the workers do.
+struct ChunkReader<R> {
+   input: R,
+   peeked: Option<u8>,
+}
+
+impl<R: Read> ChunkReader<R> {
+   fn new(input: R) -> Self {
+      ChunkReader {
+         input,
+         peeked: None,
+      }
+   }
+
+   // Up to `size` bytes and whether they end `input`, wiped when they're dropped
+   //
+   // The buffer is allocated once so growing it leaves no copies of the plaintext behind
+   fn next_chunk(&mut self, size: usize) -> Result<(Zeroizing<Vec<u8>>, bool)> {
+      let mut buffer = Zeroizing::new(Vec::with_capacity(size + 1));
+      buffer.extend(self.peeked.take());
+      let wanted = (size + 1 - buffer.len()) as u64;
+      (&mut self.input).take(wanted).read_to_end(&mut buffer)?;
+      if buffer.len() > size {
+         self.peeked = buffer.pop();
+         return Ok((buffer, false));
+      }
+      Ok((buffer, true))
+   }
+}
+
+/// Encrypts the file at `input_path` with `threads` workers, each encrypting chunks of
+/// `memory_size / threads` bytes.
+///
+/// Use `Encryptor` to pick the chunk size, capsule mode or a progress observer.
 pub fn precrypt_file(
    input_path: &str,
    file_key: SecretKey,
    output_file: &str,
    threads: usize,
    memory_size: usize,
-) -> RecryptionKeys {
-   if memory_size % threads != 0 {
-      panic!("'memory_size' must be evenly divisible by 'threads'")
-   }
-
-   let f = File::open(input_path).unwrap();
-   let file_size = f.metadata().unwrap().len();
-   let mut batches_remaining = (file_size as f64 / memory_size as f64).ceil() as u64;
-   let mut capsules: Vec<Vec<u8>> = Vec::new();
-   // Remove output file if it exists
-   if std::path::Path::new(output_file).exists() {
-      std::fs::remove_file(output_file).unwrap();
-   }
-   let mut out = OpenOptions::new()
-      .write(true)
-      .append(true)
-      .create_new(true)
-      .open(output_file)
-      .unwrap();
-
-   println!("Encrypting file: {:?}", input_path);
-   let bar = ProgressBar::new(batches_remaining);
-   bar.set_style(
-      ProgressStyle::default_bar()
-         .template("{eta} [{bar:40.cyan/blue}] {percent}%")
-         .progress_chars("=>-"),
-   );
-   while batches_remaining > 0 {
-      let (batch_encrypted, batch_capsules) =
-         precrypt_batch(&f, file_key.public_key(), threads, memory_size);
-      capsules.extend(batch_capsules);
-      // Append encrypted chunks to file
-      out.write(&batch_encrypted).unwrap();
-      batches_remaining -= 1;
-      bar.inc(1);
-   }
-   bar.finish_and_clear();
-
-   // Write out recryption keys
-   let secret_box = file_key.to_secret_array();
-   let secret_array = secret_box.as_secret().to_vec();
-   let recryption_keys = RecryptionKeys {
-      owner_secret: secret_array,
-      capsules: capsules,
-      chunk_size: (memory_size / threads) + 40,
-   };
-   return recryption_keys;
+) -> Result<RecryptionKeys> {
+   legacy_encryptor(threads, memory_size)?.encrypt_file(input_path, file_key, output_file)
 }
 
-fn precrypt_batch(
-   f: &File,
-   pubkey: PublicKey,
+/// Encrypts everything read from `input` like `precrypt_file` and writes the ciphertext
+/// to `output`.
+///
+/// Only the chunks are written since the header can't be known before the end of `input`;
+/// write `Header::from(&recryption_keys)` in front of them to get a self-describing container.
+pub fn precrypt_stream<R: Read, W: Write>(
+   input: R,
+   file_key: SecretKey,
+   output: W,
    threads: usize,
    memory_size: usize,
-) -> (Vec<u8>, Vec<Vec<u8>>) {
-   let (tx, rx) = mpsc::channel();
-   for x in 0..threads {
-      let mut buffer = Vec::new();
-      f.take((memory_size / threads) as u64)
-         .read_to_end(&mut buffer)
-         .unwrap();
-      if buffer.len() == 0 {
-         break;
+) -> Result<RecryptionKeys> {
+   legacy_encryptor(threads, memory_size)?.encrypt_stream(input, file_key, output)
+}
+
+// Splits the memory between the threads like the functions taking both always did
+fn legacy_encryptor<'a>(threads: usize, memory_size: usize) -> Result<Encryptor<'a>> {
+   Encryptor::builder()
+      .workers(threads)
+      .chunk_size(memory_size.checked_div(threads).unwrap_or(memory_size))
+      .memory_budget(memory_size)
+      .build()
+}
+
+// Encrypts the chunks after the ones `checkpointer` already recorded to `owner_pubkey`
+fn precrypt_chunks<R: Read, W: Write>(
+   input: R,
+   mut output: W,
+   owner_pubkey: &PublicKey,
+   threads: usize,
+   checkpointer: &mut Checkpointer,
+) -> Result<CapsuleManifest> {
+   let mode = checkpointer.checkpoint.mode();
+   let chunk_size = checkpointer.checkpoint.chunk_size;
+   let plaintext_chunk_size = chunk_size - mode.chunk_overhead();
+   // With a single capsule, the file key it protects encrypts the chunks
+   let chunk_key = match (mode, checkpointer.checkpoint.file_key.take()) {
+      (CapsuleMode::PerFile, Some(file_key)) => {
+         Some((file_key, checkpointer.checkpoint.capsules[0].clone()))
       }
-      let txc = tx.clone();
-      thread::spawn(move || {
-         let (capsule, cipher_chunk) = encrypt(&pubkey, &buffer).unwrap();
-         let message = EnChunkMessage {
-            bytes: cipher_chunk.to_vec(),
-            index: x,
-            capsule: capsule.to_array().to_vec(),
-         };
-         txc.send(message).unwrap();
-      });
-   }
+      (CapsuleMode::PerFile, None) => {
+         return Err(PrecryptError::InvalidCheckpoint("file key is not open".to_string()))
+      }
+      (CapsuleMode::PerChunk, _) => None,
+   };
+   let start = checkpointer.start;
+   let mut plaintext_hash = std::mem::take(&mut checkpointer.checkpoint.plaintext_hash);
 
-   // drop tx manually, to ensure that only senders in spawned threads are still in use
-   drop(tx);
+   let mut chunks = ChunkReader::new(input);
+   let mut done = false;
+   pipeline::run(
+      threads,
+      |index| {
+         if done {
+            return Ok(None);
+         }
+         let (buffer, last) = chunks.next_chunk(plaintext_chunk_size)?;
+         if buffer.is_empty() && (start + index > 0 || mode == CapsuleMode::PerChunk) {
+            return Ok(None);
+         }
+         done = last;
+         plaintext_hash.update(&buffer);
+         Ok(Some((buffer, last)))
+      },
+      |index, (buffer, last)| {
+         let index = start + index;
+         match &chunk_key {
+            Some((chunk_key, capsule)) => {
+               Ok((chunk_key.encrypt_chunk(capsule, index, last, &buffer), None))
+            }
+            None => encrypt(owner_pubkey, &buffer)
+               .map(|(capsule, cipher_chunk)| (cipher_chunk.to_vec(), Some(capsule)))
+               .map_err(|source| PrecryptError::Encryption { index, source }),
+         }
+      },
+      |_, (cipher_chunk, capsule)| {
+         // Append encrypted chunk to output
+         output.write_all(&cipher_chunk)?;
+         let plaintext_len = cipher_chunk.len() - mode.chunk_overhead();
+         let checkpoint = &mut checkpointer.checkpoint;
+         checkpoint.chunk_hashes.push(hash(&cipher_chunk));
+         if let Some(capsule) = capsule {
+            checkpoint.capsules.push(capsule.to_array().to_vec());
+         }
+         checkpointer.chunk_written(&mut output, plaintext_len)
+      },
+   )?;
+   output.flush()?;
 
-   // Add all the chunk messages to a vector
-   let mut messages: Vec<EnChunkMessage> = Vec::new();
-   for message in rx {
-      messages.push(message);
-   }
-   // Sort messages by index (order in input file)
-   messages.sort_by(|a, b| a.index.cmp(&b.index));
-   // Combine messages into a batch
-   let mut batch: Vec<u8> = Vec::new();
-   let mut capsules: Vec<Vec<u8>> = Vec::new();
-   for m in messages {
-      batch.extend(m.bytes);
-      capsules.push(m.capsule);
-   }
-   return (batch, capsules);
+   let checkpoint = &checkpointer.checkpoint;
+   Ok(CapsuleManifest {
+      owner_pubkey: owner_pubkey.to_array().to_vec(),
+      file_id: new_file_id()?,
+      capsules: checkpoint.capsules.clone(),
+      chunk_size,
+      sealed_file_key: checkpoint.sealed_file_key.clone(),
+      chunk_hashes: checkpoint.chunk_hashes.clone(),
+      // A single digest, the chunks encrypted together are never split into segments
+      plaintext_hash: plaintext_hash.finalize().concat(),
+   })
 }
 
-pub fn recrypt_keys(recryption_keys: RecryptionKeys, receiver_public: PublicKey) -> DecryptionKeys {
-   // Fragmentation/verification is not used because we aren't using proxies
-   let owner_secret: SecretKey = SecretKey::from_bytes(recryption_keys.owner_secret).unwrap();
-   let translation_key = generate_kfrags(
-      &owner_secret,
-      &receiver_public,
-      &Signer::new(SecretKey::random()),
-      1,
-      1,
-      false,
-      false,
-   )[0]
-      .clone();
-
-   let mut translated_keys: Vec<Vec<u8>> = Vec::new();
-   let capsules = recryption_keys.capsules.clone();
-   for capsule_vec in recryption_keys.capsules {
-      let capsule = Capsule::from_array(&GenericArray::from_iter(capsule_vec)).unwrap();
-      let translated_key = reencrypt(&capsule, translation_key.clone());
-      translated_keys.push(translated_key.to_array().to_vec());
-   }
-
-   let decryption_keys = DecryptionKeys {
-      owner_pubkey: owner_secret.public_key().to_array().to_vec(),
-      capsules: capsules,
-      translated_keys: translated_keys,
-      chunk_size: recryption_keys.chunk_size,
-   };
-   return decryption_keys;
+/// Translates the keys to `receiver_public`, acting as the only re-encryption proxy.
+///
+/// Use `Recryptor` to set the workers or a progress observer, or to stream the keys out.
+pub fn recrypt_keys(
+   recryption_keys: RecryptionKeys,
+   receiver_public: PublicKey,
+) -> Result<DecryptionKeys> {
+   Recryptor::builder().build()?.recrypt(&recryption_keys, receiver_public)
+}
+
+/// Translates the keys to every receiver in `receivers`, in the same order.
+///
+/// Use `Recryptor::recrypt_batch` to get each receiver's keys as soon as they're ready
+/// instead of all of them at the end.
+pub fn recrypt_keys_batch(
+   recryption_keys: &RecryptionKeys,
+   receivers: &[PublicKey],
+) -> Result<Vec<DecryptionKeys>> {
+   let mut batch = Vec::with_capacity(receivers.len());
+   Recryptor::builder().build()?.recrypt_batch(recryption_keys, receivers, |_, keys| {
+      batch.push(keys);
+      Ok(())
+   })?;
+   Ok(batch)
+}
+
+/// Translates the keys of every file in the collection to `receiver_public` at once.
+pub fn recrypt_collection(
+   collection: &Collection,
+   receiver_public: PublicKey,
+) -> Result<CollectionKeys> {
+   Recryptor::builder().build()?.recrypt_collection(collection, receiver_public)
 }
 
+/// Grants `receiver_public` access to a file that was encrypted to the owner's public key.
+///
+/// Same as `recrypt_keys` on `RecryptionKeys::from_manifest`, without keeping those keys.
+pub fn recrypt_manifest(
+   manifest: &CapsuleManifest,
+   owner_secret: &SecretKey,
+   receiver_public: PublicKey,
+) -> Result<DecryptionKeys> {
+   recrypt_keys(RecryptionKeys::from_manifest(manifest, owner_secret)?, receiver_public)
+}
+
+/// Like `recrypt_keys`, for keys whose owner secret is sealed with `passphrase`.
+pub fn recrypt_keys_with_passphrase(
+   recryption_keys: RecryptionKeys,
+   receiver_public: PublicKey,
+   passphrase: &[u8],
+) -> Result<DecryptionKeys> {
+   recrypt_keys(recryption_keys.unlock(passphrase)?, receiver_public)
+}
+
+/// Decrypts the file at `input_path` with `threads` workers.
+///
+/// Use `Decryptor` to set a memory budget or a progress observer, or to resume.
 pub fn decrypt_file(
    input_path: &str,
    output_file: &str,
    receiver_key: SecretKey,
-   decryption_keys: &mut DecryptionKeys,
+   decryption_keys: &DecryptionKeys,
    threads: usize,
-) {
-   let mut batches_remaining =
-      (decryption_keys.capsules.len() as f64 / threads as f64).ceil() as u64;
-   println!("Batches needed: {}", batches_remaining);
-   // Read input file
-   let f = File::open(input_path).unwrap();
-   // Remove output file file if it exists
-   if std::path::Path::new(output_file).exists() {
-      std::fs::remove_file(output_file).unwrap();
-   }
-   let mut out = OpenOptions::new()
-      .write(true)
-      .append(true)
-      .create_new(true)
-      .open(output_file)
-      .unwrap();
-
-   println!("Decrypting file: {:?}", input_path);
-   let bar = ProgressBar::new(batches_remaining);
-   bar.set_style(
-      ProgressStyle::default_bar()
-         .template("{eta} [{bar:40.cyan/blue}] {percent}%")
-         .progress_chars("=>-"),
-   );
-   while batches_remaining > 0 {
-      let batch_decrypted = decrypt_batch(&f, &receiver_key, decryption_keys, threads);
-      // Append encrypted chunks to file
-      out.write(&batch_decrypted).unwrap();
-      batches_remaining -= 1;
-      bar.inc(1);
-   }
-   bar.finish_and_clear();
+) -> Result<()> {
+   Decryptor::builder().workers(threads).build()?.decrypt_file(
+      input_path,
+      output_file,
+      receiver_key,
+      decryption_keys,
+   )
 }
 
-fn decrypt_batch(
-   f: &File,
-   receiver_key: &SecretKey,
-   decryption_keys: &mut DecryptionKeys,
+/// Decrypts the ciphertext read from `input` with `threads` workers and writes the
+/// plaintext to `output`.
+///
+/// Accepts both containers and legacy headerless ciphertexts, see `Decryptor::decrypt_stream`.
+pub fn decrypt_stream<R: Read, W: Write>(
+   input: R,
+   output: W,
+   receiver_key: SecretKey,
+   decryption_keys: &DecryptionKeys,
+   threads: usize,
+) -> Result<()> {
+   Decryptor::builder().workers(threads).build()?.decrypt_stream(
+      input,
+      output,
+      receiver_key,
+      decryption_keys,
+   )
+}
+
+/// Converts a legacy headerless ciphertext into a container.
+///
+/// The header is built from the keys produced when the file was encrypted, so the
+/// ciphertext no longer depends on them being stored next to it.
+pub fn convert_legacy_file(input_path: &str, output_file: &str, header: &Header) -> Result<()> {
+   let mut f = File::open(input_path)?;
+   let file_size = f.metadata()?.len();
+   let (existing, peeked) = detect_header(&mut f)?;
+   if existing.is_some() {
+      return Err(PrecryptError::InvalidHeader(
+         "file already has a header".to_string(),
+      ));
+   }
+   header.check_body_len(file_size)?;
+
+   let mut out = create_output(output_file, Overwrite::Replace)?;
+   header.write_to(&mut out)?;
+   std::io::copy(&mut Cursor::new(peeked).chain(f), &mut out)?;
+   out.flush()?;
+   Ok(())
+}
+
+fn decrypt_chunks<R: Read, W: Write>(
+   input: R,
+   output: W,
+   receiver_key: SecretKey,
+   decryption_keys: &DecryptionKeys,
    threads: usize,
-) -> Vec<u8> {
-   let (tx, rx) = mpsc::channel();
-   for x in 0..threads {
-      let mut buffer = Vec::new();
-      f.take(decryption_keys.chunk_size as u64)
-         .read_to_end(&mut buffer)
-         .unwrap();
-      if buffer.len() == 0 {
-         break;
+   checkpointer: &mut Checkpointer,
+) -> Result<()> {
+   decryption_keys.check_threshold()?;
+   let verifier = decryption_keys.fragment_verifier(&receiver_key)?;
+   let manifest = decryption_keys.verified_manifest(&verifier)?.cloned();
+   match decryption_keys.mode() {
+      CapsuleMode::PerChunk => decrypt_chunk_capsules(
+         input,
+         output,
+         &receiver_key,
+         manifest.as_ref(),
+         decryption_keys,
+         threads,
+         checkpointer,
+      ),
+      CapsuleMode::PerFile => {
+         let chunk_key = decryption_keys.open_file_key(&receiver_key, &verifier)?;
+         decrypt_derived_chunks(
+            input,
+            output,
+            &chunk_key,
+            decryption_keys,
+            manifest.as_ref(),
+            threads,
+            checkpointer,
+         )
       }
+   }
+}
 
-      // Make clones of variables the thread will use
-      let txc = tx.clone();
-      let (capsule_vec, translated_key_vec) = decryption_keys.next_keys();
-      let translated_key = VerifiedCapsuleFrag::from_verified_bytes(translated_key_vec).unwrap();
-      let receiver_key = receiver_key.clone();
-      let owner_pubkey_vec = decryption_keys.owner_pubkey.clone();
-      let owner_pubkey = PublicKey::from_array(&GenericArray::from_iter(owner_pubkey_vec)).unwrap();
-      let capsule = Capsule::from_array(&GenericArray::from_iter(capsule_vec)).unwrap();
-      thread::spawn(move || {
+// Decrypts chunks that each have their own capsule
+fn decrypt_chunk_capsules<R: Read, W: Write>(
+   mut input: R,
+   mut output: W,
+   receiver_key: &SecretKey,
+   manifest: Option<&Manifest>,
+   decryption_keys: &DecryptionKeys,
+   threads: usize,
+   checkpointer: &mut Checkpointer,
+) -> Result<()> {
+   let verifier = decryption_keys.fragment_verifier(receiver_key)?;
+   let start = checkpointer.start;
+   let first_chunk = decryption_keys.first_chunk();
+   let capsules = decryption_keys.capsules.len();
+   let mut plaintext_hash = std::mem::take(&mut checkpointer.checkpoint.plaintext_hash);
+   let chunk_size = decryption_keys.chunk_size;
+   pipeline::run(
+      threads,
+      |index| {
+         let index = start + index;
+         let buffer = read_chunk(&mut input, chunk_size)?;
+         if buffer.is_empty() {
+            return Ok(None);
+         }
+         let (capsule_vec, translated_key) = match decryption_keys.chunk_keys(index) {
+            Some(keys) => keys,
+            None => {
+               // Count the rest of the ciphertext to report how many chunks there are
+               let remaining = std::io::copy(&mut input, &mut std::io::sink())?;
+               return Err(PrecryptError::ChunkCountMismatch {
+                  chunks: index + 1 + count_chunks(remaining, chunk_size),
+                  capsules: index,
+               });
+            }
+         };
+         let capsule = parse_capsule(first_chunk + index, capsule_vec)?;
+         Ok(Some((buffer, capsule, translated_key)))
+      },
+      |index, (buffer, capsule, translated_key)| {
+         // Position of the chunk in the whole file
+         let index = first_chunk + start + index;
+         let buffer = match manifest {
+            Some(manifest) => manifest.check_chunk(index, &buffer)?,
+            None => &buffer,
+         };
+         // Check the proxies' work before trusting it
+         let translated_keys = verifier.verify(index, &capsule, translated_key)?;
          // Decrypt the cipher
-         let plaintext = decrypt_reencrypted(
-            &receiver_key,
-            &owner_pubkey,
+         decrypt_reencrypted(
+            receiver_key,
+            &verifier.owner_pubkey,
             &capsule,
-            [translated_key],
-            &buffer,
+            translated_keys,
+            buffer,
          )
-         .unwrap();
-         txc.send(DeChunkMessage {
-            bytes: plaintext.to_vec(),
-            index: x,
-         })
-         .unwrap();
-      });
-   }
-
-   // drop tx manually, to ensure that only senders in spawned threads are still in use
-   drop(tx);
-
-   // Add all the chunk messages to a vector
-   let mut messages: Vec<DeChunkMessage> = Vec::new();
-   for message in rx {
-      messages.push(message);
-   }
-   // Sort messages by index (order in input file)
-   messages.sort_by(|a, b| a.index.cmp(&b.index));
-   // Combine messages into a batch
-   let mut batch: Vec<u8> = Vec::new();
-   for m in messages {
-      batch.extend(m.bytes);
-   }
-   return batch;
+         .map(|plaintext| Zeroizing::new(plaintext.into_vec()))
+         .map_err(|source| PrecryptError::Decryption { index, source })
+      },
+      |_, plaintext| {
+         // Append decrypted chunk to output
+         output.write_all(&plaintext)?;
+         plaintext_hash.update(&plaintext);
+         checkpointer.chunk_written(&mut output, plaintext.len())
+      },
+   )?;
+   output.flush()?;
+
+   // Keys left over means the ciphertext was cut short
+   let chunks = checkpointer.checkpoint.chunks;
+   if chunks != capsules {
+      return Err(PrecryptError::ChunkCountMismatch { chunks, capsules });
+   }
+   match manifest {
+      Some(manifest) => manifest.check_plaintext(chunks, &plaintext_hash.finalize()),
+      None => Ok(()),
+   }
+}
+
+// Decrypts chunks whose keys are derived from the file key
+fn decrypt_derived_chunks<R: Read, W: Write>(
+   input: R,
+   mut output: W,
+   chunk_key: &FileKey,
+   decryption_keys: &DecryptionKeys,
+   manifest: Option<&Manifest>,
+   threads: usize,
+   checkpointer: &mut Checkpointer,
+) -> Result<()> {
+   let capsule = &decryption_keys.capsules[0];
+   let chunk_size = decryption_keys.chunk_size;
+   let start = checkpointer.start;
+   let mut plaintext_hash = std::mem::take(&mut checkpointer.checkpoint.plaintext_hash);
+   let mut chunks = ChunkReader::new(input);
+   let mut done = false;
+   pipeline::run(
+      threads,
+      |_| {
+         if done {
+            return Ok(None);
+         }
+         let (buffer, last) = chunks.next_chunk(chunk_size)?;
+         if buffer.is_empty() {
+            return Ok(None);
+         }
+         done = last;
+         Ok(Some((buffer, last)))
+      },
+      |index, (buffer, last)| {
+         let index = start + index;
+         let buffer = match manifest {
+            Some(manifest) => manifest.check_chunk(index, &buffer)?,
+            None => &buffer[..],
+         };
+         chunk_key.decrypt_chunk(capsule, index, last, buffer)
+      },
+      |_, plaintext| {
+         // Append decrypted chunk to output
+         output.write_all(&plaintext)?;
+         plaintext_hash.update(&plaintext);
+         checkpointer.chunk_written(&mut output, plaintext.len())
+      },
+   )?;
+   output.flush()?;
+
+   // Even an empty file has a final chunk
+   let chunks = checkpointer.checkpoint.chunks;
+   if chunks == 0 {
+      return Err(PrecryptError::Truncated);
+   }
+   match manifest {
+      Some(manifest) => manifest.check_plaintext(chunks, &plaintext_hash.finalize()),
+      None => Ok(()),
+   }
 }
@@ -1,27 +1,265 @@
 use clap::Arg;
 use clap::{App, AppSettings};
+use indicatif::{ProgressBar, ProgressStyle};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Write};
 use umbral_pre::*;
-use precrypt::{precrypt_file, recrypt_keys, decrypt_file, RecryptionKeys, DecryptionKeys};
+use zeroize::{Zeroize, Zeroizing};
+use precrypt::{
+    combine_decryption_keys, convert_legacy_file, decode_keys, encode_keys, owner_signer,
+    split_recryption_keys, Archive, CapsuleManifest, CapsuleMode, Collection, CollectionKeys,
+    DecryptionKeys, Decryptor,
+    Encryptor, Header, KeyEncoding, Overwrite, PrecryptError, Progress, ProxyKeys, RecryptionKeys,
+    Recryptor,
+};
 
 #[derive(Serialize, Deserialize, Clone)]
 struct Keypair {
     public_key: Vec<u8>,
     secret_key: Vec<u8>,
+    // Key recipients check translated keys against, derived from the secret key
+    #[serde(default)]
+    verifying_key: Vec<u8>,
 }
 
-fn main() -> std::io::Result<()> {
+impl Drop for Keypair {
+    fn drop(&mut self) {
+        self.secret_key.zeroize();
+    }
+}
+
+// Reads the secret key of a keypair file, wiping the bytes read once it's parsed
+fn read_secret_key(path: &std::ffi::OsStr) -> Result<SecretKey, Box<dyn std::error::Error>> {
+    let bytes = Zeroizing::new(std::fs::read(path)?);
+    let keypair: Keypair = serde_json::from_slice(&bytes)?;
+    Ok(SecretKey::from_bytes(&keypair.secret_key).map_err(PrecryptError::InvalidKey)?)
+}
+
+// Draws the chunks processed by the library as a progress bar on the terminal
+struct BarProgress {
+    bar: ProgressBar,
+}
+
+impl BarProgress {
+    fn new() -> Self {
+        let bar = ProgressBar::new(0);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{eta} [{bar:40.cyan/blue}] {percent}%")
+                .progress_chars("=>-"),
+        );
+        BarProgress { bar }
+    }
+}
+
+impl Progress for BarProgress {
+    fn start(&mut self, chunks: usize, done: usize) {
+        self.bar.set_length(chunks as u64);
+        self.bar.set_position(done as u64);
+    }
+
+    fn chunk_done(&mut self, _index: usize) {
+        self.bar.inc(1);
+    }
+
+    fn finish(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+fn key_encoding(matches: &clap::ArgMatches) -> KeyEncoding {
+    match matches.value_of("encoding") {
+        Some("cbor") => KeyEncoding::Cbor,
+        _ => KeyEncoding::Json,
+    }
+}
+
+fn overwrite_policy(sub_matches: &clap::ArgMatches) -> Overwrite {
+    match sub_matches.is_present("no_overwrite") {
+        true => Overwrite::Refuse,
+        false => Overwrite::Replace,
+    }
+}
+
+// Passphrase used for sealed keys when no file descriptor is given, before prompting
+const PASSPHRASE_VAR: &str = "PRECRYPT_PASSPHRASE";
+
+// Reads the passphrase from --passphrase_fd, the environment or the terminal, in that order
+fn read_passphrase(
+    sub_matches: &clap::ArgMatches,
+    confirm: bool,
+) -> std::io::Result<Zeroizing<String>> {
+    if let Some(fd) = sub_matches.value_of("passphrase_fd") {
+        let mut line = Zeroizing::new(String::new());
+        BufReader::new(File::open(format!("/dev/fd/{}", fd))?).read_line(&mut line)?;
+        let len = line.trim_end_matches(['\r', '\n']).len();
+        line.truncate(len);
+        return Ok(line);
+    }
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_VAR) {
+        return Ok(Zeroizing::new(passphrase));
+    }
+    let passphrase = Zeroizing::new(rpassword::prompt_password("Passphrase: ")?);
+    if confirm {
+        let repeated = Zeroizing::new(rpassword::prompt_password("Repeat passphrase: ")?);
+        if repeated != passphrase {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "passphrases don't match",
+            ));
+        }
+    }
+    Ok(passphrase)
+}
+
+// Opens sealed keys with the passphrase, other keys are returned as they are
+fn unlock_keys(
+    recryption_keys: RecryptionKeys,
+    sub_matches: &clap::ArgMatches,
+) -> Result<RecryptionKeys, Box<dyn std::error::Error>> {
+    match recryption_keys.is_sealed() {
+        true => Ok(recryption_keys.unlock(read_passphrase(sub_matches, false)?.as_bytes())?),
+        false => Ok(recryption_keys),
+    }
+}
+
+// Options shared by the commands encrypting files
+fn encryption_args<'a>() -> [Arg<'a>; 6] {
+    [
+        Arg::new("threads")
+            .short('t')
+            .long("threads")
+            .validator(|s| s.parse::<usize>())
+            .help("Number of threads to use for parallel encryption [default: number of cores]")
+            .required(false)
+            .takes_value(true),
+        Arg::new("chunk_size")
+            .short('c')
+            .long("chunk_size")
+            .validator(|s| s.parse::<usize>())
+            .default_value("5000000")
+            .help("Number of bytes encrypted as one chunk")
+            .required(false)
+            .takes_value(true),
+        Arg::new("memory_size")
+            .short('m')
+            .long("memory_size")
+            .validator(|s| s.parse::<usize>())
+            .help("Maximum number of bytes to be stored in memory at once, fewer threads are used to stay below it")
+            .required(false)
+            .takes_value(true),
+        Arg::new("no_overwrite")
+            .long("no_overwrite")
+            .help("Fail instead of replacing an existing output file")
+            .required(false),
+        Arg::new("single_capsule")
+            .short('s')
+            .long("single_capsule")
+            .help("Use one capsule for the whole file so the keys stay small")
+            .required(false),
+        Arg::new("resume")
+            .short('r')
+            .long("resume")
+            .help("Continue an interrupted encryption from its last checkpoint")
+            .required(false),
+    ]
+}
+
+fn build_encryptor<'a>(sub_matches: &clap::ArgMatches) -> Result<Encryptor<'a>, PrecryptError> {
+    let mode = match sub_matches.is_present("single_capsule") {
+        true => CapsuleMode::PerFile,
+        false => CapsuleMode::PerChunk,
+    };
+    let mut builder = Encryptor::builder()
+        .chunk_size(sub_matches.value_of_t("chunk_size").unwrap())
+        .mode(mode)
+        .overwrite(overwrite_policy(sub_matches))
+        .progress(BarProgress::new());
+    if sub_matches.is_present("threads") {
+        builder = builder.workers(sub_matches.value_of_t("threads").unwrap());
+    }
+    if sub_matches.is_present("memory_size") {
+        builder = builder.memory_budget(sub_matches.value_of_t("memory_size").unwrap());
+    }
+    builder.build()
+}
+
+fn build_recryptor<'a>(sub_matches: &clap::ArgMatches) -> Result<Recryptor<'a>, PrecryptError> {
+    let mut builder = Recryptor::builder().progress(BarProgress::new());
+    if sub_matches.is_present("threads") {
+        builder = builder.workers(sub_matches.value_of_t("threads").unwrap());
+    }
+    builder.build()
+}
+
+// Parses a range written as <start>-<end>, the end excluded
+fn parse_range(range: &str) -> Result<std::ops::Range<u64>, String> {
+    let parsed = range
+        .split_once('-')
+        .and_then(|(start, end)| Some(start.parse::<u64>().ok()?..end.parse::<u64>().ok()?));
+    parsed.ok_or_else(|| format!("'{}' is not a range like 0-100", range))
+}
+
+fn parse_pubkey(json: &str) -> Result<PublicKey, Box<dyn std::error::Error>> {
+    let public_vec: Vec<u8> = serde_json::from_str(json)?;
+    Ok(PublicKey::from_bytes(&public_vec).map_err(PrecryptError::InvalidKey)?)
+}
+
+// Reads the recryption keys, or signs a capsule manifest when the owner's keypair is given
+fn read_recryption_keys(
+    sub_matches: &clap::ArgMatches,
+) -> Result<RecryptionKeys, Box<dyn std::error::Error>> {
+    // Wiped once parsed, the keys hold the owner secret
+    let keys_path = sub_matches.value_of_os("recryption_keys").unwrap();
+    let keys_array = Zeroizing::new(std::fs::read(keys_path)?);
+    match sub_matches.value_of_os("owner_keypair") {
+        Some(keypair_path) => {
+            let manifest: CapsuleManifest = decode_keys(&keys_array)?;
+            let owner_secret = read_secret_key(keypair_path)?;
+            Ok(RecryptionKeys::from_manifest(&manifest, &owner_secret)?)
+        }
+        None => unlock_keys(decode_keys(&keys_array)?, sub_matches),
+    }
+}
+
+// Replaces the file at `path` in one step, so a crash leaves either the old or the new bytes
+fn replace_file(path: &std::ffi::OsStr, bytes: &[u8]) -> std::io::Result<()> {
+    let mut temp_path = path.to_os_string();
+    temp_path.push(".tmp");
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(bytes)?;
+    temp_file.sync_all()?;
+    std::fs::rename(temp_path, path)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = App::new("precrypt")
         .about("Cli for pre-network")
         .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::new("encoding")
+                .long("encoding")
+                .global(true)
+                .possible_values(["json", "cbor"])
+                .default_value("json")
+                .help("Encoding of the key files written, key files are read in any encoding")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("passphrase_fd")
+                .long("passphrase_fd")
+                .global(true)
+                .validator(|s| s.parse::<u32>())
+                .help("File descriptor to read the passphrase of sealed keys from, instead of PRECRYPT_PASSPHRASE or a prompt")
+                .takes_value(true),
+        )
         .subcommand(
             App::new("encrypt")
                 .about("Encrypts file with proxy based re-encryption")
                 .args([
                     Arg::new("input_file")
-                        .help("Path of the file to be encrypted")
+                        .help("Path of the file to be encrypted, or of a directory to encrypt as an archive")
                         .allow_invalid_utf8(true)
                         .takes_value(true)
                         .required(true),
@@ -37,38 +275,215 @@ fn main() -> std::io::Result<()> {
                         .allow_invalid_utf8(true)
                         .help("Output path for the new encrypted file")
                         .required(true),
+                    Arg::new("passphrase")
+                        .short('p')
+                        .long("passphrase")
+                        .help("Seal the owner secret in the recryption keys with a passphrase")
+                        .required(false),
+                ])
+                .args(encryption_args()),
+        )
+        .subcommand(
+            App::new("deposit")
+                .about("Encrypts file to an owner's public key, without their secret key")
+                .args([
+                    Arg::new("input_file")
+                        .help("Path of the file to be encrypted")
+                        .allow_invalid_utf8(true)
+                        .takes_value(true)
+                        .required(true),
+                    Arg::new("owner_pubkey")
+                        .help("Public key byte array of the owner of the file")
+                        .required(true),
+                    Arg::new("output_manifest")
+                        .allow_invalid_utf8(true)
+                        .help("Output path for the capsule manifest to hand to the owner")
+                        .required(true),
+                    Arg::new("output_file")
+                        .allow_invalid_utf8(true)
+                        .help("Output path for the new encrypted file")
+                        .required(true),
+                ])
+                .args(encryption_args()),
+        )
+        .subcommand(
+            App::new("append")
+                .about("Encrypts a file as new chunks at the end of an encrypted file")
+                .args([
+                    Arg::new("input_file")
+                        .help("Path of the file to be appended")
+                        .allow_invalid_utf8(true)
+                        .takes_value(true)
+                        .required(true),
+                    Arg::new("recryption_keys")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the recryption keys of the encrypted file, updated in their encoding to cover the new chunks")
+                        .required(true),
+                    Arg::new("encrypted_file")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the encrypted file to append to")
+                        .required(true),
                     Arg::new("threads")
                         .short('t')
                         .long("threads")
                         .validator(|s| s.parse::<usize>())
-                        .default_value("10")
-                        .help("Number of threads to use for parallel encryption")
+                        .help("Number of threads to use for parallel encryption [default: number of cores]")
                         .required(false)
                         .takes_value(true),
-                    Arg::new("memory_size")
-                        .short('m')
-                        .long("memory_size")
+                ]),
+        )
+        .subcommand(
+            App::new("recrypt")
+                .about("Translates encryption key to a new pubkey")
+                .args([
+                    Arg::new("recryption_keys")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the recryption keys json file, of the capsule manifest with --owner_keypair, or of the collection with --collection")
+                        .required(true),
+                    Arg::new("receiver_pubkey")
+                        .help("Public key byte array of the receiver of the file, or with --batch the path of a file with one per line")
+                        .required(true),
+                    Arg::new("output")
+                        .allow_invalid_utf8(true)
+                        .help("Output path for decryption keys, or with --batch a directory with one file per receiver")
+                        .required(true),
+                    Arg::new("batch")
+                        .short('b')
+                        .long("batch")
+                        .help("Grant access to every receiver listed in a file at once")
+                        .conflicts_with_all(&["chunks", "bytes"])
+                        .required(false),
+                    Arg::new("collection")
+                        .short('c')
+                        .long("collection")
+                        .help("Grant access to every file of a collection made with collect at once")
+                        .conflicts_with_all(&["batch", "chunks", "bytes", "owner_keypair"])
+                        .required(false),
+                    Arg::new("chunks")
+                        .long("chunks")
+                        .validator(parse_range)
+                        .help("Only grant access to the chunks in <start>-<end>, the end excluded")
+                        .conflicts_with("bytes")
+                        .required(false)
+                        .takes_value(true),
+                    Arg::new("bytes")
+                        .long("bytes")
+                        .validator(parse_range)
+                        .help("Only grant access to the chunks holding the plaintext bytes in <start>-<end>, the end excluded")
+                        .required(false)
+                        .takes_value(true),
+                    Arg::new("owner_keypair")
+                        .short('k')
+                        .long("owner_keypair")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the owner's keypair, to grant access to a file deposited with a capsule manifest")
+                        .required(false)
+                        .takes_value(true),
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
                         .validator(|s| s.parse::<usize>())
-                        .default_value("50000000")
-                        .help("Maximum number of bytes to be stored in memory at once")
+                        .help("Number of threads to use for parallel recryption [default: number of cores]")
                         .required(false)
                         .takes_value(true),
                 ]),
         )
         .subcommand(
-            App::new("recrypt")
-                .about("Translates encryption key to a new pubkey")
+            App::new("collect")
+                .about("Adds files encrypted with the same owner keypair to a collection")
+                .args([
+                    Arg::new("owner_keypair")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the keypair the files were encrypted with")
+                        .required(true),
+                    Arg::new("collection")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the collection, created if it doesn't exist")
+                        .required(true),
+                    Arg::new("keys")
+                        .allow_invalid_utf8(true)
+                        .help("Paths of the recryption keys or capsule manifests of the files to add")
+                        .multiple_values(true)
+                        .required(true),
+                    Arg::new("passphrase")
+                        .short('p')
+                        .long("passphrase")
+                        .help("Seal the owner secret in the collection with a passphrase")
+                        .required(false),
+                ]),
+        )
+        .subcommand(
+            App::new("delegate")
+                .about("Splits the translation to a pubkey between several re-encryption proxies")
                 .args([
                     Arg::new("recryption_keys")
                         .allow_invalid_utf8(true)
-                        .help("Path of the recryption keys json file")
+                        .help("Path of the recryption keys json file, or of the capsule manifest with --owner_keypair")
                         .required(true),
                     Arg::new("receiver_pubkey")
                         .help("Public key byte array of the receiver of the file")
                         .required(true),
                     Arg::new("output")
+                        .help("Output path prefix for the proxy keys, one file per proxy")
+                        .required(true),
+                    Arg::new("threshold")
+                        .short('m')
+                        .long("threshold")
+                        .validator(|s| s.parse::<usize>())
+                        .default_value("1")
+                        .help("Number of proxies needed to decrypt the file")
+                        .required(false)
+                        .takes_value(true),
+                    Arg::new("shares")
+                        .short('n')
+                        .long("shares")
+                        .validator(|s| s.parse::<usize>())
+                        .default_value("1")
+                        .help("Number of proxies to split the translation between")
+                        .required(false)
+                        .takes_value(true),
+                    Arg::new("owner_keypair")
+                        .short('k')
+                        .long("owner_keypair")
                         .allow_invalid_utf8(true)
-                        .help("Output path for decryption keys")
+                        .help("Path of the owner's keypair, to grant access to a file deposited with a capsule manifest")
+                        .required(false)
+                        .takes_value(true),
+                ]),
+        )
+        .subcommand(
+            App::new("reencrypt")
+                .about("Translates the capsules with a single proxy's keys")
+                .args([
+                    Arg::new("proxy_keys")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the proxy keys json file")
+                        .required(true),
+                    Arg::new("output")
+                        .allow_invalid_utf8(true)
+                        .help("Output path for the decryption key share")
+                        .required(true),
+                    Arg::new("threads")
+                        .short('t')
+                        .long("threads")
+                        .validator(|s| s.parse::<usize>())
+                        .help("Number of threads to use for parallel recryption [default: number of cores]")
+                        .required(false)
+                        .takes_value(true),
+                ]),
+        )
+        .subcommand(
+            App::new("combine")
+                .about("Combines the decryption key shares of several proxies")
+                .args([
+                    Arg::new("output")
+                        .allow_invalid_utf8(true)
+                        .help("Output path for the combined decryption keys")
+                        .required(true),
+                    Arg::new("shares")
+                        .allow_invalid_utf8(true)
+                        .help("Paths of the decryption key shares")
+                        .multiple_occurrences(true)
                         .required(true),
                 ]),
         )
@@ -82,7 +497,7 @@ fn main() -> std::io::Result<()> {
                         .required(true),
                     Arg::new("decryption_keys")
                         .allow_invalid_utf8(true)
-                        .help("Path of the decryption keys json file")
+                        .help("Path of the decryption keys json file, or of the collection keys with --file_id")
                         .required(true),
                     Arg::new("receiver_keypair")
                         .allow_invalid_utf8(true)
@@ -92,14 +507,100 @@ fn main() -> std::io::Result<()> {
                         .allow_invalid_utf8(true)
                         .help("Output path for the decrypted file")
                         .required(true),
+                    Arg::new("file_id")
+                        .short('f')
+                        .long("file_id")
+                        .help("ID of the file to decrypt, for keys granted to a whole collection")
+                        .required(false)
+                        .takes_value(true),
                     Arg::new("threads")
                         .short('t')
                         .long("threads")
                         .validator(|s| s.parse::<usize>())
-                        .default_value("10")
-                        .help("Number of threads to use for parallel encryption")
+                        .help("Number of threads to use for parallel decryption [default: number of cores]")
                         .required(false)
-                        .takes_value(true)
+                        .takes_value(true),
+                    Arg::new("memory_size")
+                        .short('m')
+                        .long("memory_size")
+                        .validator(|s| s.parse::<usize>())
+                        .help("Maximum number of bytes to be stored in memory at once, fewer threads are used to stay below it")
+                        .required(false)
+                        .takes_value(true),
+                    Arg::new("no_overwrite")
+                        .long("no_overwrite")
+                        .help("Fail instead of replacing an existing output file")
+                        .required(false),
+                    Arg::new("resume")
+                        .short('r')
+                        .long("resume")
+                        .help("Continue an interrupted decryption from its last checkpoint")
+                        .required(false),
+                    Arg::new("verifying_key")
+                        .long("verifying_key")
+                        .help("Verifying key the owner published, keys signed with any other are refused")
+                        .required(false)
+                        .takes_value(true),
+                ]),
+        )
+        .subcommand(
+            App::new("extract")
+                .about("Extracts the files of an encrypted directory archive")
+                .args([
+                    Arg::new("input_file")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the encrypted archive")
+                        .required(true),
+                    Arg::new("decryption_keys")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the decryption keys json file")
+                        .required(true),
+                    Arg::new("receiver_keypair")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the keypair to decrypt the archive with")
+                        .required(true),
+                    Arg::new("output")
+                        .allow_invalid_utf8(true)
+                        .help("Output directory, or output file with --entry")
+                        .required_unless_present("list"),
+                    Arg::new("entry")
+                        .short('e')
+                        .long("entry")
+                        .help("Path of a single file in the archive to extract")
+                        .required(false)
+                        .takes_value(true),
+                    Arg::new("list")
+                        .short('l')
+                        .long("list")
+                        .help("List the entries of the archive instead of extracting them")
+                        .required(false),
+                    Arg::new("verifying_key")
+                        .long("verifying_key")
+                        .help("Verifying key the owner published, keys signed with any other are refused")
+                        .required(false)
+                        .takes_value(true),
+                    Arg::new("no_overwrite")
+                        .long("no_overwrite")
+                        .help("Fail instead of replacing existing output files")
+                        .required(false),
+                ]),
+        )
+        .subcommand(
+            App::new("convert")
+                .about("Adds a header to a file encrypted by an older version of precrypt")
+                .args([
+                    Arg::new("input_file")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the headerless encrypted file")
+                        .required(true),
+                    Arg::new("keys")
+                        .allow_invalid_utf8(true)
+                        .help("Path of the recryption or decryption keys json file for the file")
+                        .required(true),
+                    Arg::new("output_file")
+                        .allow_invalid_utf8(true)
+                        .help("Output path for the converted file")
+                        .required(true),
                 ]),
         )
         .subcommand(
@@ -116,50 +617,279 @@ fn main() -> std::io::Result<()> {
         Some(("encrypt", sub_matches)) => {
             // Read the keypair file
             let keypair_path = sub_matches.value_of_os("owner_keypair").unwrap();
-            let secret_file = File::open(&keypair_path).unwrap();
-            let secret_json: Keypair = serde_json::from_reader(BufReader::new(secret_file)).unwrap();
-            let wasm_secret = SecretKey::from_bytes(&secret_json.secret_key).unwrap();
+            let wasm_secret = read_secret_key(keypair_path)?;
 
             // Read the input file path
             let input_path = sub_matches.value_of_os("input_file").unwrap();
             let output_keys = sub_matches.value_of_os("output_keys").unwrap();
             let output_file = sub_matches.value_of_os("output_file").unwrap();
 
-            let threads: usize = sub_matches.value_of_t("threads").unwrap();
-            let memory_size: usize = sub_matches.value_of_t("memory_size").unwrap();
+            let mut encryptor = build_encryptor(sub_matches)?;
 
-            let recryption_keys = precrypt_file(
+            // A directory is encrypted with all its contents as a single archive
+            let archive = std::path::Path::new(input_path).is_dir();
+            match archive {
+                true => println!("Encrypting directory: {:?}", input_path),
+                false => println!("Encrypting file: {:?}", input_path),
+            }
+            let input_path = input_path.to_str().unwrap();
+            let output_file = output_file.to_str().unwrap();
+            // Ask before encrypting so a mistyped passphrase doesn't waste the run
+            let passphrase = match sub_matches.is_present("passphrase") {
+                true => match read_passphrase(sub_matches, true)? {
+                    passphrase if passphrase.is_empty() => return Err("passphrase must not be empty".into()),
+                    passphrase => Some(passphrase),
+                },
+                false => None,
+            };
+            let mut recryption_keys = match (archive, sub_matches.is_present("resume")) {
+                (true, true) => encryptor.resume_archive(input_path, wasm_secret, output_file)?,
+                (true, false) => encryptor.encrypt_archive(input_path, wasm_secret, output_file)?,
+                (false, true) => encryptor.resume_file(input_path, wasm_secret, output_file)?,
+                (false, false) => encryptor.encrypt_file(input_path, wasm_secret, output_file)?,
+            };
+            if let Some(passphrase) = passphrase {
+                recryption_keys.seal(passphrase.as_bytes())?;
+            }
+            std::fs::write(output_keys, encode_keys(&recryption_keys, key_encoding(sub_matches))?)?;
+            Ok(())
+        }
+        Some(("deposit", sub_matches)) => {
+            let owner_public = parse_pubkey(sub_matches.value_of("owner_pubkey").unwrap())?;
+            let input_path = sub_matches.value_of_os("input_file").unwrap();
+            let output_manifest = sub_matches.value_of_os("output_manifest").unwrap();
+            let output_file = sub_matches.value_of_os("output_file").unwrap();
+            let mut encryptor = build_encryptor(sub_matches)?;
+
+            println!("Encrypting file: {:?}", input_path);
+            let input_path = input_path.to_str().unwrap();
+            let output_file = output_file.to_str().unwrap();
+            let manifest = match sub_matches.is_present("resume") {
+                true => encryptor.resume_file_for(input_path, owner_public, output_file)?,
+                false => encryptor.encrypt_file_for(input_path, owner_public, output_file)?,
+            };
+            std::fs::write(output_manifest, encode_keys(&manifest, key_encoding(sub_matches))?)?;
+            Ok(())
+        }
+        Some(("append", sub_matches)) => {
+            let input_path = sub_matches.value_of_os("input_file").unwrap();
+            let keys_path = sub_matches.value_of_os("recryption_keys").unwrap();
+            let encrypted_file = sub_matches.value_of_os("encrypted_file").unwrap();
+            let keys_bytes = Zeroizing::new(std::fs::read(keys_path)?);
+            // Written back as they were found, whatever `--encoding` says
+            let encoding = KeyEncoding::detect(&keys_bytes);
+            let recryption_keys: RecryptionKeys = decode_keys(&keys_bytes)?;
+            // Sealed keys are opened for the append and sealed again with the same passphrase
+            let passphrase = match recryption_keys.is_sealed() {
+                true => Some(read_passphrase(sub_matches, false)?),
+                false => None,
+            };
+            let mut recryption_keys = match &passphrase {
+                Some(passphrase) => recryption_keys.unlock(passphrase.as_bytes())?,
+                None => recryption_keys,
+            };
+
+            let mut builder = Encryptor::builder().progress(BarProgress::new());
+            if sub_matches.is_present("threads") {
+                builder = builder.workers(sub_matches.value_of_t("threads").unwrap());
+            }
+            println!("Appending file: {:?}", input_path);
+            let chunks = builder.build()?.append_file(
                 input_path.to_str().unwrap(),
-                wasm_secret,
-                &output_file.to_str().unwrap(),
-                threads,
-                memory_size,
+                encrypted_file.to_str().unwrap(),
+                &mut recryption_keys,
+            )?;
+            if let Some(passphrase) = passphrase {
+                recryption_keys.seal(passphrase.as_bytes())?;
+            }
+            replace_file(keys_path, &encode_keys(&recryption_keys, encoding)?)?;
+            // Receivers holding keys of the rest only need these chunks granted
+            println!(
+                "Wrote chunks {} to {}, grant them with: recrypt --chunks {}-{}",
+                chunks.start, chunks.end, chunks.start, chunks.end
             );
-            std::fs::write(
-                output_keys,
-                serde_json::to_string(&recryption_keys).unwrap(),
-             )?;
             Ok(())
         }
-        Some(("recrypt", sub_matches)) => {
-            // Read recryption keys from file
-            let recryption_keys_path = sub_matches.value_of_os("recryption_keys").unwrap();
-            let recryption_keys_array = std::fs::read(recryption_keys_path).unwrap();
-            let recryption_keys: RecryptionKeys = serde_json::from_slice(&recryption_keys_array)?;
+        Some(("recrypt", sub_matches)) if sub_matches.is_present("batch") => {
+            // One receiver pubkey per line, blank lines are skipped
+            let receivers_path = sub_matches.value_of("receiver_pubkey").unwrap();
+            let mut receivers: Vec<PublicKey> = Vec::new();
+            for line in BufReader::new(File::open(receivers_path)?).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    receivers.push(parse_pubkey(&line)?);
+                }
+            }
+            let recryption_keys = read_recryption_keys(sub_matches)?;
+
+            // Receivers get their own file: <output>/1.json, <output>/2.json, ... in list order
+            let output_dir = std::path::Path::new(sub_matches.value_of_os("output").unwrap());
+            std::fs::create_dir_all(output_dir)?;
+            let encoding = key_encoding(sub_matches);
+            let extension = sub_matches.value_of("encoding").unwrap();
+            build_recryptor(sub_matches)?.recrypt_batch(
+                &recryption_keys,
+                &receivers,
+                |index, decryption_keys| {
+                    let output_path = output_dir.join(format!("{}.{}", index + 1, extension));
+                    std::fs::write(output_path, encode_keys(&decryption_keys, encoding)?)?;
+                    Ok(())
+                },
+            )?;
+            Ok(())
+        }
+        Some(("recrypt", sub_matches)) if sub_matches.is_present("collection") => {
+            let receiver_public = parse_pubkey(sub_matches.value_of("receiver_pubkey").unwrap())?;
+            let collection_path = sub_matches.value_of_os("recryption_keys").unwrap();
+            let collection: Collection =
+                decode_keys(&Zeroizing::new(std::fs::read(collection_path)?))?;
+            let collection = match collection.is_sealed() {
+                true => collection.unlock(read_passphrase(sub_matches, false)?.as_bytes())?,
+                false => collection,
+            };
+
+            let collection_keys =
+                build_recryptor(sub_matches)?.recrypt_collection(&collection, receiver_public)?;
+            let output_path = sub_matches.value_of_os("output").unwrap();
+            std::fs::write(output_path, encode_keys(&collection_keys, key_encoding(sub_matches))?)?;
+            Ok(())
+        }
+        Some(("recrypt", sub_matches))
+            if sub_matches.is_present("chunks") || sub_matches.is_present("bytes") =>
+        {
+            let receiver_public = parse_pubkey(sub_matches.value_of("receiver_pubkey").unwrap())?;
+            let recryption_keys = read_recryption_keys(sub_matches)?;
+            let chunks = match sub_matches.value_of("chunks") {
+                Some(chunks) => {
+                    let chunks = parse_range(chunks)?;
+                    chunks.start as usize..chunks.end as usize
+                }
+                None => {
+                    let bytes = parse_range(sub_matches.value_of("bytes").unwrap())?;
+                    recryption_keys.chunks_covering(bytes)?
+                }
+            };
 
+            let decryption_keys = build_recryptor(sub_matches)?.recrypt_range(
+                &recryption_keys,
+                receiver_public,
+                chunks.clone(),
+            )?;
+            let output_path = sub_matches.value_of_os("output").unwrap();
+            std::fs::write(output_path, encode_keys(&decryption_keys, key_encoding(sub_matches))?)?;
+            // Tells the receiver what to download instead of the whole file
+            if let Some(range) = decryption_keys.ciphertext_range() {
+                println!(
+                    "Granted chunks {} to {}, stored at bytes {} to {} of the encrypted file",
+                    chunks.start, chunks.end, range.start, range.end
+                );
+            }
+            Ok(())
+        }
+        Some(("recrypt", sub_matches)) => {
             // Read receiver pubkey from argument
-            let receiver_public_str = sub_matches.value_of("receiver_pubkey").unwrap();
-            let public_vec: Vec<u8> = serde_json::from_str(receiver_public_str)?;
-            let receiver_public = PublicKey::from_bytes(&public_vec).unwrap();
+            let receiver_public = parse_pubkey(sub_matches.value_of("receiver_pubkey").unwrap())?;
+
+            let recryption_keys = read_recryption_keys(sub_matches)?;
+
+            // The keys are written as the capsules are translated
+            let output = File::create(sub_matches.value_of_os("output").unwrap())?;
+            build_recryptor(sub_matches)?.recrypt_to(
+                &recryption_keys,
+                receiver_public,
+                output,
+                key_encoding(sub_matches),
+            )?;
+            Ok(())
+        }
+        Some(("collect", sub_matches)) => {
+            let owner_secret = read_secret_key(sub_matches.value_of_os("owner_keypair").unwrap())?;
+            let collection_path = std::path::Path::new(sub_matches.value_of_os("collection").unwrap());
+            let collection = match collection_path.exists() {
+                true => decode_keys(&Zeroizing::new(std::fs::read(collection_path)?))?,
+                false => Collection::new(&owner_secret),
+            };
+            // A sealed collection is opened to add the files and sealed again with the same
+            // passphrase, others are sealed if asked to
+            let passphrase = match (collection.is_sealed(), sub_matches.is_present("passphrase")) {
+                (true, _) => Some(read_passphrase(sub_matches, false)?),
+                (false, true) => match read_passphrase(sub_matches, true)? {
+                    passphrase if passphrase.is_empty() => return Err("passphrase must not be empty".into()),
+                    passphrase => Some(passphrase),
+                },
+                (false, false) => None,
+            };
+            let mut collection = match (&passphrase, collection.is_sealed()) {
+                (Some(passphrase), true) => collection.unlock(passphrase.as_bytes())?,
+                _ => collection,
+            };
+            if collection.owner_pubkey()? != owner_secret.public_key() {
+                return Err(PrecryptError::InvalidConfig(
+                    "the collection belongs to another owner keypair".to_string(),
+                )
+                .into());
+            }
+
+            for keys_path in sub_matches.values_of_os("keys").unwrap() {
+                let keys_array = Zeroizing::new(std::fs::read(keys_path)?);
+                // Capsule manifests are signed with the owner's keypair as they're added
+                let file_id = match decode_keys::<CapsuleManifest>(&keys_array) {
+                    Ok(manifest) => collection.add_manifest(&manifest)?,
+                    Err(_) => {
+                        let recryption_keys = unlock_keys(decode_keys(&keys_array)?, sub_matches)?;
+                        collection.add(&recryption_keys)?
+                    }
+                };
+                println!("Added {:?} as file {}", keys_path, file_id);
+            }
+            if let Some(passphrase) = passphrase {
+                collection.seal(passphrase.as_bytes())?;
+            }
+            std::fs::write(collection_path, encode_keys(&collection, key_encoding(sub_matches))?)?;
+            Ok(())
+        }
+        Some(("delegate", sub_matches)) => {
+            let receiver_public = parse_pubkey(sub_matches.value_of("receiver_pubkey").unwrap())?;
+            let recryption_keys = read_recryption_keys(sub_matches)?;
+            let threshold: usize = sub_matches.value_of_t("threshold").unwrap();
+            let shares: usize = sub_matches.value_of_t("shares").unwrap();
+            let proxy_keys =
+                split_recryption_keys(&recryption_keys, receiver_public, threshold, shares)?;
+
+            // Each proxy gets its own file: <output>.1.json, <output>.2.json, ...
+            let output_prefix = sub_matches.value_of("output").unwrap();
+            let encoding = key_encoding(sub_matches);
+            let extension = sub_matches.value_of("encoding").unwrap();
+            for (index, proxy_keys) in proxy_keys.iter().enumerate() {
+                std::fs::write(
+                    format!("{}.{}.{}", output_prefix, index + 1, extension),
+                    encode_keys(proxy_keys, encoding)?,
+                )?;
+            }
+            Ok(())
+        }
+        Some(("reencrypt", sub_matches)) => {
+            let proxy_keys_path = sub_matches.value_of_os("proxy_keys").unwrap();
+            let proxy_keys: ProxyKeys = decode_keys(&std::fs::read(proxy_keys_path)?)?;
+
+            let output = File::create(sub_matches.value_of_os("output").unwrap())?;
+            build_recryptor(sub_matches)?.proxy_recrypt_to(
+                &proxy_keys,
+                output,
+                key_encoding(sub_matches),
+            )?;
+            Ok(())
+        }
+        Some(("combine", sub_matches)) => {
+            let mut shares: Vec<DecryptionKeys> = Vec::new();
+            for share_path in sub_matches.values_of_os("shares").unwrap() {
+                shares.push(decode_keys(&std::fs::read(share_path)?)?);
+            }
+
+            let decryption_keys = combine_decryption_keys(&shares)?;
 
-            let decryption_keys = recrypt_keys(recryption_keys, receiver_public);
-            
             let output_path = sub_matches.value_of_os("output").unwrap();
-            std::fs::write(
-                output_path,
-                serde_json::to_string(&decryption_keys).unwrap(),
-             )
-            .unwrap();
+            std::fs::write(output_path, encode_keys(&decryption_keys, key_encoding(sub_matches))?)?;
             Ok(())
         }
         Some(("decrypt", sub_matches)) => {
@@ -167,27 +897,106 @@ fn main() -> std::io::Result<()> {
             let input_path = sub_matches.value_of_os("input_file").unwrap();
             // Read decryption keys file
             let decryption_keys_path = sub_matches.value_of_os("decryption_keys").unwrap();
-            let decryption_keys_array = std::fs::read(decryption_keys_path).unwrap();
-            let mut decryption_keys: DecryptionKeys =
-                serde_json::from_slice(&decryption_keys_array)?;
+            let keys_array = std::fs::read(decryption_keys_path)?;
+            let decryption_keys: DecryptionKeys = match sub_matches.value_of("file_id") {
+                Some(file_id) => {
+                    let collection_keys: CollectionKeys = decode_keys(&keys_array)?;
+                    collection_keys.file(file_id).ok_or_else(|| {
+                        PrecryptError::InvalidConfig(format!("no file {} in the collection", file_id))
+                    })?
+                }
+                None => decode_keys(&keys_array)?,
+            };
 
             // Read receiver secret
             let keypair_path = sub_matches.value_of_os("receiver_keypair").unwrap();
-            let secret_file = File::open(&keypair_path).unwrap();
-            let secret_json: Keypair = serde_json::from_reader(BufReader::new(secret_file)).unwrap();
-            let wasm_secret = SecretKey::from_bytes(&secret_json.secret_key).unwrap();
+            let wasm_secret = read_secret_key(keypair_path)?;
             // Decrypt the cipher
             let output_path = sub_matches.value_of_os("output").unwrap();
 
-            let threads: usize = sub_matches.value_of_t("threads").unwrap();
+            let mut builder = Decryptor::builder()
+                .overwrite(overwrite_policy(sub_matches))
+                .progress(BarProgress::new());
+            if sub_matches.is_present("threads") {
+                builder = builder.workers(sub_matches.value_of_t("threads").unwrap());
+            }
+            if sub_matches.is_present("memory_size") {
+                builder = builder.memory_budget(sub_matches.value_of_t("memory_size").unwrap());
+            }
+            if let Some(verifying_key) = sub_matches.value_of("verifying_key") {
+                builder = builder.verifying_key(parse_pubkey(verifying_key)?);
+            }
+            let mut decryptor = builder.build()?;
+
+            println!("Decrypting file: {:?}", input_path);
+            if let Some(chunks) = decryption_keys.granted_chunks() {
+                println!(
+                    "The keys only grant chunks {} to {} of the file",
+                    chunks.start, chunks.end
+                );
+            }
+            let input_path = input_path.to_str().unwrap();
+            let output_path = output_path.to_str().unwrap();
+            let keys = &decryption_keys;
+            match sub_matches.is_present("resume") {
+                true => decryptor.resume_file(input_path, output_path, wasm_secret, keys)?,
+                false => decryptor.decrypt_file(input_path, output_path, wasm_secret, keys)?,
+            };
+            Ok(())
+        }
+        Some(("extract", sub_matches)) => {
+            let input_path = sub_matches.value_of_os("input_file").unwrap();
+            let decryption_keys_path = sub_matches.value_of_os("decryption_keys").unwrap();
+            let decryption_keys: DecryptionKeys =
+                decode_keys(&std::fs::read(decryption_keys_path)?)?;
+            if let Some(verifying_key) = sub_matches.value_of("verifying_key") {
+                decryption_keys.check_verifying_key(&parse_pubkey(verifying_key)?)?;
+            }
+            let keypair_path = sub_matches.value_of_os("receiver_keypair").unwrap();
+            let wasm_secret = read_secret_key(keypair_path)?;
+
+            let mut archive =
+                Archive::open(input_path.to_str().unwrap(), wasm_secret, decryption_keys)?;
+            if sub_matches.is_present("list") {
+                for entry in archive.entries() {
+                    match entry.directory {
+                        true => println!("{:>12} {}/", "", entry.path),
+                        false => println!("{:>12} {}", entry.size, entry.path),
+                    }
+                }
+                return Ok(());
+            }
+
+            let output_path = sub_matches.value_of_os("output").unwrap().to_str().unwrap();
+            let overwrite = overwrite_policy(sub_matches);
+            match sub_matches.value_of("entry") {
+                Some(entry) => {
+                    if overwrite == Overwrite::Refuse && std::path::Path::new(output_path).exists() {
+                        return Err(PrecryptError::OutputExists(output_path.to_string()).into());
+                    }
+                    archive.extract_entry(entry, File::create(output_path)?)?
+                }
+                None => archive.extract_all(output_path, overwrite)?,
+            }
+            Ok(())
+        }
+        Some(("convert", sub_matches)) => {
+            let input_path = sub_matches.value_of_os("input_file").unwrap();
+            let output_path = sub_matches.value_of_os("output_file").unwrap();
+
+            // Either kind of keys holds the chunk size and capsules
+            let keys_path = sub_matches.value_of_os("keys").unwrap();
+            let keys_array = Zeroizing::new(std::fs::read(keys_path)?);
+            let header = match decode_keys::<RecryptionKeys>(&keys_array) {
+                Ok(recryption_keys) => Header::from(&recryption_keys),
+                Err(_) => Header::from(&decode_keys::<DecryptionKeys>(&keys_array)?),
+            };
 
-            decrypt_file(
+            convert_legacy_file(
                 input_path.to_str().unwrap(),
                 output_path.to_str().unwrap(),
-                wasm_secret,
-                &mut decryption_keys,
-                threads
-            );
+                &header,
+            )?;
             Ok(())
         }
         Some(("keygen", sub_matches)) => {
@@ -196,13 +1005,14 @@ fn main() -> std::io::Result<()> {
 
             // Parse secret as array
             let secret_box = keypair.to_secret_array();
-            let secret_array = secret_box.as_secret().to_vec();
 
             let keypair = Keypair {
                 public_key: keypair.public_key().to_array().to_vec(),
-                secret_key: secret_array.to_owned(),
+                secret_key: secret_box.as_secret().to_vec(),
+                verifying_key: owner_signer(&keypair).verifying_key().to_array().to_vec(),
             };
-            std::fs::write(output_path, serde_json::to_string(&keypair).unwrap()).unwrap();
+            let json = Zeroizing::new(serde_json::to_string(&keypair).unwrap());
+            std::fs::write(output_path, json.as_bytes()).unwrap();
 
             // serde_json::from_str(s: &'a str)
             Ok(())
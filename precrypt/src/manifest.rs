@@ -0,0 +1,358 @@
+use crate::{PrecryptError, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::ops::Range;
+use umbral_pre::*;
+
+const FILE_ID_LEN: usize = 16;
+// Zero bytes a chunk itself may end with when looking for the padding after it, more are as
+// unlikely as a forged tag
+const MAX_TRAILING_ZEROS: usize = 16;
+
+/// Signed description of an encrypted file, produced by the owner when encrypting, or when
+/// granting access to a file encrypted to their public key.
+///
+/// Binds every ciphertext chunk to its position and to the file, so dropping, reordering
+/// or splicing chunks (together with their capsules or not) fails to decrypt. It travels
+/// with the keys rather than with the ciphertext, so storage access alone can't replace it.
+///
+/// Keys granting part of the file carry a manifest the owner signed again with the chunks
+/// granted, so capsules can't be dropped from keys to pass them off as a partial grant.
+///
+/// Data appended to the file later is described by a segment of its own, with the digest
+/// of its plaintext, so the chunks and digest signed before stay valid.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Manifest {
+   #[serde(with = "crate::encoding::bytes")]
+   file_id: Vec<u8>,
+   chunk_size: usize,
+   chunk_count: usize,
+   // SHA-256 of every ciphertext chunk in chunk order
+   #[serde(with = "crate::encoding::byte_list")]
+   chunk_hashes: Vec<Vec<u8>>,
+   // SHA-256 of every capsule in the keys
+   #[serde(with = "crate::encoding::byte_list")]
+   capsule_hashes: Vec<Vec<u8>>,
+   // SHA-256 of the plaintext, up to the first appended segment if there is one
+   #[serde(with = "crate::encoding::bytes")]
+   plaintext_hash: Vec<u8>,
+   #[serde(with = "crate::encoding::bytes")]
+   signature: Vec<u8>,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   granted_chunks: Option<Range<usize>>,
+   #[serde(default, skip_serializing_if = "Vec::is_empty")]
+   appended: Vec<Segment>,
+}
+
+// Data appended after the file was encrypted, starting at a chunk of its own
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Segment {
+   first_chunk: usize,
+   // Zeros filling the slot of the chunk before, which was the last one and not full
+   padding: usize,
+   // SHA-256 of the plaintext appended
+   #[serde(with = "crate::encoding::bytes")]
+   plaintext_hash: Vec<u8>,
+}
+
+impl Manifest {
+   pub(crate) fn sign(
+      signer: &Signer,
+      file_id: Vec<u8>,
+      chunk_size: usize,
+      chunk_hashes: Vec<Vec<u8>>,
+      capsules: &[Vec<u8>],
+      plaintext_hash: Vec<u8>,
+   ) -> Manifest {
+      let mut manifest = Manifest {
+         file_id,
+         chunk_size,
+         chunk_count: chunk_hashes.len(),
+         chunk_hashes,
+         capsule_hashes: capsules.iter().map(|capsule| hash(capsule)).collect(),
+         plaintext_hash,
+         signature: Vec::new(),
+         granted_chunks: None,
+         appended: Vec::new(),
+      };
+      manifest.signature = signer.sign(&manifest.message()).to_array().to_vec();
+      manifest
+   }
+
+   /// The same manifest, signed again to only grant the chunks in `chunks`.
+   pub(crate) fn grant(&self, signer: &Signer, chunks: Range<usize>) -> Manifest {
+      let mut manifest = Manifest {
+         granted_chunks: Some(chunks),
+         ..self.clone()
+      };
+      manifest.signature = signer.sign(&manifest.message()).to_array().to_vec();
+      manifest
+   }
+
+   /// The same manifest with the chunks and capsules of appended data, signed again.
+   ///
+   /// `padding` zeros follow the last chunk written so far to fill its slot, so the appended
+   /// chunks start on a slot boundary and the chunks already there stay as they are.
+   pub(crate) fn append(
+      &self,
+      signer: &Signer,
+      chunk_hashes: &[Vec<u8>],
+      capsules: &[Vec<u8>],
+      padding: usize,
+      plaintext_hash: Vec<u8>,
+   ) -> Manifest {
+      let mut manifest = self.clone();
+      manifest.appended.push(Segment {
+         first_chunk: self.chunk_count,
+         padding,
+         plaintext_hash,
+      });
+      manifest.chunk_hashes.extend_from_slice(chunk_hashes);
+      manifest.chunk_count = manifest.chunk_hashes.len();
+      manifest.capsule_hashes.extend(capsules.iter().map(|capsule| hash(capsule)));
+      manifest.signature = signer.sign(&manifest.message()).to_array().to_vec();
+      manifest
+   }
+
+   /// Random identifier of the file, different for every encryption.
+   pub fn file_id(&self) -> &[u8] {
+      &self.file_id
+   }
+
+   pub fn chunk_count(&self) -> usize {
+      self.chunk_count
+   }
+
+   /// Chunks the owner granted access to, if not the whole file.
+   pub fn granted_chunks(&self) -> Option<Range<usize>> {
+      self.granted_chunks.clone()
+   }
+
+   /// Checks the owner's signature and that the manifest describes these capsules, the
+   /// capsules of the granted chunks if only some are.
+   pub(crate) fn verify(
+      &self,
+      verifying_key: &PublicKey,
+      chunk_size: usize,
+      capsules: &[Vec<u8>],
+   ) -> Result<()> {
+      let signature = Signature::from_bytes(&self.signature)
+         .map_err(|_| invalid("signature is malformed"))?;
+      if !signature.verify(verifying_key, &self.message()) {
+         return Err(invalid("signature does not match the owner's verifying key"));
+      }
+      if self.chunk_hashes.len() != self.chunk_count {
+         return Err(invalid("chunk hashes don't match the chunk count"));
+      }
+      if self.chunk_size != chunk_size {
+         return Err(invalid("chunk size does not match the keys"));
+      }
+      // Segments start in order, and only a chunk before them can be padded
+      let mut next = 0;
+      for segment in &self.appended {
+         let padding_max = match segment.first_chunk {
+            0 => 0,
+            _ => chunk_size.saturating_sub(1),
+         };
+         if segment.first_chunk < next
+            || segment.first_chunk >= self.chunk_count
+            || segment.padding > padding_max
+         {
+            return Err(invalid("appended segments don't match the chunks"));
+         }
+         next = segment.first_chunk + 1;
+      }
+      let granted = self.granted_chunks.clone().unwrap_or(0..self.capsule_hashes.len());
+      let capsules_match = match self.capsule_hashes.get(granted) {
+         Some(capsule_hashes) => {
+            capsule_hashes.len() == capsules.len()
+               && capsule_hashes
+                  .iter()
+                  .zip(capsules)
+                  .all(|(capsule_hash, capsule)| *capsule_hash == hash(capsule))
+         }
+         None => false,
+      };
+      if !capsules_match {
+         return Err(invalid("capsules do not match the keys"));
+      }
+      Ok(())
+   }
+
+   /// Checks that `chunk` is the ciphertext chunk the owner wrote at `index`, returning it
+   /// without the padding that follows it when data was appended after it.
+   ///
+   /// Data appended after this manifest was signed pads its last chunk too, with zeros it
+   /// doesn't know the length of, so they're found by trying the lengths they allow.
+   pub(crate) fn check_chunk<'a>(&self, index: usize, chunk: &'a [u8]) -> Result<&'a [u8]> {
+      let padding = self.appended.iter().find(|segment| segment.first_chunk == index + 1);
+      let len = self.chunk_size.saturating_sub(padding.map_or(0, |segment| segment.padding));
+      let chunk = &chunk[..chunk.len().min(len)];
+      let chunk_hash = match self.chunk_hashes.get(index) {
+         Some(chunk_hash) => chunk_hash,
+         None => return Err(PrecryptError::ChunkMismatch { index }),
+      };
+      if *chunk_hash == hash(chunk) {
+         return Ok(chunk);
+      }
+      if index + 1 == self.chunk_count && chunk.len() == self.chunk_size {
+         let data_end = chunk.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+         let unpadded = (data_end..chunk.len())
+            .take(MAX_TRAILING_ZEROS + 1)
+            .find(|len| *chunk_hash == hash(&chunk[..*len]));
+         if let Some(len) = unpadded {
+            return Ok(&chunk[..len]);
+         }
+      }
+      Err(PrecryptError::ChunkMismatch { index })
+   }
+
+   /// Whether zeros may fill the slot of the chunk at `index` after it, because data was
+   /// appended after it.
+   pub(crate) fn may_be_padded(&self, index: usize) -> bool {
+      index + 1 == self.chunk_count
+         || self.appended.iter().any(|segment| segment.first_chunk == index + 1)
+   }
+
+   /// Plaintext bytes before the chunk at `index`, for chunks of `overhead` bytes more.
+   pub(crate) fn plaintext_offset(&self, index: usize, overhead: usize) -> u64 {
+      let padding: usize = self
+         .appended
+         .iter()
+         .filter(|segment| segment.first_chunk <= index)
+         .map(|segment| segment.padding)
+         .sum();
+      let plaintext_chunk_size = self.chunk_size.saturating_sub(overhead) as u64;
+      (index as u64 * plaintext_chunk_size).saturating_sub(padding as u64)
+   }
+
+   /// Chunk holding the plaintext byte at `position`, for chunks of `overhead` bytes more.
+   pub(crate) fn chunk_at(&self, position: u64, overhead: usize) -> usize {
+      let padding: usize = self
+         .appended
+         .iter()
+         .filter(|segment| self.plaintext_offset(segment.first_chunk, overhead) <= position)
+         .map(|segment| segment.padding)
+         .sum();
+      let plaintext_chunk_size = self.chunk_size.saturating_sub(overhead).max(1) as u64;
+      ((position + padding as u64) / plaintext_chunk_size) as usize
+   }
+
+   /// Plaintext offsets where the appended segments start, and a new digest with them.
+   pub(crate) fn segment_starts(&self, overhead: usize) -> Vec<u64> {
+      self.appended
+         .iter()
+         .map(|segment| self.plaintext_offset(segment.first_chunk, overhead))
+         .collect()
+   }
+
+   /// Checks the number of chunks decrypted and the digests of the plaintext, one for what
+   /// was first encrypted and one for every appended segment.
+   ///
+   /// Only the number of chunks is checked when part of the file is granted.
+   pub(crate) fn check_plaintext(&self, chunks: usize, digests: &[Vec<u8>]) -> Result<()> {
+      let expected = self.granted_chunks.as_ref().map_or(self.chunk_count, |c| c.len());
+      if chunks != expected {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks,
+            capsules: expected,
+         });
+      }
+      let expected = std::iter::once(&self.plaintext_hash)
+         .chain(self.appended.iter().map(|segment| &segment.plaintext_hash));
+      if self.granted_chunks.is_none() && !expected.eq(digests) {
+         return Err(PrecryptError::DigestMismatch);
+      }
+      Ok(())
+   }
+
+   // Every field but the signature, with lengths so the encoding is unambiguous
+   fn message(&self) -> Vec<u8> {
+      let mut message = b"precrypt manifest".to_vec();
+      let mut push = |bytes: &[u8]| {
+         message.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+         message.extend_from_slice(bytes);
+      };
+      push(&self.file_id);
+      push(&(self.chunk_size as u64).to_le_bytes());
+      push(&(self.chunk_count as u64).to_le_bytes());
+      for chunk_hash in &self.chunk_hashes {
+         push(chunk_hash);
+      }
+      for capsule_hash in &self.capsule_hashes {
+         push(capsule_hash);
+      }
+      push(&self.plaintext_hash);
+      // Left out of manifests of files never appended to so their signatures stay the same
+      if !self.appended.is_empty() {
+         push(b"appended");
+         for segment in &self.appended {
+            push(&(segment.first_chunk as u64).to_le_bytes());
+            push(&(segment.padding as u64).to_le_bytes());
+            push(&segment.plaintext_hash);
+         }
+      }
+      // Left out of manifests of the whole file so their signatures stay the same
+      if let Some(granted) = &self.granted_chunks {
+         push(&(granted.start as u64).to_le_bytes());
+         push(&(granted.end as u64).to_le_bytes());
+      }
+      message
+   }
+}
+
+/// Random identifier for a new encryption.
+pub(crate) fn new_file_id() -> Result<Vec<u8>> {
+   let mut file_id = vec![0u8; FILE_ID_LEN];
+   getrandom::getrandom(&mut file_id).map_err(|err| PrecryptError::Io(err.into()))?;
+   Ok(file_id)
+}
+
+/// Digests of the plaintext as it's written, a new one starting at every appended segment.
+#[derive(Clone, Default)]
+pub(crate) struct PlaintextHash {
+   hasher: Sha256,
+   position: u64,
+   // Plaintext offsets of the appended segments, from `Manifest::segment_starts`
+   starts: Vec<u64>,
+   digests: Vec<Vec<u8>>,
+}
+
+impl PlaintextHash {
+   pub fn new(starts: Vec<u64>) -> Self {
+      PlaintextHash {
+         starts,
+         ..PlaintextHash::default()
+      }
+   }
+
+   pub fn update(&mut self, mut bytes: &[u8]) {
+      while let Some(&start) = self.starts.get(self.digests.len()) {
+         let until = start.saturating_sub(self.position);
+         if until > bytes.len() as u64 {
+            break;
+         }
+         let (segment, rest) = bytes.split_at(until as usize);
+         self.hasher.update(segment);
+         self.position += until;
+         self.digests.push(self.hasher.finalize_reset().to_vec());
+         bytes = rest;
+      }
+      self.hasher.update(bytes);
+      self.position += bytes.len() as u64;
+   }
+
+   /// Digest of every segment of the plaintext, a single one if nothing was appended.
+   pub fn finalize(mut self) -> Vec<Vec<u8>> {
+      self.digests.push(self.hasher.finalize().to_vec());
+      self.digests
+   }
+}
+
+pub(crate) fn hash(bytes: &[u8]) -> Vec<u8> {
+   Sha256::digest(bytes).to_vec()
+}
+
+fn invalid(msg: &str) -> PrecryptError {
+   PrecryptError::InvalidManifest(msg.to_string())
+}
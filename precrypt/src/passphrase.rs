@@ -0,0 +1,82 @@
+use crate::{PrecryptError, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+// Binds the ciphertext to its purpose so it can't be passed off as another sealed value
+const ASSOCIATED_DATA: &[u8] = b"precrypt owner secret";
+
+/// A secret encrypted under a key derived from a passphrase with Argon2id.
+///
+/// The Argon2 costs are stored with it, so they can be raised for new secrets without
+/// breaking the ones already sealed.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct SealedSecret {
+   #[serde(with = "crate::encoding::bytes")]
+   salt: Vec<u8>,
+   #[serde(with = "crate::encoding::bytes")]
+   nonce: Vec<u8>,
+   #[serde(with = "crate::encoding::bytes")]
+   ciphertext: Vec<u8>,
+   // Memory in KiB, passes and lanes
+   memory_cost: u32,
+   time_cost: u32,
+   parallelism: u32,
+}
+
+impl SealedSecret {
+   pub fn seal(secret: &[u8], passphrase: &[u8]) -> Result<SealedSecret> {
+      let mut salt = vec![0u8; SALT_LEN];
+      let mut nonce = vec![0u8; NONCE_LEN];
+      getrandom::getrandom(&mut salt).map_err(|err| PrecryptError::Io(err.into()))?;
+      getrandom::getrandom(&mut nonce).map_err(|err| PrecryptError::Io(err.into()))?;
+      let mut sealed = SealedSecret {
+         salt,
+         nonce,
+         ciphertext: Vec::new(),
+         memory_cost: Params::DEFAULT_M_COST,
+         time_cost: Params::DEFAULT_T_COST,
+         parallelism: Params::DEFAULT_P_COST,
+      };
+      let payload = Payload {
+         msg: secret,
+         aad: ASSOCIATED_DATA,
+      };
+      sealed.ciphertext = sealed
+         .cipher(passphrase)?
+         .encrypt(Nonce::from_slice(&sealed.nonce), payload)
+         .map_err(|_| PrecryptError::InvalidConfig("secret can't be sealed".to_string()))?;
+      Ok(sealed)
+   }
+
+   /// Decrypts the secret, failing with `WrongPassphrase` for any other passphrase.
+   pub fn open(&self, passphrase: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+      if self.nonce.len() != NONCE_LEN {
+         return Err(PrecryptError::WrongPassphrase);
+      }
+      let payload = Payload {
+         msg: &self.ciphertext,
+         aad: ASSOCIATED_DATA,
+      };
+      self
+         .cipher(passphrase)?
+         .decrypt(Nonce::from_slice(&self.nonce), payload)
+         .map(Zeroizing::new)
+         .map_err(|_| PrecryptError::WrongPassphrase)
+   }
+
+   fn cipher(&self, passphrase: &[u8]) -> Result<ChaCha20Poly1305> {
+      let params = Params::new(self.memory_cost, self.time_cost, self.parallelism, Some(KEY_LEN))
+         .map_err(|err| PrecryptError::InvalidConfig(format!("invalid Argon2 costs: {}", err)))?;
+      let mut key = Zeroizing::new([0u8; KEY_LEN]);
+      Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+         .hash_password_into(passphrase, &self.salt, key.as_mut())
+         .map_err(|err| PrecryptError::InvalidConfig(format!("invalid passphrase or salt: {}", err)))?;
+      Ok(ChaCha20Poly1305::new(Key::from_slice(key.as_ref())))
+   }
+}
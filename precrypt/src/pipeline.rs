@@ -0,0 +1,99 @@
+use crate::Result;
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+
+/// Runs chunk jobs on a fixed pool of `threads` workers.
+///
+/// `next_job` is called on the current thread to read chunk after chunk until it returns
+/// `None`, `work` runs on the pool, and `finish` is called on the current thread with the
+/// results in chunk order. At most `threads` chunks are read but not yet finished at any
+/// time, so reading the next chunks, processing the current ones and writing out the
+/// finished ones overlap without holding more than `threads` chunks in memory.
+///
+/// Returns the number of chunks processed. Stops at the first error, and a panic in
+/// `work` is raised again on the current thread.
+///
+/// A single worker runs the jobs on the current thread, so it also works where threads
+/// can't be spawned, like WebAssembly.
+pub(crate) fn run<J, T>(
+   threads: usize,
+   mut next_job: impl FnMut(usize) -> Result<Option<J>>,
+   work: impl Fn(usize, J) -> Result<T> + Sync,
+   mut finish: impl FnMut(usize, T) -> Result<()>,
+) -> Result<usize>
+where
+   J: Send,
+   T: Send,
+{
+   // One worker never overlaps reading with processing anyway
+   if threads == 1 {
+      let mut index = 0;
+      while let Some(job) = next_job(index)? {
+         finish(index, work(index, job)?)?;
+         index += 1;
+      }
+      return Ok(index);
+   }
+
+   let (job_tx, job_rx) = mpsc::channel::<(usize, J)>();
+   let (result_tx, result_rx) = mpsc::channel::<(usize, thread::Result<Result<T>>)>();
+   let job_rx = Mutex::new(job_rx);
+
+   thread::scope(|scope| {
+      for _ in 0..threads {
+         let result_tx = result_tx.clone();
+         let job_rx = &job_rx;
+         let work = &work;
+         scope.spawn(move || loop {
+            // The lock is only held while waiting for the next job
+            let job = job_rx.lock().unwrap().recv();
+            let (index, job) = match job {
+               Ok(job) => job,
+               Err(_) => break,
+            };
+            // A panic is handed over too, the other workers would wait for jobs forever
+            let result = panic::catch_unwind(AssertUnwindSafe(|| work(index, job)));
+            if result_tx.send((index, result)).is_err() {
+               break;
+            }
+         });
+      }
+      drop(result_tx);
+
+      // Workers stop once the job sender is dropped, including on early return
+      let job_tx = job_tx;
+      let mut read = 0;
+      let mut finished = 0;
+      let mut exhausted = false;
+      let mut pending: BTreeMap<usize, T> = BTreeMap::new();
+      loop {
+         while !exhausted && read - finished < threads {
+            match next_job(read)? {
+               Some(job) => {
+                  job_tx.send((read, job)).unwrap();
+                  read += 1;
+               }
+               None => exhausted = true,
+            }
+         }
+         if finished == read {
+            return Ok(finished);
+         }
+
+         let (index, result) = result_rx.recv().unwrap();
+         let result = match result {
+            Ok(result) => result?,
+            Err(payload) => panic::resume_unwind(payload),
+         };
+         pending.insert(index, result);
+         // Hand over every result that is next in line
+         while let Some(result) = pending.remove(&finished) {
+            finish(finished, result)?;
+            finished += 1;
+         }
+      }
+   })
+}
@@ -0,0 +1,47 @@
+/// Observer told how far an encryption, decryption or recryption got.
+///
+/// A recryption reports its capsules as chunks, a batch recryption its receivers, and
+/// processes no bytes.
+///
+/// Every callback has an empty default, so an implementation only needs the ones it
+/// uses. Callbacks are made in order on the thread writing the output: the one that called
+/// the library function, or a thread of tokio's blocking pool for the async functions.
+pub trait Progress {
+   /// The run is about to start, with `done` of its `chunks` already done by an
+   /// interrupted run it resumes. Not called for streams, whose length is unknown.
+   fn start(&mut self, _chunks: usize, _done: usize) {}
+
+   /// Another `bytes` of plaintext were encrypted or decrypted.
+   fn bytes_processed(&mut self, _bytes: u64) {}
+
+   /// The chunk at `index` was written to the output.
+   fn chunk_done(&mut self, _index: usize) {}
+
+   /// Every chunk was written.
+   fn finish(&mut self) {}
+}
+
+// Lets an observer be lent to an `Encryptor` or `Decryptor` and read afterwards
+impl<P: Progress + ?Sized> Progress for &mut P {
+   fn start(&mut self, chunks: usize, done: usize) {
+      (**self).start(chunks, done)
+   }
+
+   fn bytes_processed(&mut self, bytes: u64) {
+      (**self).bytes_processed(bytes)
+   }
+
+   fn chunk_done(&mut self, index: usize) {
+      (**self).chunk_done(index)
+   }
+
+   fn finish(&mut self) {
+      (**self).finish()
+   }
+}
+
+/// Progress that is not reported anywhere.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
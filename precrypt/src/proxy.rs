@@ -0,0 +1,485 @@
+use crate::encoding::{write_keys, ByteSlice};
+use crate::{
+   parse_capsule, pipeline, DecryptionKeys, KeyEncoding, Manifest, PrecryptError, Progress,
+   RecryptionKeys, Recryptor, Result,
+};
+use serde::ser::{Error as _, SerializeSeq};
+use serde::{Deserialize, Serialize, Serializer};
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::ops::Range;
+use umbral_pre::*;
+use zeroize::Zeroizing;
+
+/// Signing key the owner signs key fragments with.
+///
+/// It is derived from the owner secret, so it stays the same for every file and
+/// delegation of the owner and its verifying key can be published once.
+pub fn owner_signer(owner_secret: &SecretKey) -> Signer {
+   let secret = owner_secret.to_secret_array();
+   let factory = SecretKeyFactory::from_secure_randomness(secret.as_secret()).unwrap();
+   Signer::new(factory.make_key(b"precrypt signing key"))
+}
+
+/// What a single re-encryption proxy needs to translate a file's capsules to one receiver.
+///
+/// Holds one of the `shares` key fragments of a delegation but never the owner secret,
+/// so a proxy alone can't decrypt the file or grant access to it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProxyKeys {
+   #[serde(with = "crate::encoding::bytes")]
+   owner_pubkey: Vec<u8>,
+   #[serde(with = "crate::encoding::byte_list")]
+   capsules: Vec<Vec<u8>>,
+   // Wiped when the keys are dropped
+   #[serde(with = "crate::encoding::bytes")]
+   translation_key: Zeroizing<Vec<u8>>,
+   #[serde(with = "crate::encoding::bytes")]
+   receiver_pubkey: Vec<u8>,
+   #[serde(with = "crate::encoding::bytes")]
+   verifying_key: Vec<u8>,
+   threshold: usize,
+   chunk_size: usize,
+   #[serde(
+      default,
+      skip_serializing_if = "Option::is_none",
+      with = "crate::encoding::optional_bytes"
+   )]
+   sealed_file_key: Option<Vec<u8>>,
+   #[serde(default, skip_serializing_if = "Option::is_none")]
+   manifest: Option<Manifest>,
+}
+
+impl ProxyKeys {
+   /// Keeps only the capsules of the chunks in `chunks`, so just those are translated.
+   ///
+   /// The manifest is signed again with the chunks granted by `signer`, the owner's.
+   pub(crate) fn restrict(mut self, chunks: Range<usize>, signer: &Signer) -> Result<ProxyKeys> {
+      if self.sealed_file_key.is_some() {
+         return Err(PrecryptError::InvalidConfig(
+            "a file with a single capsule can only be granted whole".to_string(),
+         ));
+      }
+      // The manifest tells the receiver which chunks are granted and checks them
+      let manifest = match &self.manifest {
+         Some(manifest) if manifest.granted_chunks().is_none() => manifest,
+         _ => {
+            return Err(PrecryptError::InvalidConfig(
+               "only keys of a whole file with a manifest can be granted in part".to_string(),
+            ))
+         }
+      };
+      if chunks.is_empty() || chunks.end > self.capsules.len() {
+         return Err(PrecryptError::InvalidConfig(format!(
+            "chunk range {:?} is empty or past the file's {} chunks",
+            chunks,
+            self.capsules.len()
+         )));
+      }
+      self.manifest = Some(manifest.grant(signer, chunks.clone()));
+      self.capsules = self.capsules[chunks].to_vec();
+      Ok(self)
+   }
+}
+
+// Never prints the proxy's key fragment
+impl fmt::Debug for ProxyKeys {
+   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("ProxyKeys")
+         .field("translation_key", &"[redacted]")
+         .field("capsules", &self.capsules.len())
+         .field("threshold", &self.threshold)
+         .field("chunk_size", &self.chunk_size)
+         .finish_non_exhaustive()
+   }
+}
+
+/// Splits the delegation to `receiver_public` into `shares` proxy keys.
+///
+/// Any `threshold` of the proxies' translated keys, combined with
+/// `combine_decryption_keys`, let the receiver decrypt the file; fewer don't.
+pub fn split_recryption_keys(
+   recryption_keys: &RecryptionKeys,
+   receiver_public: PublicKey,
+   threshold: usize,
+   shares: usize,
+) -> Result<Vec<ProxyKeys>> {
+   if threshold == 0 || threshold > shares {
+      return Err(PrecryptError::InvalidConfig(format!(
+         "'threshold' must be between 1 and the number of shares ({})",
+         shares
+      )));
+   }
+   let owner_secret = recryption_keys.owner_secret()?;
+   // Signing both keys binds every fragment to this owner and receiver
+   let signer = owner_signer(&owner_secret);
+   let translation_keys = generate_kfrags(
+      &owner_secret,
+      &receiver_public,
+      &signer,
+      threshold,
+      shares,
+      true,
+      true,
+   );
+
+   let owner_pubkey = owner_secret.public_key().to_array().to_vec();
+   let receiver_pubkey = receiver_public.to_array().to_vec();
+   let verifying_key = signer.verifying_key().to_array().to_vec();
+   let proxy_keys = translation_keys
+      .iter()
+      .map(|translation_key| ProxyKeys {
+         owner_pubkey: owner_pubkey.clone(),
+         capsules: recryption_keys.capsules.clone(),
+         translation_key: Zeroizing::new(translation_key.to_array().to_vec()),
+         receiver_pubkey: receiver_pubkey.clone(),
+         verifying_key: verifying_key.clone(),
+         threshold,
+         chunk_size: recryption_keys.chunk_size,
+         sealed_file_key: recryption_keys.sealed_file_key.clone(),
+         manifest: recryption_keys.manifest.clone(),
+      })
+      .collect();
+   Ok(proxy_keys)
+}
+
+/// Translates every capsule with the proxy's key fragment, on a worker per core.
+///
+/// The key fragment is first checked against the owner's verifying key. The result is
+/// one share of the receiver's decryption keys; unless the threshold is one, it has to
+/// be combined with the shares of other proxies before decrypting.
+///
+/// Use `Recryptor` to pick the workers, report progress or write the keys as they're made.
+pub fn proxy_recrypt(proxy_keys: &ProxyKeys) -> Result<DecryptionKeys> {
+   Recryptor::builder().build()?.proxy_recrypt(proxy_keys)
+}
+
+// Translates the capsules on `workers` threads, handing the translated keys to `translated`
+// in capsule order
+fn translate_capsules(
+   proxy_keys: &ProxyKeys,
+   workers: usize,
+   progress: &mut dyn Progress,
+   mut translated: impl FnMut(Vec<u8>) -> Result<()>,
+) -> Result<()> {
+   let owner_pubkey = parse_public_key(&proxy_keys.owner_pubkey)?;
+   let receiver_pubkey = parse_public_key(&proxy_keys.receiver_pubkey)?;
+   let verifying_key = parse_public_key(&proxy_keys.verifying_key)?;
+   let translation_key = KeyFrag::from_bytes(&proxy_keys.translation_key)
+      .map_err(PrecryptError::InvalidKey)?
+      .verify(&verifying_key, Some(&owner_pubkey), Some(&receiver_pubkey))
+      .map_err(|(source, _)| PrecryptError::KeyVerification(source))?;
+
+   let capsules = &proxy_keys.capsules;
+   progress.start(capsules.len(), 0);
+   // No point in starting more workers than there are capsules
+   pipeline::run(
+      workers.min(capsules.len()).max(1),
+      |index| Ok(capsules.get(index)),
+      |index, capsule_vec| {
+         let capsule = parse_capsule(index, capsule_vec)?;
+         Ok(reencrypt(&capsule, translation_key.clone()).to_array().to_vec())
+      },
+      |index, translated_key| {
+         translated(translated_key)?;
+         progress.chunk_done(index);
+         Ok(())
+      },
+   )?;
+   progress.finish();
+   Ok(())
+}
+
+/// Translates every capsule like `proxy_recrypt`, keeping the translated keys in memory.
+pub(crate) fn translate(
+   proxy_keys: &ProxyKeys,
+   workers: usize,
+   progress: &mut dyn Progress,
+) -> Result<DecryptionKeys> {
+   let mut translated_keys = Vec::with_capacity(proxy_keys.capsules.len());
+   translate_capsules(proxy_keys, workers, progress, |translated_key| {
+      translated_keys.push(translated_key);
+      Ok(())
+   })?;
+   Ok(DecryptionKeys {
+      owner_pubkey: proxy_keys.owner_pubkey.clone(),
+      capsules: proxy_keys.capsules.clone(),
+      translated_keys,
+      chunk_size: proxy_keys.chunk_size,
+      sealed_file_key: proxy_keys.sealed_file_key.clone(),
+      threshold: proxy_keys.threshold,
+      verifying_key: proxy_keys.verifying_key.clone(),
+      manifest: proxy_keys.manifest.clone(),
+   })
+}
+
+/// Translates every capsule like `proxy_recrypt`, writing each translated key to `output`
+/// as soon as it's made instead of keeping them all in memory.
+pub(crate) fn write_translated<W: Write>(
+   proxy_keys: &ProxyKeys,
+   workers: usize,
+   progress: &mut dyn Progress,
+   output: W,
+   encoding: KeyEncoding,
+) -> Result<()> {
+   let keys = StreamedDecryptionKeys {
+      owner_pubkey: &proxy_keys.owner_pubkey,
+      capsules: &proxy_keys.capsules,
+      translated_keys: TranslatedKeys {
+         proxy_keys,
+         workers,
+         progress: RefCell::new(progress),
+         error: RefCell::new(None),
+      },
+      chunk_size: proxy_keys.chunk_size,
+      sealed_file_key: proxy_keys.sealed_file_key.as_ref(),
+      threshold: proxy_keys.threshold,
+      verifying_key: &proxy_keys.verifying_key,
+      manifest: proxy_keys.manifest.as_ref(),
+   };
+   let written = write_keys(&keys, output, encoding);
+   // A failed translation shows up as a serialization error, report what actually failed
+   match keys.translated_keys.error.take() {
+      Some(err) => Err(err),
+      None => written,
+   }
+}
+
+// Serializes like `DecryptionKeys`, translating the capsules while their keys are written
+#[derive(Serialize)]
+struct StreamedDecryptionKeys<'a> {
+   #[serde(with = "crate::encoding::bytes")]
+   owner_pubkey: &'a [u8],
+   #[serde(with = "crate::encoding::byte_list")]
+   capsules: &'a [Vec<u8>],
+   translated_keys: TranslatedKeys<'a>,
+   chunk_size: usize,
+   #[serde(
+      skip_serializing_if = "Option::is_none",
+      serialize_with = "crate::encoding::optional_bytes::serialize"
+   )]
+   sealed_file_key: Option<&'a Vec<u8>>,
+   threshold: usize,
+   #[serde(with = "crate::encoding::bytes")]
+   verifying_key: &'a [u8],
+   #[serde(skip_serializing_if = "Option::is_none")]
+   manifest: Option<&'a Manifest>,
+}
+
+struct TranslatedKeys<'a> {
+   proxy_keys: &'a ProxyKeys,
+   workers: usize,
+   progress: RefCell<&'a mut dyn Progress>,
+   // Why the translation failed, serializers only take their own errors
+   error: RefCell<Option<PrecryptError>>,
+}
+
+impl Serialize for TranslatedKeys<'_> {
+   fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+      let mut seq = serializer.serialize_seq(Some(self.proxy_keys.capsules.len()))?;
+      let mut write_error = None;
+      let mut progress = self.progress.borrow_mut();
+      let result = translate_capsules(self.proxy_keys, self.workers, *progress, |translated_key| {
+         seq.serialize_element(&ByteSlice(&translated_key)).map_err(|err| {
+            let msg = err.to_string();
+            write_error = Some(err);
+            PrecryptError::InvalidKeyEncoding(msg)
+         })
+      });
+      if let Some(err) = write_error {
+         return Err(err);
+      }
+      if let Err(err) = result {
+         let msg = err.to_string();
+         *self.error.borrow_mut() = Some(err);
+         return Err(S::Error::custom(msg));
+      }
+      seq.end()
+   }
+}
+
+/// Translates the keys to every receiver in `receivers`, acting as their only proxy.
+///
+/// The owner secret and the capsules are parsed once for the whole batch, and receivers
+/// are translated on `workers` threads. Their keys are handed to `granted` with the
+/// receiver's index, in the order of `receivers`.
+pub(crate) fn translate_batch(
+   recryption_keys: &RecryptionKeys,
+   receivers: &[PublicKey],
+   workers: usize,
+   progress: &mut dyn Progress,
+   mut granted: impl FnMut(usize, DecryptionKeys) -> Result<()>,
+) -> Result<()> {
+   let owner_secret = recryption_keys.owner_secret()?;
+   let signer = owner_signer(&owner_secret);
+   let capsules = recryption_keys
+      .capsules
+      .iter()
+      .enumerate()
+      .map(|(index, capsule_vec)| parse_capsule(index, capsule_vec))
+      .collect::<Result<Vec<_>>>()?;
+   let owner_pubkey = owner_secret.public_key().to_array().to_vec();
+   let verifying_key = signer.verifying_key().to_array().to_vec();
+
+   progress.start(receivers.len(), 0);
+   pipeline::run(
+      workers.min(receivers.len()).max(1),
+      |index| Ok(receivers.get(index)),
+      |_, receiver_public| {
+         // Same as `split_recryption_keys` with a single share
+         let translation_keys =
+            generate_kfrags(&owner_secret, receiver_public, &signer, 1, 1, true, true);
+         let translated_keys = capsules
+            .iter()
+            .map(|capsule| {
+               reencrypt(capsule, translation_keys[0].clone()).to_array().to_vec()
+            })
+            .collect();
+         Ok(DecryptionKeys {
+            owner_pubkey: owner_pubkey.clone(),
+            capsules: recryption_keys.capsules.clone(),
+            translated_keys,
+            chunk_size: recryption_keys.chunk_size,
+            sealed_file_key: recryption_keys.sealed_file_key.clone(),
+            threshold: 1,
+            verifying_key: verifying_key.clone(),
+            manifest: recryption_keys.manifest.clone(),
+         })
+      },
+      |index, decryption_keys| {
+         granted(index, decryption_keys)?;
+         progress.chunk_done(index);
+         Ok(())
+      },
+   )?;
+   progress.finish();
+   Ok(())
+}
+
+/// Combines the decryption key shares of different proxies into keys that can decrypt.
+///
+/// Shares translated with the same key fragment are only counted once, even from separate
+/// runs of a proxy. Fails if fewer than `threshold` distinct shares are given or if they
+/// were not made for the same file.
+pub fn combine_decryption_keys(shares: &[DecryptionKeys]) -> Result<DecryptionKeys> {
+   let first = match shares.first() {
+      Some(first) => first,
+      None => return Err(PrecryptError::ThresholdNotMet { shares: 0, threshold: 1 }),
+   };
+   let mut distinct: Vec<(Option<&[u8]>, &DecryptionKeys)> = Vec::new();
+   for share in shares {
+      if share.owner_pubkey != first.owner_pubkey
+         || share.capsules != first.capsules
+         || share.chunk_size != first.chunk_size
+         || share.sealed_file_key != first.sealed_file_key
+         || share.threshold != first.threshold
+         || share.verifying_key != first.verifying_key
+         || share.manifest != first.manifest
+      {
+         return Err(PrecryptError::InvalidConfig(
+            "decryption key shares belong to different files".to_string(),
+         ));
+      }
+      if share.translated_keys.len() != share.capsules.len() {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks: share.translated_keys.len(),
+            capsules: share.capsules.len(),
+         });
+      }
+      // Translating is randomized, so only the key fragment tells two runs apart
+      let kfrag_id = share_kfrag_id(share)?;
+      let duplicate = distinct.iter().any(|(other_id, other)| match (other_id, kfrag_id) {
+         (Some(other_id), Some(kfrag_id)) => *other_id == kfrag_id,
+         _ => other.translated_keys == share.translated_keys,
+      });
+      if !duplicate {
+         distinct.push((kfrag_id, share));
+      }
+   }
+   if distinct.len() < first.threshold {
+      return Err(PrecryptError::ThresholdNotMet {
+         shares: distinct.len(),
+         threshold: first.threshold,
+      });
+   }
+
+   // Every translated key holds one capsule fragment from each of the used shares
+   let translated_keys = (0..first.capsules.len())
+      .map(|index| {
+         distinct[..first.threshold]
+            .iter()
+            .flat_map(|(_, share)| share.translated_keys[index].iter().copied())
+            .collect()
+      })
+      .collect();
+   Ok(DecryptionKeys {
+      translated_keys,
+      ..first.clone()
+   })
+}
+
+/// Public keys a capsule fragment is checked against before it is used.
+pub(crate) struct FragmentVerifier {
+   pub verifying_key: PublicKey,
+   pub owner_pubkey: PublicKey,
+   pub receiver_pubkey: PublicKey,
+}
+
+impl FragmentVerifier {
+   /// Parses the capsule fragments of one translated key and verifies each of them.
+   ///
+   /// A fragment that was not produced from the owner's signed delegation to the
+   /// receiver, or not from this capsule, fails with the index of its chunk.
+   pub fn verify(
+      &self,
+      index: usize,
+      capsule: &Capsule,
+      translated_key: &[u8],
+   ) -> Result<Vec<VerifiedCapsuleFrag>> {
+      translated_key
+         .chunks(CapsuleFrag::serialized_size())
+         .map(|fragment| {
+            CapsuleFrag::from_bytes(fragment)
+               .map_err(PrecryptError::InvalidKey)?
+               .verify(
+                  capsule,
+                  &self.verifying_key,
+                  &self.owner_pubkey,
+                  &self.receiver_pubkey,
+               )
+               .map_err(|(source, _)| PrecryptError::Verification { index, source })
+         })
+         .collect()
+   }
+}
+
+fn parse_public_key(bytes: &[u8]) -> Result<PublicKey> {
+   PublicKey::from_bytes(bytes).map_err(PrecryptError::InvalidKey)
+}
+
+// A capsule fragment is serialized as two points, the ID of the key fragment that made it,
+// a point and a proof
+const KFRAG_ID_OFFSET: usize = 2 * 33;
+const KFRAG_ID_LEN: usize = 32;
+
+// ID of the key fragment every capsule fragment of a single proxy's share was made with,
+// none for a file without capsules
+fn share_kfrag_id(share: &DecryptionKeys) -> Result<Option<&[u8]>> {
+   let mut kfrag_id = None;
+   for translated_key in &share.translated_keys {
+      if translated_key.len() != CapsuleFrag::serialized_size() {
+         return Err(PrecryptError::InvalidConfig(
+            "only shares of a single proxy can be combined".to_string(),
+         ));
+      }
+      let id = &translated_key[KFRAG_ID_OFFSET..KFRAG_ID_OFFSET + KFRAG_ID_LEN];
+      if kfrag_id.is_some_and(|kfrag_id| kfrag_id != id) {
+         return Err(PrecryptError::InvalidConfig(
+            "share was translated with more than one key fragment".to_string(),
+         ));
+      }
+      kfrag_id = Some(id);
+   }
+   Ok(kfrag_id)
+}
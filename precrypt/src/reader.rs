@@ -0,0 +1,286 @@
+use crate::container::{count_chunks, detect_header};
+use crate::file_key::FileKey;
+use crate::proxy::FragmentVerifier;
+use crate::{parse_capsule, CapsuleMode, DecryptionKeys, Manifest, PrecryptError, Result};
+use std::collections::VecDeque;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::sync::Arc;
+use umbral_pre::*;
+use zeroize::Zeroizing;
+
+const DEFAULT_CACHED_CHUNKS: usize = 4;
+
+/// Decrypts any byte range of a ciphertext without decrypting the chunks before it.
+///
+/// Seeking maps the plaintext position to its chunk using the fixed chunk size, skipping
+/// the padding in front of appended data, and only
+/// the chunks that are actually read get decrypted. The most recently used chunks are
+/// kept in memory so small sequential reads don't decrypt the same chunk twice.
+///
+/// Every chunk read is checked against the keys' manifest, but since the plaintext is
+/// never read as a whole its digest is not.
+///
+/// The keys are only read, so several readers can share them through an `Arc`, e.g. to
+/// decrypt different ranges of a file on different threads.
+///
+/// With keys granting part of a file, the reader reads the plaintext of the granted chunks
+/// alone, from the whole container or from the bytes of its `ciphertext_range`.
+pub struct DecryptingReader<R: Read + Seek> {
+   input: R,
+   chunks: ChunkDecrypter,
+   // Offset of the first chunk in `input`, past the header if there is one
+   body_offset: u64,
+   chunk_count: usize,
+   plaintext_len: u64,
+   position: u64,
+   // Decrypted chunks are wiped when they leave the cache
+   cache: VecDeque<(usize, Zeroizing<Vec<u8>>)>,
+   cached_chunks: usize,
+}
+
+impl<R: Read + Seek> DecryptingReader<R> {
+   pub fn new(
+      mut input: R,
+      receiver_key: SecretKey,
+      decryption_keys: impl Into<Arc<DecryptionKeys>>,
+   ) -> Result<Self> {
+      let chunks = ChunkDecrypter::new(receiver_key, decryption_keys.into())?;
+      let decryption_keys = &chunks.decryption_keys;
+      let input_len = input.seek(SeekFrom::End(0))?;
+      input.seek(SeekFrom::Start(0))?;
+      let header = detect_header(&mut input)?.0;
+      let mut body_offset = 0;
+      let mut body_len = input_len;
+      if let Some(header) = &header {
+         header.check_keys(decryption_keys)?;
+         body_offset = header.encoded_len() as u64;
+         body_len -= body_offset;
+         header.check_body_len(body_len)?;
+         // Only the chunks of the keys are read, the granted ones or those from before an append
+         if let Some(body) = header.keys_body(decryption_keys) {
+            body_offset += body.start;
+            body_len = (body_len - body.start).min(body.end - body.start);
+         }
+      }
+      let chunk_count = count_chunks(body_len, decryption_keys.chunk_size);
+      let mode = decryption_keys.mode();
+      let capsules = decryption_keys.capsules.len();
+      if mode == CapsuleMode::PerChunk && chunk_count != capsules {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks: chunk_count,
+            capsules,
+         });
+      }
+      if let Some(manifest) = chunks.manifest.as_ref().filter(|_| !decryption_keys.is_partial()) {
+         if chunk_count != manifest.chunk_count() {
+            return Err(PrecryptError::ChunkCountMismatch {
+               chunks: chunk_count,
+               capsules: manifest.chunk_count(),
+            });
+         }
+      }
+      if mode == CapsuleMode::PerFile && chunk_count == 0 {
+         return Err(PrecryptError::Truncated);
+      }
+      let overhead = mode.chunk_overhead();
+      if chunk_count > 0 && decryption_keys.chunk_size <= overhead {
+         return Err(PrecryptError::InvalidConfig(format!(
+            "chunk size must be larger than {}",
+            overhead
+         )));
+      }
+      // A last chunk too short to hold its overhead was cut off
+      let mut last_len = match chunk_count {
+         0 => 0,
+         _ => body_len - ((chunk_count - 1) * decryption_keys.chunk_size) as u64,
+      };
+      // A full slot may end with the padding in front of data appended after the chunk
+      let last = decryption_keys.first_chunk() + chunk_count.saturating_sub(1);
+      let padded = chunks.manifest.as_ref().is_some_and(|manifest| manifest.may_be_padded(last));
+      if chunk_count > 0 && last_len == decryption_keys.chunk_size as u64 && padded {
+         let last_start = ((chunk_count - 1) * decryption_keys.chunk_size) as u64;
+         input.seek(SeekFrom::Start(body_offset + last_start))?;
+         let mut slot = Vec::new();
+         (&mut input).take(last_len).read_to_end(&mut slot)?;
+         last_len = decryption_keys.unpadded_len(chunk_count - 1, &slot)? as u64;
+      }
+      let plaintext_len = match chunk_count {
+         0 => 0,
+         _ if last_len < overhead as u64 => return Err(PrecryptError::Truncated),
+         _ => decryption_keys.plaintext_offset(chunk_count - 1) + last_len - overhead as u64,
+      };
+
+      Ok(DecryptingReader {
+         input,
+         chunks,
+         body_offset,
+         chunk_count,
+         plaintext_len,
+         position: 0,
+         cache: VecDeque::new(),
+         cached_chunks: DEFAULT_CACHED_CHUNKS,
+      })
+   }
+
+   /// Sets how many decrypted chunks are kept in memory (at least one).
+   pub fn cached_chunks(mut self, cached_chunks: usize) -> Self {
+      self.cached_chunks = cached_chunks.max(1);
+      self.cache.truncate(self.cached_chunks);
+      self
+   }
+
+   /// Total length of the decrypted plaintext.
+   pub fn plaintext_len(&self) -> u64 {
+      self.plaintext_len
+   }
+
+   pub fn into_inner(self) -> R {
+      self.input
+   }
+
+   // Returns the decrypted chunk, moving it to the front of the cache
+   fn chunk(&mut self, index: usize) -> Result<&[u8]> {
+      match self.cache.iter().position(|(i, _)| *i == index) {
+         Some(cached) => {
+            let entry = self.cache.remove(cached).unwrap();
+            self.cache.push_front(entry);
+         }
+         None => {
+            let plaintext = self.decrypt_chunk(index)?;
+            self.cache.truncate(self.cached_chunks - 1);
+            self.cache.push_front((index, plaintext));
+         }
+      }
+      Ok(&self.cache[0].1)
+   }
+
+   fn decrypt_chunk(&mut self, index: usize) -> Result<Zeroizing<Vec<u8>>> {
+      let chunk_size = self.chunks.decryption_keys.chunk_size;
+      self.input
+         .seek(SeekFrom::Start(self.body_offset + (index * chunk_size) as u64))?;
+      let mut buffer = Vec::new();
+      (&mut self.input)
+         .take(chunk_size as u64)
+         .read_to_end(&mut buffer)?;
+      self.chunks.decrypt(index, index + 1 == self.chunk_count, &buffer)
+   }
+}
+
+/// Decrypts the chunks of a ciphertext one at a time, in any order.
+///
+/// The keys are verified once up front; every chunk is then checked against the manifest
+/// before it's decrypted.
+pub(crate) struct ChunkDecrypter {
+   receiver_key: SecretKey,
+   verifier: FragmentVerifier,
+   pub decryption_keys: Arc<DecryptionKeys>,
+   pub manifest: Option<Manifest>,
+   // Key the chunks are derived from, for files with a single capsule
+   file_key: Option<FileKey>,
+}
+
+impl ChunkDecrypter {
+   pub fn new(receiver_key: SecretKey, decryption_keys: Arc<DecryptionKeys>) -> Result<Self> {
+      decryption_keys.check_threshold()?;
+      let verifier = decryption_keys.fragment_verifier(&receiver_key)?;
+      let manifest = decryption_keys.verified_manifest(&verifier)?.cloned();
+      let capsules = decryption_keys.capsules.len();
+      if decryption_keys.translated_keys.len() != capsules {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks: decryption_keys.translated_keys.len(),
+            capsules,
+         });
+      }
+      let file_key = match decryption_keys.mode() {
+         CapsuleMode::PerChunk => None,
+         CapsuleMode::PerFile => {
+            Some(decryption_keys.open_file_key(&receiver_key, &verifier)?)
+         }
+      };
+      Ok(ChunkDecrypter {
+         receiver_key,
+         verifier,
+         decryption_keys,
+         manifest,
+         file_key,
+      })
+   }
+
+   /// Decrypts the ciphertext chunk at `index` of the keys' chunks, `last` if no chunk
+   /// follows it.
+   pub fn decrypt(
+      &self,
+      index: usize,
+      last: bool,
+      ciphertext: &[u8],
+   ) -> Result<Zeroizing<Vec<u8>>> {
+      let decryption_keys = &self.decryption_keys;
+      // Position of the chunk in the whole file
+      let chunk = decryption_keys.first_chunk() + index;
+      let ciphertext = match &self.manifest {
+         Some(manifest) => manifest.check_chunk(chunk, ciphertext)?,
+         None => ciphertext,
+      };
+      if let Some(file_key) = &self.file_key {
+         return file_key.decrypt_chunk(&decryption_keys.capsules[0], index, last, ciphertext);
+      }
+      let capsules = decryption_keys.capsules.len();
+      if index >= capsules {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks: index + 1,
+            capsules,
+         });
+      }
+      let capsule = parse_capsule(chunk, &decryption_keys.capsules[index])?;
+      let translated_keys =
+         self.verifier.verify(chunk, &capsule, &decryption_keys.translated_keys[index])?;
+      let plaintext = decrypt_reencrypted(
+         &self.receiver_key,
+         &self.verifier.owner_pubkey,
+         &capsule,
+         translated_keys,
+         ciphertext,
+      )
+      .map_err(|source| PrecryptError::Decryption { index: chunk, source })?;
+      Ok(Zeroizing::new(plaintext.into_vec()))
+   }
+}
+
+impl<R: Read + Seek> Read for DecryptingReader<R> {
+   fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+      if buf.is_empty() || self.position >= self.plaintext_len {
+         return Ok(0);
+      }
+      let decryption_keys = &self.chunks.decryption_keys;
+      let index = decryption_keys.chunk_at(self.position);
+      let offset = (self.position - decryption_keys.plaintext_offset(index)) as usize;
+      let chunk = self.chunk(index)?;
+      let available = &chunk[offset.min(chunk.len())..];
+      let len = available.len().min(buf.len());
+      buf[..len].copy_from_slice(&available[..len]);
+      self.position += len as u64;
+      Ok(len)
+   }
+}
+
+impl<R: Read + Seek> Seek for DecryptingReader<R> {
+   fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+      let position = match pos {
+         SeekFrom::Start(offset) => Some(offset),
+         SeekFrom::End(offset) => self.plaintext_len.checked_add_signed(offset),
+         SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+      };
+      match position {
+         Some(position) => {
+            self.position = position;
+            Ok(position)
+         }
+         None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+         )),
+      }
+   }
+}
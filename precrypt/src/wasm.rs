@@ -0,0 +1,210 @@
+use crate::container::{header_len, Header, MAGIC};
+use crate::manifest::PlaintextHash;
+use crate::reader::ChunkDecrypter;
+use crate::{decode_keys, owner_signer, CapsuleMode, DecryptionKeys, PrecryptError, Result};
+use js_sys::Uint8Array;
+use umbral_pre::*;
+use wasm_bindgen::prelude::*;
+use zeroize::Zeroizing;
+
+/// Generates a keypair, as JSON in the same format as the CLI's `keygen`.
+#[wasm_bindgen]
+pub fn keygen() -> String {
+   let secret_key = SecretKey::random();
+   let secret_box = secret_key.to_secret_array();
+   let keypair = serde_json::json!({
+      "public_key": secret_key.public_key().to_array().to_vec(),
+      "secret_key": secret_box.as_secret().to_vec(),
+      "verifying_key": owner_signer(&secret_key).verifying_key().to_array().to_vec(),
+   });
+   keypair.to_string()
+}
+
+/// Public key of `secret_key`, to hand to whoever grants access to a file.
+#[wasm_bindgen(js_name = publicKey)]
+pub fn public_key(secret_key: &[u8]) -> std::result::Result<Vec<u8>, JsError> {
+   let secret_key = SecretKey::from_bytes(secret_key).map_err(PrecryptError::InvalidKey)?;
+   Ok(secret_key.public_key().to_array().to_vec())
+}
+
+/// Decrypts a ciphertext handed over in pieces of any size, e.g. as it's fetched.
+///
+/// Every chunk is checked against the manifest as soon as it's complete, so tampering is
+/// caught before the chunk's plaintext is returned. The digest of the whole plaintext and
+/// the number of chunks can only be checked by `finish`, whose error must therefore
+/// discard everything decrypted before.
+///
+/// Keys granting part of a file decrypt the bytes of their ciphertext range, starting at
+/// the first granted chunk, rather than the whole container.
+#[wasm_bindgen]
+pub struct StreamDecryptor {
+   chunks: ChunkDecrypter,
+   // Ciphertext received but not decrypted yet
+   pending: Vec<u8>,
+   // Whether the header, if there is one, was read
+   body_started: bool,
+   // Number of chunks the header lists
+   header_chunks: Option<usize>,
+   // Number of chunks the keys decrypt, when data was appended after they were made
+   keys_chunks: Option<usize>,
+   decrypted: usize,
+   plaintext_hash: PlaintextHash,
+   finished: bool,
+}
+
+#[wasm_bindgen]
+impl StreamDecryptor {
+   /// Takes the `DecryptionKeys` JSON and the receiver's secret key bytes.
+   #[wasm_bindgen(constructor)]
+   pub fn new(
+      decryption_keys: &str,
+      secret_key: &[u8],
+   ) -> std::result::Result<StreamDecryptor, JsError> {
+      let decryption_keys: DecryptionKeys = decode_keys(decryption_keys.as_bytes())?;
+      let overhead = decryption_keys.mode().chunk_overhead();
+      if decryption_keys.chunk_size <= overhead {
+         return Err(PrecryptError::InvalidConfig(format!(
+            "chunk size must be larger than {}",
+            overhead
+         ))
+         .into());
+      }
+      let receiver_key = SecretKey::from_bytes(secret_key).map_err(PrecryptError::InvalidKey)?;
+      let plaintext_hash = PlaintextHash::new(decryption_keys.segment_starts());
+      Ok(StreamDecryptor {
+         chunks: ChunkDecrypter::new(receiver_key, decryption_keys.into())?,
+         pending: Vec::new(),
+         body_started: false,
+         header_chunks: None,
+         keys_chunks: None,
+         decrypted: 0,
+         plaintext_hash,
+         finished: false,
+      })
+   }
+
+   /// Size of the encrypted chunks, a good size to fetch the ciphertext in.
+   #[wasm_bindgen(getter, js_name = chunkSize)]
+   pub fn chunk_size(&self) -> usize {
+      self.chunks.decryption_keys.chunk_size
+   }
+
+   /// Adds the next bytes of the ciphertext, returning the plaintext of the chunks they
+   /// complete.
+   ///
+   /// The last complete chunk is held back until more bytes or `finish` tell whether it's
+   /// the final one.
+   pub fn push(&mut self, ciphertext: &[u8]) -> std::result::Result<Uint8Array, JsError> {
+      self.check_open()?;
+      self.pending.extend_from_slice(ciphertext);
+      if !self.read_header(false)? {
+         return Ok(Uint8Array::new_with_length(0));
+      }
+      let chunk_size = self.chunk_size();
+      let ready = self.pending.len().saturating_sub(1) / chunk_size;
+      let mut plaintext = Zeroizing::new(Vec::with_capacity(ready * chunk_size));
+      let body: Vec<u8> = self.pending.drain(..ready * chunk_size).collect();
+      for chunk in body.chunks(chunk_size) {
+         if self.keys_done() {
+            break;
+         }
+         plaintext.extend_from_slice(&self.decrypt(chunk, false)?);
+      }
+      // What was appended after the keys were made is left alone
+      if self.keys_done() {
+         self.pending.clear();
+      }
+      Ok(Uint8Array::from(&plaintext[..]))
+   }
+
+   /// Decrypts the final chunk and checks the file is complete and unmodified.
+   pub fn finish(&mut self) -> std::result::Result<Uint8Array, JsError> {
+      self.check_open()?;
+      self.finished = true;
+      self.read_header(true)?;
+      let mut plaintext = Zeroizing::new(Vec::new());
+      if !self.pending.is_empty() && !self.keys_done() {
+         let chunk = std::mem::take(&mut self.pending);
+         plaintext = self.decrypt(&chunk, true)?;
+      }
+      self.check_complete()?;
+      Ok(Uint8Array::from(&plaintext[..]))
+   }
+}
+
+impl StreamDecryptor {
+   fn check_open(&self) -> Result<()> {
+      match self.finished {
+         true => Err(PrecryptError::InvalidConfig("decryption is already finished".to_string())),
+         false => Ok(()),
+      }
+   }
+
+   // Strips the header, returning whether the chunks after it can be decrypted yet
+   fn read_header(&mut self, finishing: bool) -> Result<bool> {
+      if self.body_started {
+         return Ok(true);
+      }
+      if !self.pending.starts_with(MAGIC) {
+         // A legacy ciphertext has no header, which is only sure once the magic would be there
+         let maybe_magic = self.pending.len() < MAGIC.len() && MAGIC.starts_with(&self.pending);
+         if maybe_magic && !finishing {
+            return Ok(false);
+         }
+         self.body_started = true;
+         return Ok(true);
+      }
+      let len = match header_len(&self.pending)? {
+         Some(len) if len <= self.pending.len() => len,
+         _ if !finishing => return Ok(false),
+         _ => return Err(PrecryptError::InvalidHeader("header is truncated".to_string())),
+      };
+      let header = Header::read_from(&mut &self.pending[..len])?;
+      header.check_keys(&self.chunks.decryption_keys)?;
+      if self.chunks.decryption_keys.is_partial() {
+         return Err(PrecryptError::InvalidConfig(
+            "keys for part of a file decrypt the bytes of their ciphertext range".to_string(),
+         ));
+      }
+      self.header_chunks = Some(header.chunk_count);
+      self.keys_chunks = header
+         .keys_body(&self.chunks.decryption_keys)
+         .map(|_| self.chunks.decryption_keys.capsules.len());
+      self.pending.drain(..len);
+      self.body_started = true;
+      Ok(true)
+   }
+
+   fn keys_done(&self) -> bool {
+      self.keys_chunks == Some(self.decrypted)
+   }
+
+   fn decrypt(&mut self, chunk: &[u8], last: bool) -> Result<Zeroizing<Vec<u8>>> {
+      let plaintext = self.chunks.decrypt(self.decrypted, last, chunk)?;
+      self.plaintext_hash.update(&plaintext);
+      self.decrypted += 1;
+      Ok(plaintext)
+   }
+
+   fn check_complete(&self) -> Result<()> {
+      let decryption_keys = &self.chunks.decryption_keys;
+      let expected = match decryption_keys.mode() {
+         // Even an empty file has a final chunk
+         CapsuleMode::PerFile if self.decrypted == 0 => return Err(PrecryptError::Truncated),
+         CapsuleMode::PerFile => self.header_chunks,
+         CapsuleMode::PerChunk => Some(decryption_keys.capsules.len()),
+      };
+      if let Some(expected) = expected.filter(|expected| *expected != self.decrypted) {
+         return Err(PrecryptError::ChunkCountMismatch {
+            chunks: self.decrypted,
+            capsules: expected,
+         });
+      }
+      match &self.chunks.manifest {
+         Some(manifest) => {
+            manifest.check_plaintext(self.decrypted, &self.plaintext_hash.clone().finalize())
+         }
+         None => Ok(()),
+      }
+   }
+}
@@ -0,0 +1,144 @@
+#![cfg(feature = "async")]
+
+use precrypt::{recrypt_keys, CapsuleMode, Decryptor, Encryptor, PrecryptError, Progress};
+use std::io;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use umbral_pre::*;
+
+#[tokio::test]
+async fn test_async_round_trip() {
+   let test_data: Vec<u8> = (0..200000u32).map(|i| (i % 251) as u8).collect();
+   let receiver = SecretKey::random();
+
+   for mode in [CapsuleMode::PerChunk, CapsuleMode::PerFile] {
+      let mut encrypted: Vec<u8> = Vec::new();
+      let recryption_keys = Encryptor::builder()
+         .workers(4)
+         .chunk_size(10000)
+         .mode(mode)
+         .build()
+         .unwrap()
+         .encrypt_async(Cursor::new(test_data.clone()), SecretKey::random(), &mut encrypted)
+         .await
+         .unwrap();
+      let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+
+      let mut decrypted: Vec<u8> = Vec::new();
+      Decryptor::builder()
+         .workers(4)
+         .build()
+         .unwrap()
+         .decrypt_async(
+            Cursor::new(encrypted),
+            &mut decrypted,
+            receiver.clone(),
+            decryption_keys,
+         )
+         .await
+         .unwrap();
+      assert_eq!(test_data, decrypted);
+   }
+}
+
+// Fails every read and write
+struct Broken;
+
+impl AsyncRead for Broken {
+   fn poll_read(
+      self: Pin<&mut Self>,
+      _: &mut Context<'_>,
+      _: &mut ReadBuf<'_>,
+   ) -> Poll<io::Result<()>> {
+      Poll::Ready(Err(io::Error::other("broken input")))
+   }
+}
+
+impl AsyncWrite for Broken {
+   fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, _: &[u8]) -> Poll<io::Result<usize>> {
+      Poll::Ready(Err(io::Error::other("broken output")))
+   }
+
+   fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+      Poll::Ready(Ok(()))
+   }
+
+   fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+      Poll::Ready(Ok(()))
+   }
+}
+
+#[tokio::test]
+async fn test_async_errors() {
+   // Read and write errors on the async side are reported, not a cancelled job
+   let result = Encryptor::builder()
+      .chunk_size(100)
+      .build()
+      .unwrap()
+      .encrypt_async(Broken, SecretKey::random(), Vec::new())
+      .await;
+   match result {
+      Err(PrecryptError::Io(err)) => assert_eq!("broken input", err.to_string()),
+      _ => panic!("expected the read error"),
+   }
+
+   let result = Encryptor::builder()
+      .chunk_size(100)
+      .build()
+      .unwrap()
+      .encrypt_async(Cursor::new(vec![0u8; 1000]), SecretKey::random(), Broken)
+      .await;
+   match result {
+      Err(PrecryptError::Io(err)) => assert_eq!("broken output", err.to_string()),
+      _ => panic!("expected the write error"),
+   }
+}
+
+// Counts the chunks written, readable while the job runs
+#[derive(Clone, Default)]
+struct ChunkCounter(Arc<AtomicUsize>);
+
+impl ChunkCounter {
+   fn chunks(&self) -> usize {
+      self.0.load(Ordering::SeqCst)
+   }
+}
+
+impl Progress for ChunkCounter {
+   fn chunk_done(&mut self, _index: usize) {
+      self.0.fetch_add(1, Ordering::SeqCst);
+   }
+}
+
+#[tokio::test]
+async fn test_async_cancel() {
+   // The input never ends, so only cancelling stops the encryption
+   let (mut writer, reader) = tokio::io::duplex(1024);
+   let counter = ChunkCounter::default();
+   let encryption = tokio::spawn(
+      Encryptor::builder()
+         .chunk_size(100)
+         .progress(counter.clone())
+         .build()
+         .unwrap()
+         .encrypt_async(reader, SecretKey::random(), tokio::io::sink()),
+   );
+   while counter.chunks() == 0 {
+      writer.write_all(&[7u8; 1000]).await.unwrap();
+      tokio::task::yield_now().await;
+   }
+   encryption.abort();
+   assert!(matches!(encryption.await, Err(err) if err.is_cancelled()));
+
+   // Nothing reads the input any more and no chunk is encrypted after the ones in flight
+   assert!(writer.write_all(&[7u8; 2000]).await.is_err());
+   std::thread::sleep(Duration::from_millis(50));
+   let cancelled_at = counter.chunks();
+   std::thread::sleep(Duration::from_millis(200));
+   assert_eq!(cancelled_at, counter.chunks());
+}
@@ -141,8 +141,8 @@ fn test_integration_threaded() {
          "tests/t_seller.json",
          "tests/t_recrypt.json",
          "tests/t_encrypted.txt",
-         "-m",
-         "10",
+         "-c",
+         "5",
          "-t",
          "2",
       ])
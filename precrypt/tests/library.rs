@@ -0,0 +1,1750 @@
+use precrypt::{
+   combine_decryption_keys, convert_legacy_file, decode_keys, decrypt_file, decrypt_stream,
+   encode_keys, owner_signer, precrypt_file, precrypt_stream, proxy_recrypt, recrypt_collection,
+   recrypt_keys, recrypt_keys_batch, recrypt_keys_with_passphrase, recrypt_manifest,
+   split_recryption_keys, Archive, CapsuleManifest, CapsuleMode, Collection, CollectionKeys,
+   DecryptingReader, DecryptionKeys, Decryptor, Encryptor, Header, KeyEncoding, Overwrite,
+   PrecryptError, Progress, ProxyKeys, RecryptionKeys, Recryptor,
+};
+use std::fs;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use umbral_pre::*;
+
+fn temp_path(name: &str) -> PathBuf {
+   std::env::temp_dir().join(format!("precrypt-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn test_round_trip() {
+   let input = temp_path("rt_secret.txt");
+   let encrypted = temp_path("rt_encrypted.txt");
+   let decrypted = temp_path("rt_decrypted.txt");
+   let test_data = "The crow flies at midnight.";
+   fs::write(&input, test_data).unwrap();
+
+   let owner = SecretKey::random();
+   let receiver = SecretKey::random();
+   let recryption_keys = precrypt_file(
+      input.to_str().unwrap(),
+      owner,
+      encrypted.to_str().unwrap(),
+      2,
+      10,
+   )
+   .unwrap();
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+   decrypt_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver,
+      &decryption_keys,
+      2,
+   )
+   .unwrap();
+   assert_eq!(test_data, fs::read_to_string(&decrypted).unwrap());
+
+   fs::remove_file(input).unwrap();
+   fs::remove_file(encrypted).unwrap();
+   fs::remove_file(decrypted).unwrap();
+}
+
+#[test]
+fn test_errors_instead_of_panics() {
+   let input = temp_path("err_secret.txt");
+   let encrypted = temp_path("err_encrypted.txt");
+   let decrypted = temp_path("err_decrypted.txt");
+   fs::write(&input, "The crow flies at midnight.").unwrap();
+
+   // There must be at least one thread
+   let result = precrypt_file(
+      input.to_str().unwrap(),
+      SecretKey::random(),
+      encrypted.to_str().unwrap(),
+      0,
+      10,
+   );
+   assert!(matches!(result, Err(PrecryptError::InvalidConfig(_))));
+
+   // Missing input file
+   let result = precrypt_file(
+      temp_path("missing.txt").to_str().unwrap(),
+      SecretKey::random(),
+      encrypted.to_str().unwrap(),
+      1,
+      10,
+   );
+   assert!(matches!(result, Err(PrecryptError::Io(_))));
+
+   let recryption_keys = precrypt_file(
+      input.to_str().unwrap(),
+      SecretKey::random(),
+      encrypted.to_str().unwrap(),
+      1,
+      10,
+   )
+   .unwrap();
+   let receiver = SecretKey::random();
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+
+   // Dropping a capsule leaves a chunk without keys
+   let mut json: serde_json::Value = serde_json::to_value(&decryption_keys).unwrap();
+   json["capsules"].as_array_mut().unwrap().pop();
+   let truncated: DecryptionKeys = serde_json::from_value(json).unwrap();
+   let result = decrypt_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver.clone(),
+      &truncated,
+      1,
+   );
+   assert!(matches!(result, Err(PrecryptError::ChunkCountMismatch { .. })));
+
+   // Corrupt owner pubkey
+   let mut json: serde_json::Value = serde_json::to_value(&decryption_keys).unwrap();
+   json["owner_pubkey"] = serde_json::json!([1, 2, 3]);
+   let corrupt: DecryptionKeys = serde_json::from_value(json).unwrap();
+   let result = decrypt_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver,
+      &corrupt,
+      1,
+   );
+   assert!(matches!(result, Err(PrecryptError::InvalidKey(_))));
+
+   // Wrong receiver key, the translated keys weren't made for it
+   let keys = decryption_keys;
+   let result = decrypt_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      SecretKey::random(),
+      &keys,
+      1,
+   );
+   assert!(matches!(result, Err(PrecryptError::Verification { index: 0, .. })));
+
+   // A body cut off shorter than the overhead of its only chunk
+   let receiver = SecretKey::random();
+   let mut body: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(b"The crow flies"), SecretKey::random(), &mut body, 1, 100)
+         .unwrap();
+   let keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+   let result = DecryptingReader::new(Cursor::new(&body[..20]), receiver.clone(), keys.clone());
+   assert!(matches!(result, Err(PrecryptError::Truncated)));
+   let result = decrypt_stream(Cursor::new(&body[..20]), std::io::sink(), receiver, &keys, 1);
+   assert!(result.is_err());
+
+   fs::remove_file(input).unwrap();
+   fs::remove_file(encrypted).unwrap();
+   let _ = fs::remove_file(decrypted);
+}
+
+#[test]
+fn test_stream_round_trip() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let owner = SecretKey::random();
+   let receiver = SecretKey::random();
+
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(&test_data), owner, &mut encrypted, 4, 64).unwrap();
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+
+   let mut decrypted: Vec<u8> = Vec::new();
+   decrypt_stream(
+      Cursor::new(&encrypted),
+      &mut decrypted,
+      receiver.clone(),
+      &decryption_keys,
+      3,
+   )
+   .unwrap();
+   assert_eq!(test_data, decrypted);
+
+   // Dropping trailing chunks leaves keys unused
+   let chunk_size = 64 / 4 + 40;
+   let result = decrypt_stream(
+      Cursor::new(&encrypted[..chunk_size * 10]),
+      std::io::sink(),
+      receiver,
+      &decryption_keys,
+      3,
+   );
+   assert!(matches!(result, Err(PrecryptError::ChunkCountMismatch { .. })));
+}
+
+#[test]
+fn test_container_header() {
+   let input = temp_path("hdr_secret.txt");
+   let encrypted = temp_path("hdr_encrypted.txt");
+   let legacy = temp_path("hdr_legacy.txt");
+   let converted = temp_path("hdr_converted.txt");
+   let decrypted = temp_path("hdr_decrypted.txt");
+   let test_data = "The crow flies at midnight.";
+   fs::write(&input, test_data).unwrap();
+
+   let receiver = SecretKey::random();
+   let recryption_keys = precrypt_file(
+      input.to_str().unwrap(),
+      SecretKey::random(),
+      encrypted.to_str().unwrap(),
+      2,
+      10,
+   )
+   .unwrap();
+   let container = fs::read(&encrypted).unwrap();
+   let header = Header::read_from(&mut Cursor::new(&container)).unwrap();
+   assert_eq!(Header::from(&recryption_keys), header);
+   assert_eq!(6, header.capsules.len());
+
+   // Keys for a different encryption of the same file don't match the header
+   let other_keys = precrypt_stream(
+      Cursor::new(test_data),
+      SecretKey::random(),
+      std::io::sink(),
+      2,
+      10,
+   )
+   .unwrap();
+   let other_keys = recrypt_keys(other_keys, receiver.public_key()).unwrap();
+   let result = decrypt_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver.clone(),
+      &other_keys,
+      2,
+   );
+   assert!(matches!(result, Err(PrecryptError::InvalidHeader(_))));
+
+   // Strip the header to get a legacy file, then convert it back
+   let header_len = header.encoded_len();
+   fs::write(&legacy, &container[header_len..]).unwrap();
+   convert_legacy_file(
+      legacy.to_str().unwrap(),
+      converted.to_str().unwrap(),
+      &header,
+   )
+   .unwrap();
+   assert_eq!(container, fs::read(&converted).unwrap());
+
+   // Legacy files still decrypt
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+   decrypt_file(
+      legacy.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver,
+      &decryption_keys,
+      2,
+   )
+   .unwrap();
+   assert_eq!(test_data, fs::read_to_string(&decrypted).unwrap());
+
+   for path in [input, encrypted, legacy, converted, decrypted] {
+      fs::remove_file(path).unwrap();
+   }
+}
+
+#[test]
+fn test_decrypting_reader() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let input = temp_path("reader_secret.bin");
+   let encrypted = temp_path("reader_encrypted.bin");
+   fs::write(&input, &test_data).unwrap();
+
+   let receiver = SecretKey::random();
+   let recryption_keys = precrypt_file(
+      input.to_str().unwrap(),
+      SecretKey::random(),
+      encrypted.to_str().unwrap(),
+      4,
+      256,
+   )
+   .unwrap();
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+
+   let f = fs::File::open(&encrypted).unwrap();
+   let mut reader = DecryptingReader::new(f, receiver, decryption_keys)
+      .unwrap()
+      .cached_chunks(2);
+   assert_eq!(1000, reader.plaintext_len());
+
+   // Range spanning a chunk boundary
+   let mut buffer = vec![0u8; 100];
+   reader.seek(SeekFrom::Start(590)).unwrap();
+   reader.read_exact(&mut buffer).unwrap();
+   assert_eq!(&test_data[590..690], &buffer[..]);
+
+   // Range at the end of the file
+   reader.seek(SeekFrom::End(-10)).unwrap();
+   let mut tail = Vec::new();
+   reader.read_to_end(&mut tail).unwrap();
+   assert_eq!(&test_data[990..], &tail[..]);
+
+   // Everything from the start
+   reader.rewind().unwrap();
+   let mut all = Vec::new();
+   reader.read_to_end(&mut all).unwrap();
+   assert_eq!(test_data, all);
+
+   fs::remove_file(input).unwrap();
+   fs::remove_file(encrypted).unwrap();
+}
+
+#[test]
+fn test_single_capsule() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let input = temp_path("single_secret.bin");
+   let encrypted = temp_path("single_encrypted.bin");
+   let decrypted = temp_path("single_decrypted.bin");
+   fs::write(&input, &test_data).unwrap();
+
+   let receiver = SecretKey::random();
+   let recryption_keys = Encryptor::builder()
+      .workers(4)
+      .chunk_size(16)
+      .mode(CapsuleMode::PerFile)
+      .build()
+      .unwrap()
+      .encrypt_file(input.to_str().unwrap(), SecretKey::random(), encrypted.to_str().unwrap())
+      .unwrap();
+   assert_eq!(CapsuleMode::PerFile, recryption_keys.mode());
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+
+   // Keys stay the same size however many chunks the file has
+   let container = fs::read(&encrypted).unwrap();
+   let header = Header::read_from(&mut Cursor::new(&container)).unwrap();
+   assert_eq!(1, header.capsules.len());
+   assert_eq!(63, header.chunk_count);
+
+   decrypt_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver.clone(),
+      &decryption_keys,
+      3,
+   )
+   .unwrap();
+   assert_eq!(test_data, fs::read(&decrypted).unwrap());
+
+   let f = fs::File::open(&encrypted).unwrap();
+   let mut reader = DecryptingReader::new(f, receiver.clone(), decryption_keys.clone()).unwrap();
+   assert_eq!(1000, reader.plaintext_len());
+   let mut buffer = vec![0u8; 100];
+   reader.seek(SeekFrom::Start(590)).unwrap();
+   reader.read_exact(&mut buffer).unwrap();
+   assert_eq!(&test_data[590..690], &buffer[..]);
+
+   // A stream gets the same header from its keys
+   let mut body: Vec<u8> = Vec::new();
+   let recryption_keys = Encryptor::builder()
+      .chunk_size(16)
+      .mode(CapsuleMode::PerFile)
+      .build()
+      .unwrap()
+      .encrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut body)
+      .unwrap();
+   let stream_keys = recrypt_keys(recryption_keys.clone(), receiver.public_key()).unwrap();
+   assert_eq!(Header::from(&recryption_keys), Header::from(&stream_keys));
+   let mut stream = Header::from(&recryption_keys).to_bytes();
+   stream.extend_from_slice(&body);
+   let mut decrypted_stream: Vec<u8> = Vec::new();
+   decrypt_stream(Cursor::new(&stream), &mut decrypted_stream, receiver.clone(), &stream_keys, 3)
+      .unwrap();
+   assert_eq!(test_data, decrypted_stream);
+   let mut reader = DecryptingReader::new(Cursor::new(stream), receiver.clone(), stream_keys)
+      .unwrap();
+   let mut decrypted_stream: Vec<u8> = Vec::new();
+   reader.read_to_end(&mut decrypted_stream).unwrap();
+   assert_eq!(test_data, decrypted_stream);
+
+   // Dropping whole chunks from the end is detected without a header, even by keys
+   // from before manifests
+   let mut unsigned = serde_json::to_value(&decryption_keys).unwrap();
+   unsigned.as_object_mut().unwrap().remove("manifest");
+   let decryption_keys: DecryptionKeys = serde_json::from_value(unsigned).unwrap();
+   let body = &container[header.encoded_len()..];
+   let chunk_size = 64 / 4 + 16;
+   let result = decrypt_stream(
+      Cursor::new(&body[..chunk_size * 10]),
+      std::io::sink(),
+      receiver.clone(),
+      &decryption_keys,
+      3,
+   );
+   assert!(matches!(result, Err(PrecryptError::AuthenticationFailed { index: 9 })));
+
+   // Swapping two chunks is detected
+   let mut swapped = body.to_vec();
+   swapped[..chunk_size * 2].rotate_left(chunk_size);
+   let result = decrypt_stream(
+      Cursor::new(&swapped),
+      std::io::sink(),
+      receiver.clone(),
+      &decryption_keys,
+      3,
+   );
+   assert!(matches!(result, Err(PrecryptError::AuthenticationFailed { .. })));
+
+   let result = decrypt_stream(
+      Cursor::new(&body[..0]),
+      std::io::sink(),
+      receiver,
+      &decryption_keys,
+      3,
+   );
+   assert!(matches!(result, Err(PrecryptError::Truncated)));
+
+   for path in [input, encrypted, decrypted] {
+      fs::remove_file(path).unwrap();
+   }
+}
+
+#[test]
+fn test_single_capsule_empty_stream() {
+   let receiver = SecretKey::random();
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys = Encryptor::builder()
+      .workers(2)
+      .chunk_size(32)
+      .mode(CapsuleMode::PerFile)
+      .build()
+      .unwrap()
+      .encrypt_stream(Cursor::new(Vec::new()), SecretKey::random(), &mut encrypted)
+      .unwrap();
+   // The final chunk holds only its tag
+   assert_eq!(16, encrypted.len());
+
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+   let mut decrypted: Vec<u8> = Vec::new();
+   decrypt_stream(
+      Cursor::new(&encrypted),
+      &mut decrypted,
+      receiver,
+      &decryption_keys,
+      2,
+   )
+   .unwrap();
+   assert!(decrypted.is_empty());
+}
+
+#[test]
+fn test_threshold_recrypt() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let receiver = SecretKey::random();
+
+   for mode in [CapsuleMode::PerChunk, CapsuleMode::PerFile] {
+      let mut encrypted: Vec<u8> = Vec::new();
+      let recryption_keys = Encryptor::builder()
+         .workers(2)
+         .chunk_size(128)
+         .mode(mode)
+         .build()
+         .unwrap()
+         .encrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted)
+         .unwrap();
+      let proxy_keys = split_recryption_keys(&recryption_keys, receiver.public_key(), 2, 3).unwrap();
+      let shares: Vec<DecryptionKeys> =
+         proxy_keys.iter().map(|keys| proxy_recrypt(keys).unwrap()).collect();
+
+      // Any two of the three proxies are enough
+      for pair in [[0, 1], [0, 2], [1, 2]] {
+         let decryption_keys =
+            combine_decryption_keys(&[shares[pair[0]].clone(), shares[pair[1]].clone()]).unwrap();
+         let mut decrypted: Vec<u8> = Vec::new();
+         decrypt_stream(
+            Cursor::new(&encrypted),
+            &mut decrypted,
+            receiver.clone(),
+            &decryption_keys,
+            2,
+         )
+         .unwrap();
+         assert_eq!(test_data, decrypted);
+      }
+
+      // A single proxy, even if given twice, can't decrypt
+      let result = combine_decryption_keys(&[shares[0].clone(), shares[0].clone()]);
+      assert!(matches!(
+         result,
+         Err(PrecryptError::ThresholdNotMet { shares: 1, threshold: 2 })
+      ));
+      // Translating again gives different bytes, but the same key fragment
+      let again = proxy_recrypt(&proxy_keys[0]).unwrap();
+      let result = combine_decryption_keys(&[shares[0].clone(), again]);
+      assert!(matches!(
+         result,
+         Err(PrecryptError::ThresholdNotMet { shares: 1, threshold: 2 })
+      ));
+      let result = decrypt_stream(
+         Cursor::new(&encrypted),
+         std::io::sink(),
+         receiver.clone(),
+         &shares[0],
+         2,
+      );
+      assert!(matches!(result, Err(PrecryptError::ThresholdNotMet { .. })));
+   }
+
+   let result = split_recryption_keys(
+      &precrypt_stream(Cursor::new(&test_data), SecretKey::random(), std::io::sink(), 2, 256)
+         .unwrap(),
+      receiver.public_key(),
+      4,
+      3,
+   );
+   assert!(matches!(result, Err(PrecryptError::InvalidConfig(_))));
+}
+
+#[test]
+fn test_verified_recrypt() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let owner = SecretKey::random();
+   let receiver = SecretKey::random();
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(&test_data), owner.clone(), &mut encrypted, 2, 256).unwrap();
+
+   // The signing key is the same for every delegation of the owner
+   let decryption_keys = recrypt_keys(recryption_keys.clone(), receiver.public_key()).unwrap();
+   assert_eq!(
+      owner_signer(&owner).verifying_key().to_array().to_vec(),
+      decryption_keys.verifying_key()
+   );
+
+   // A proxy returning the fragment of another chunk is caught on that chunk
+   let mut forged = serde_json::to_value(&decryption_keys).unwrap();
+   forged["translated_keys"].as_array_mut().unwrap().swap(2, 3);
+   let forged: DecryptionKeys = serde_json::from_value(forged).unwrap();
+   let result = decrypt_stream(
+      Cursor::new(&encrypted),
+      std::io::sink(),
+      receiver.clone(),
+      &forged,
+      1,
+   );
+   assert!(matches!(result, Err(PrecryptError::Verification { index: 2, .. })));
+
+   // Fragments made for someone else don't verify for the receiver
+   let other = SecretKey::random();
+   let other_keys = recrypt_keys(recryption_keys.clone(), other.public_key()).unwrap();
+   let result = decrypt_stream(
+      Cursor::new(&encrypted),
+      std::io::sink(),
+      receiver.clone(),
+      &other_keys,
+      1,
+   );
+   assert!(matches!(result, Err(PrecryptError::Verification { index: 0, .. })));
+
+   // Proxies refuse key fragments that weren't signed for the receiver they claim
+   let proxy_keys = split_recryption_keys(&recryption_keys, receiver.public_key(), 1, 1).unwrap();
+   let mut forged = serde_json::to_value(&proxy_keys[0]).unwrap();
+   forged["receiver_pubkey"] =
+      serde_json::to_value(other.public_key().to_array().to_vec()).unwrap();
+   let forged: ProxyKeys = serde_json::from_value(forged).unwrap();
+   assert!(matches!(proxy_recrypt(&forged), Err(PrecryptError::KeyVerification(_))));
+
+   // Keys signed by someone else check out on their own, but not against the owner's key
+   let verifying_key = owner_signer(&owner).verifying_key();
+   let mallory = SecretKey::random();
+   let mut substituted: Vec<u8> = Vec::new();
+   let mallory_keys =
+      precrypt_stream(Cursor::new(b"Not the owner's"), mallory, &mut substituted, 1, 256).unwrap();
+   let mallory_keys = recrypt_keys(mallory_keys, receiver.public_key()).unwrap();
+   let mut forged = serde_json::to_value(&decryption_keys).unwrap();
+   forged["verifying_key"] = serde_json::to_value(mallory_keys.verifying_key()).unwrap();
+   let forged: DecryptionKeys = serde_json::from_value(forged).unwrap();
+   decrypt_stream(Cursor::new(&substituted), Vec::new(), receiver.clone(), &mallory_keys, 1)
+      .unwrap();
+   let mut decryptor = Decryptor::builder().verifying_key(verifying_key).build().unwrap();
+   for (ciphertext, keys) in [(&substituted, &mallory_keys), (&encrypted, &forged)] {
+      let result =
+         decryptor.decrypt_stream(Cursor::new(ciphertext), Vec::new(), receiver.clone(), keys);
+      assert!(matches!(result, Err(PrecryptError::VerifyingKeyMismatch)));
+   }
+   let mut plaintext: Vec<u8> = Vec::new();
+   decryptor
+      .decrypt_stream(Cursor::new(&encrypted), &mut plaintext, receiver, &decryption_keys)
+      .unwrap();
+   assert_eq!(test_data, plaintext);
+}
+
+#[test]
+fn test_manifest() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let owner = SecretKey::random();
+   let receiver = SecretKey::random();
+
+   for mode in [CapsuleMode::PerChunk, CapsuleMode::PerFile] {
+      let mut encrypted: Vec<u8> = Vec::new();
+      let mut encryptor = Encryptor::builder().workers(2).chunk_size(128).mode(mode).build().unwrap();
+      let recryption_keys = encryptor
+         .encrypt_stream(Cursor::new(&test_data), owner.clone(), &mut encrypted)
+         .unwrap();
+      let manifest = recryption_keys.manifest().unwrap();
+      assert_eq!(8, manifest.chunk_count());
+      let decryption_keys = recrypt_keys(recryption_keys.clone(), receiver.public_key()).unwrap();
+      let chunk_size = match mode {
+         CapsuleMode::PerChunk => 256 / 2 + 40,
+         CapsuleMode::PerFile => 256 / 2 + 16,
+      };
+
+      // Chunks spliced in from another encryption of the same file are rejected
+      let mut other: Vec<u8> = Vec::new();
+      encryptor.encrypt_stream(Cursor::new(&test_data), owner.clone(), &mut other).unwrap();
+      let mut spliced = encrypted.clone();
+      spliced[chunk_size * 3..chunk_size * 4]
+         .copy_from_slice(&other[chunk_size * 3..chunk_size * 4]);
+      let result = decrypt_stream(
+         Cursor::new(&spliced),
+         std::io::sink(),
+         receiver.clone(),
+         &decryption_keys,
+         1,
+      );
+      assert!(matches!(result, Err(PrecryptError::ChunkMismatch { index: 3 })));
+
+      // Dropping trailing chunks together with their capsules is rejected
+      if mode == CapsuleMode::PerChunk {
+         let mut truncated = serde_json::to_value(&decryption_keys).unwrap();
+         truncated["capsules"].as_array_mut().unwrap().truncate(6);
+         truncated["translated_keys"].as_array_mut().unwrap().truncate(6);
+         let truncated: DecryptionKeys = serde_json::from_value(truncated).unwrap();
+         let result = decrypt_stream(
+            Cursor::new(&encrypted[..chunk_size * 6]),
+            std::io::sink(),
+            receiver.clone(),
+            &truncated,
+            1,
+         );
+         assert!(matches!(result, Err(PrecryptError::InvalidManifest(_))));
+      }
+
+      // A manifest that wasn't signed by the owner is rejected before decrypting
+      let mut forged = serde_json::to_value(&decryption_keys).unwrap();
+      forged["manifest"]["plaintext_hash"] = serde_json::json!(vec![0u8; 32]);
+      let forged: DecryptionKeys = serde_json::from_value(forged).unwrap();
+      let result = decrypt_stream(
+         Cursor::new(&encrypted),
+         std::io::sink(),
+         receiver.clone(),
+         &forged,
+         1,
+      );
+      assert!(matches!(result, Err(PrecryptError::InvalidManifest(_))));
+   }
+}
+
+#[test]
+fn test_resume_decrypt() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let input = temp_path("resume_secret.bin");
+   let encrypted = temp_path("resume_encrypted.bin");
+   let decrypted = temp_path("resume_decrypted.bin");
+   let checkpoint = temp_path("resume_decrypted.bin.checkpoint");
+   fs::write(&input, &test_data).unwrap();
+
+   let receiver = SecretKey::random();
+   let recryption_keys = precrypt_file(
+      input.to_str().unwrap(),
+      SecretKey::random(),
+      encrypted.to_str().unwrap(),
+      2,
+      128,
+   )
+   .unwrap();
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+
+   // Break chunk 9 so decrypting stops there, after saving the first batch
+   let container = fs::read(&encrypted).unwrap();
+   let mut broken = container.clone();
+   let chunk_size = 128 / 2 + 40;
+   let body_offset = Header::read_from(&mut Cursor::new(&container))
+      .unwrap()
+      .encoded_len();
+   broken[body_offset + 9 * chunk_size] ^= 1;
+   fs::write(&encrypted, &broken).unwrap();
+   let result = Decryptor::builder().workers(2).build().unwrap().resume_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver.clone(),
+      &decryption_keys,
+   );
+   assert!(matches!(result, Err(PrecryptError::ChunkMismatch { index: 9 })));
+   assert!(checkpoint.exists());
+
+   // The output must still hold every chunk the checkpoint records
+   let partial = fs::read(&decrypted).unwrap();
+   assert!(partial.len() >= 8 * 64);
+   fs::write(&decrypted, &partial[..100]).unwrap();
+   let result = Decryptor::builder().workers(2).build().unwrap().resume_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver.clone(),
+      &decryption_keys,
+   );
+   assert!(matches!(result, Err(PrecryptError::InvalidCheckpoint(_))));
+   fs::write(&decrypted, &partial).unwrap();
+
+   // Once the ciphertext is repaired, decryption continues after the checkpoint
+   fs::write(&encrypted, &container).unwrap();
+   let mut progress = RecordedProgress::default();
+   Decryptor::builder().workers(2).progress(&mut progress).build().unwrap().resume_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver,
+      &decryption_keys,
+   )
+   .unwrap();
+   assert_eq!(test_data, fs::read(&decrypted).unwrap());
+   assert!(!checkpoint.exists());
+   let (chunks, done) = progress.started.unwrap();
+   assert_eq!(16, chunks);
+   assert!((2..9).contains(&done));
+   assert_eq!((done..16).collect::<Vec<_>>(), progress.chunks);
+   assert_eq!((1000 - done * 64) as u64, progress.bytes);
+   assert!(progress.finished);
+
+   for path in [input, encrypted, decrypted] {
+      fs::remove_file(path).unwrap();
+   }
+}
+
+#[test]
+fn test_resume_encrypt() {
+   let input = temp_path("resume_encrypt_secret.bin");
+   let encrypted = temp_path("resume_encrypt_encrypted.bin");
+   let decrypted = temp_path("resume_encrypt_decrypted.bin");
+   let encrypted_checkpoint = temp_path("resume_encrypt_encrypted.bin.checkpoint");
+   let decrypted_checkpoint = temp_path("resume_encrypt_decrypted.bin.checkpoint");
+   let receiver = SecretKey::random();
+
+   // Stopped in the middle, or after the last checkpoint when only the short final chunk
+   // is left to account for
+   for mode in [CapsuleMode::PerChunk, CapsuleMode::PerFile] {
+      for (len, at_chunk) in [(1000u32, Some(5)), (250, None)] {
+         let test_data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+         fs::write(&input, &test_data).unwrap();
+         let owner_secret = SecretKey::random();
+
+         interrupt(|| {
+            let mut progress = InterruptingProgress { at_chunk };
+            let mut encryptor = Encryptor::builder()
+               .chunk_size(100)
+               .workers(3)
+               .mode(mode)
+               .progress(&mut progress)
+               .build()
+               .unwrap();
+            let owner_secret = owner_secret.clone();
+            encryptor
+               .encrypt_file(input.to_str().unwrap(), owner_secret, encrypted.to_str().unwrap())
+               .unwrap();
+         });
+         assert!(encrypted_checkpoint.exists());
+         let mut encryptor =
+            Encryptor::builder().chunk_size(100).workers(3).mode(mode).build().unwrap();
+         let recryption_keys = encryptor
+            .resume_file(input.to_str().unwrap(), owner_secret, encrypted.to_str().unwrap())
+            .unwrap();
+         assert!(!encrypted_checkpoint.exists());
+         let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+
+         interrupt(|| {
+            let mut progress = InterruptingProgress { at_chunk };
+            let mut decryptor =
+               Decryptor::builder().workers(3).progress(&mut progress).build().unwrap();
+            decryptor
+               .decrypt_file(
+                  encrypted.to_str().unwrap(),
+                  decrypted.to_str().unwrap(),
+                  receiver.clone(),
+                  &decryption_keys,
+               )
+               .unwrap();
+         });
+         assert!(decrypted_checkpoint.exists());
+         Decryptor::builder().workers(3).build().unwrap().resume_file(
+            encrypted.to_str().unwrap(),
+            decrypted.to_str().unwrap(),
+            receiver.clone(),
+            &decryption_keys,
+         )
+         .unwrap();
+         assert!(!decrypted_checkpoint.exists());
+         assert_eq!(test_data, fs::read(&decrypted).unwrap());
+      }
+   }
+
+   for path in [input, encrypted, decrypted] {
+      fs::remove_file(path).unwrap();
+   }
+}
+
+// Runs `run` until the progress observer stops it, like a crash would
+fn interrupt(run: impl FnOnce()) {
+   let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(run));
+   assert!(result.is_err());
+}
+
+// Stops the run once chunk `at_chunk` is written, or once every chunk is
+struct InterruptingProgress {
+   at_chunk: Option<usize>,
+}
+
+impl Progress for InterruptingProgress {
+   fn chunk_done(&mut self, index: usize) {
+      if self.at_chunk == Some(index) {
+         panic!("interrupted at chunk {}", index);
+      }
+   }
+
+   fn finish(&mut self) {
+      if self.at_chunk.is_none() {
+         panic!("interrupted after the last chunk");
+      }
+   }
+}
+
+#[derive(Default)]
+struct RecordedProgress {
+   started: Option<(usize, usize)>,
+   bytes: u64,
+   chunks: Vec<usize>,
+   finished: bool,
+}
+
+impl Progress for RecordedProgress {
+   fn start(&mut self, chunks: usize, done: usize) {
+      self.started = Some((chunks, done));
+   }
+
+   fn bytes_processed(&mut self, bytes: u64) {
+      self.bytes += bytes;
+   }
+
+   fn chunk_done(&mut self, index: usize) {
+      self.chunks.push(index);
+   }
+
+   fn finish(&mut self) {
+      self.finished = true;
+   }
+}
+
+#[test]
+fn test_progress() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let input = temp_path("progress_secret.bin");
+   let encrypted = temp_path("progress_encrypted.bin");
+   let decrypted = temp_path("progress_decrypted.bin");
+   fs::write(&input, &test_data).unwrap();
+
+   let receiver = SecretKey::random();
+   let mut progress = RecordedProgress::default();
+   let recryption_keys = Encryptor::builder()
+      .workers(4)
+      .chunk_size(100)
+      .progress(&mut progress)
+      .build()
+      .unwrap()
+      .encrypt_file(input.to_str().unwrap(), SecretKey::random(), encrypted.to_str().unwrap())
+      .unwrap();
+   assert_eq!(Some((10, 0)), progress.started);
+   assert_eq!((0..10).collect::<Vec<_>>(), progress.chunks);
+   assert_eq!(1000, progress.bytes);
+   assert!(progress.finished);
+
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+   let mut progress = RecordedProgress::default();
+   Decryptor::builder()
+      .workers(4)
+      .progress(&mut progress)
+      .build()
+      .unwrap()
+      .decrypt_file(
+         encrypted.to_str().unwrap(),
+         decrypted.to_str().unwrap(),
+         receiver,
+         &decryption_keys,
+      )
+      .unwrap();
+   assert_eq!(test_data, fs::read(&decrypted).unwrap());
+   assert_eq!(Some((10, 0)), progress.started);
+   assert_eq!((0..10).collect::<Vec<_>>(), progress.chunks);
+   assert_eq!(1000, progress.bytes);
+   assert!(progress.finished);
+
+   for path in [input, encrypted, decrypted] {
+      fs::remove_file(path).unwrap();
+   }
+}
+
+#[test]
+fn test_parallel_recrypt() {
+   let test_data: Vec<u8> = (0..2000u32).map(|i| (i % 251) as u8).collect();
+   let receiver = SecretKey::random();
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted, 1, 100)
+         .unwrap();
+   assert_eq!(20, recryption_keys.manifest().unwrap().chunk_count());
+
+   let decrypt = |keys: &DecryptionKeys| {
+      let mut decrypted: Vec<u8> = Vec::new();
+      decrypt_stream(Cursor::new(&encrypted), &mut decrypted, receiver.clone(), keys, 2)
+         .unwrap();
+      assert_eq!(test_data, decrypted);
+   };
+
+   // The translated keys come out in capsule order whatever the number of workers
+   for workers in [1, 3, 8] {
+      let mut progress = RecordedProgress::default();
+      let mut recryptor = Recryptor::builder()
+         .workers(workers)
+         .progress(&mut progress)
+         .build()
+         .unwrap();
+      assert_eq!(workers, recryptor.workers());
+      let decryption_keys = recryptor.recrypt(&recryption_keys, receiver.public_key()).unwrap();
+      drop(recryptor);
+      // Decrypting checks every translated key against its capsule
+      assert_eq!(recryption_keys.manifest(), decryption_keys.manifest());
+      decrypt(&decryption_keys);
+      assert_eq!(Some((20, 0)), progress.started);
+      assert_eq!((0..20).collect::<Vec<_>>(), progress.chunks);
+      assert_eq!(0, progress.bytes);
+      assert!(progress.finished);
+   }
+
+   // Streamed keys read back like encoded ones in either encoding
+   for encoding in [KeyEncoding::Json, KeyEncoding::Cbor] {
+      let mut progress = RecordedProgress::default();
+      let mut streamed: Vec<u8> = Vec::new();
+      Recryptor::builder()
+         .workers(4)
+         .progress(&mut progress)
+         .build()
+         .unwrap()
+         .recrypt_to(&recryption_keys, receiver.public_key(), &mut streamed, encoding)
+         .unwrap();
+      assert_eq!((0..20).collect::<Vec<_>>(), progress.chunks);
+      let decryption_keys: DecryptionKeys = decode_keys(&streamed).unwrap();
+      assert_eq!(recryption_keys.manifest(), decryption_keys.manifest());
+      assert_eq!(streamed, *encode_keys(&decryption_keys, encoding).unwrap());
+      decrypt(&decryption_keys);
+   }
+
+   // A proxy's share streams the same way
+   let proxy_keys =
+      split_recryption_keys(&recryption_keys, receiver.public_key(), 2, 3).unwrap();
+   let mut shares: Vec<DecryptionKeys> = Vec::new();
+   for proxy_keys in &proxy_keys[1..] {
+      let mut streamed: Vec<u8> = Vec::new();
+      Recryptor::builder()
+         .build()
+         .unwrap()
+         .proxy_recrypt_to(proxy_keys, &mut streamed, KeyEncoding::Cbor)
+         .unwrap();
+      shares.push(decode_keys(&streamed).unwrap());
+   }
+   decrypt(&combine_decryption_keys(&shares).unwrap());
+
+   let result = Recryptor::builder().workers(0).build();
+   assert!(matches!(result, Err(PrecryptError::InvalidConfig(_))));
+}
+
+#[test]
+fn test_batch_recrypt() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let mut encrypted: Vec<u8> = Vec::new();
+   let mut recryption_keys =
+      precrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted, 1, 200)
+         .unwrap();
+   let receivers: Vec<SecretKey> = (0..5).map(|_| SecretKey::random()).collect();
+   let receiver_publics: Vec<PublicKey> = receivers.iter().map(|r| r.public_key()).collect();
+
+   let decrypt = |receiver: &SecretKey, keys: &DecryptionKeys| {
+      let mut decrypted: Vec<u8> = Vec::new();
+      decrypt_stream(Cursor::new(&encrypted), &mut decrypted, receiver.clone(), keys, 2)
+         .map(|_| decrypted)
+   };
+
+   // Every receiver gets its own keys, in the order of the list
+   let batch = recrypt_keys_batch(&recryption_keys, &receiver_publics).unwrap();
+   assert_eq!(5, batch.len());
+   for (receiver, keys) in receivers.iter().zip(&batch) {
+      assert_eq!(test_data, decrypt(receiver, keys).unwrap());
+   }
+   assert!(decrypt(&receivers[0], &batch[1]).is_err());
+
+   let mut progress = RecordedProgress::default();
+   let mut granted: Vec<usize> = Vec::new();
+   Recryptor::builder()
+      .workers(3)
+      .progress(&mut progress)
+      .build()
+      .unwrap()
+      .recrypt_batch(&recryption_keys, &receiver_publics, |index, keys| {
+         assert_eq!(test_data, decrypt(&receivers[index], &keys).unwrap());
+         granted.push(index);
+         Ok(())
+      })
+      .unwrap();
+   assert_eq!((0..5).collect::<Vec<_>>(), granted);
+   assert_eq!(Some((5, 0)), progress.started);
+   assert_eq!((0..5).collect::<Vec<_>>(), progress.chunks);
+   assert!(progress.finished);
+
+   // The first error stops the batch
+   let mut recryptor = Recryptor::builder().workers(2).build().unwrap();
+   let result = recryptor.recrypt_batch(&recryption_keys, &receiver_publics, |index, _| {
+      match index {
+         2 => Err(PrecryptError::InvalidConfig("stop".to_string())),
+         _ => Ok(()),
+      }
+   });
+   assert!(matches!(result, Err(PrecryptError::InvalidConfig(_))));
+
+   assert!(recrypt_keys_batch(&recryption_keys, &[]).unwrap().is_empty());
+   recryption_keys.seal(b"passphrase").unwrap();
+   let result = recrypt_keys_batch(&recryption_keys, &receiver_publics);
+   assert!(matches!(result, Err(PrecryptError::PassphraseRequired)));
+}
+
+#[test]
+fn test_partial_grant() {
+   let test_data: Vec<u8> = (0..1050u32).map(|i| (i % 251) as u8).collect();
+   let input = temp_path("partial_secret.bin");
+   let encrypted = temp_path("partial_encrypted.bin");
+   let decrypted = temp_path("partial_decrypted.bin");
+   fs::write(&input, &test_data).unwrap();
+
+   let receiver = SecretKey::random();
+   let recryption_keys = Encryptor::builder()
+      .chunk_size(100)
+      .build()
+      .unwrap()
+      .encrypt_file(input.to_str().unwrap(), SecretKey::random(), encrypted.to_str().unwrap())
+      .unwrap();
+   let container = fs::read(&encrypted).unwrap();
+   let mut recryptor = Recryptor::builder().workers(2).build().unwrap();
+
+   // A byte range is granted as the chunks holding it
+   let chunks = recryption_keys.chunks_covering(250..420).unwrap();
+   assert_eq!(2..5, chunks);
+   // Keys whose chunks can't hold their overhead have no chunks to map bytes to
+   let mut malformed = serde_json::to_value(&recryption_keys).unwrap();
+   malformed["chunk_size"] = serde_json::json!(40);
+   let malformed: RecryptionKeys = serde_json::from_value(malformed).unwrap();
+   let result = malformed.chunks_covering(250..420);
+   assert!(matches!(result, Err(PrecryptError::InvalidConfig(_))));
+   let keys = recryptor.recrypt_range(&recryption_keys, receiver.public_key(), chunks).unwrap();
+   assert!(keys.is_partial());
+   assert_eq!(2, keys.first_chunk());
+   assert_eq!(Some(2..5), keys.granted_chunks());
+   let segment = &test_data[200..500];
+
+   // The owner signs the chunks granted, so they can't be changed
+   let mut widened = serde_json::to_value(&keys).unwrap();
+   widened["manifest"]["granted_chunks"]["start"] = serde_json::json!(0);
+   let widened: DecryptionKeys = serde_json::from_value(widened).unwrap();
+   let result = DecryptingReader::new(Cursor::new(&container), receiver.clone(), widened);
+   assert!(matches!(result, Err(PrecryptError::InvalidManifest(_))));
+
+   // From the whole container
+   Decryptor::builder()
+      .build()
+      .unwrap()
+      .decrypt_file(
+         encrypted.to_str().unwrap(),
+         decrypted.to_str().unwrap(),
+         receiver.clone(),
+         &keys,
+      )
+      .unwrap();
+   assert_eq!(segment, fs::read(&decrypted).unwrap());
+   let mut plaintext: Vec<u8> = Vec::new();
+   decrypt_stream(Cursor::new(&container), &mut plaintext, receiver.clone(), &keys, 2).unwrap();
+   assert_eq!(segment, plaintext);
+   let mut reader =
+      DecryptingReader::new(Cursor::new(&container), receiver.clone(), keys.clone()).unwrap();
+   assert_eq!(300, reader.plaintext_len());
+   let mut plaintext: Vec<u8> = Vec::new();
+   reader.read_to_end(&mut plaintext).unwrap();
+   assert_eq!(segment, plaintext);
+
+   // From just the bytes of the granted chunks
+   let range = keys.ciphertext_range().unwrap();
+   let download = &container[range.start as usize..range.end as usize];
+   let mut plaintext: Vec<u8> = Vec::new();
+   decrypt_stream(Cursor::new(download), &mut plaintext, receiver.clone(), &keys, 2).unwrap();
+   assert_eq!(segment, plaintext);
+   let mut reader = DecryptingReader::new(Cursor::new(download), receiver.clone(), keys).unwrap();
+   let mut plaintext: Vec<u8> = Vec::new();
+   reader.read_to_end(&mut plaintext).unwrap();
+   assert_eq!(segment, plaintext);
+
+   // Chunks other than the granted ones don't decrypt
+   let shifted = &container[range.start as usize + 140..];
+   let keys = recryptor.recrypt_range(&recryption_keys, receiver.public_key(), 2..5).unwrap();
+   let result = decrypt_stream(Cursor::new(shifted), Vec::new(), receiver.clone(), &keys, 2);
+   assert!(matches!(result, Err(PrecryptError::ChunkMismatch { index: 2 })));
+
+   // The range of the shorter last chunk runs past the end of the container
+   let keys = recryptor.recrypt_range(&recryption_keys, receiver.public_key(), 8..11).unwrap();
+   let range = keys.ciphertext_range().unwrap();
+   assert!(range.end > container.len() as u64);
+   let download = &container[range.start as usize..];
+   let mut plaintext: Vec<u8> = Vec::new();
+   decrypt_stream(Cursor::new(download), &mut plaintext, receiver.clone(), &keys, 2).unwrap();
+   assert_eq!(&test_data[800..], plaintext);
+
+   // Keys for the whole file are unaffected
+   let keys = recryptor.recrypt(&recryption_keys, receiver.public_key()).unwrap();
+   assert!(!keys.is_partial());
+   assert_eq!(None, keys.ciphertext_range());
+
+   for chunks in [3..3, 9..12] {
+      let result = recryptor.recrypt_range(&recryption_keys, receiver.public_key(), chunks);
+      assert!(matches!(result, Err(PrecryptError::InvalidConfig(_))));
+   }
+   let mut encrypted_stream: Vec<u8> = Vec::new();
+   let single_capsule = Encryptor::builder()
+      .mode(CapsuleMode::PerFile)
+      .build()
+      .unwrap()
+      .encrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted_stream)
+      .unwrap();
+   let result = recryptor.recrypt_range(&single_capsule, receiver.public_key(), 0..1);
+   assert!(matches!(result, Err(PrecryptError::InvalidConfig(_))));
+
+   for path in [input, encrypted, decrypted] {
+      fs::remove_file(path).unwrap();
+   }
+}
+
+#[test]
+fn test_builder() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let input = temp_path("builder_secret.bin");
+   let encrypted = temp_path("builder_encrypted.bin");
+   let decrypted = temp_path("builder_decrypted.bin");
+   fs::write(&input, &test_data).unwrap();
+
+   // Options that can't work are rejected before anything is read
+   for builder in [
+      Encryptor::builder().chunk_size(0),
+      Encryptor::builder().workers(0),
+      Encryptor::builder().chunk_size(100).memory_budget(99),
+   ] {
+      assert!(matches!(builder.build(), Err(PrecryptError::InvalidConfig(_))));
+   }
+   assert!(matches!(
+      Decryptor::builder().workers(0).build(),
+      Err(PrecryptError::InvalidConfig(_))
+   ));
+   let encryptor = Encryptor::builder().workers(8).chunk_size(100).memory_budget(250).build();
+   assert_eq!(2, encryptor.unwrap().workers());
+
+   // Memory no longer has to divide evenly between the threads
+   let receiver = SecretKey::random();
+   let recryption_keys = precrypt_file(
+      input.to_str().unwrap(),
+      SecretKey::random(),
+      encrypted.to_str().unwrap(),
+      3,
+      100,
+   )
+   .unwrap();
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+
+   // A chunk must fit in the decryption memory budget too
+   let result = Decryptor::builder().memory_budget(50).build().unwrap().decrypt_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver.clone(),
+      &decryption_keys,
+   );
+   assert!(matches!(result, Err(PrecryptError::InvalidConfig(_))));
+
+   let mut decryptor = Decryptor::builder()
+      .workers(3)
+      .memory_budget(200)
+      .overwrite(Overwrite::Refuse)
+      .build()
+      .unwrap();
+   decryptor
+      .decrypt_file(
+         encrypted.to_str().unwrap(),
+         decrypted.to_str().unwrap(),
+         receiver.clone(),
+         &decryption_keys,
+      )
+      .unwrap();
+   assert_eq!(test_data, fs::read(&decrypted).unwrap());
+
+   // An existing output is left alone unless it may be replaced
+   fs::write(&decrypted, "keep me").unwrap();
+   let result = decryptor.decrypt_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver,
+      &decryption_keys,
+   );
+   assert!(matches!(result, Err(PrecryptError::OutputExists(_))));
+   assert_eq!("keep me", fs::read_to_string(&decrypted).unwrap());
+
+   for path in [input, encrypted, decrypted] {
+      fs::remove_file(path).unwrap();
+   }
+}
+
+// Rewrites every base64 string as the number array older versions wrote
+fn to_legacy_json(value: serde_json::Value) -> serde_json::Value {
+   match value {
+      serde_json::Value::String(text) => serde_json::json!(base64::decode(text).unwrap()),
+      serde_json::Value::Array(items) => items.into_iter().map(to_legacy_json).collect(),
+      serde_json::Value::Object(fields) => serde_json::Value::Object(
+         fields.into_iter().map(|(name, field)| (name, to_legacy_json(field))).collect(),
+      ),
+      other => other,
+   }
+}
+
+#[test]
+fn test_key_encodings() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let receiver = SecretKey::random();
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted, 2, 128)
+         .unwrap();
+   let decryption_keys = recrypt_keys(recryption_keys.clone(), receiver.public_key()).unwrap();
+
+   let json = encode_keys(&decryption_keys, KeyEncoding::Json).unwrap();
+   let cbor = encode_keys(&decryption_keys, KeyEncoding::Cbor).unwrap();
+   let legacy = to_legacy_json(serde_json::from_slice(&json).unwrap());
+   let legacy = serde_json::to_vec(&legacy).unwrap();
+   // Byte strings are base64 in JSON and raw in CBOR
+   let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+   assert!(value["owner_pubkey"].is_string());
+   assert!(cbor.len() < json.len());
+   assert!(json.len() * 2 < legacy.len());
+
+   // Every encoding is detected and decrypts the same
+   for bytes in [&json, &cbor, &legacy] {
+      let keys: DecryptionKeys = decode_keys(bytes).unwrap();
+      assert_eq!(json, encode_keys(&keys, KeyEncoding::Json).unwrap());
+      let mut decrypted: Vec<u8> = Vec::new();
+      decrypt_stream(Cursor::new(&encrypted), &mut decrypted, receiver.clone(), &keys, 2)
+         .unwrap();
+      assert_eq!(test_data, decrypted);
+   }
+
+   // Recryption keys and their manifest round trip too
+   let cbor = encode_keys(&recryption_keys, KeyEncoding::Cbor).unwrap();
+   let decoded: RecryptionKeys = decode_keys(&cbor).unwrap();
+   assert_eq!(recryption_keys.manifest(), decoded.manifest());
+
+   let result = decode_keys::<DecryptionKeys>(b"not keys");
+   assert!(matches!(result, Err(PrecryptError::InvalidKeyEncoding(_))));
+}
+
+#[test]
+fn test_sealed_owner_secret() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let receiver = SecretKey::random();
+   let mut encrypted: Vec<u8> = Vec::new();
+   let mut recryption_keys =
+      precrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted, 2, 128)
+         .unwrap();
+   assert!(!recryption_keys.is_sealed());
+   recryption_keys.seal(b"correct horse").unwrap();
+   assert!(recryption_keys.is_sealed());
+
+   // The plaintext secret is no longer in the key file
+   let json = encode_keys(&recryption_keys, KeyEncoding::Json).unwrap();
+   let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+   assert!(value.get("owner_secret").is_none());
+   let sealed: RecryptionKeys = decode_keys(&json).unwrap();
+
+   let result = recrypt_keys(sealed.clone(), receiver.public_key());
+   assert!(matches!(result, Err(PrecryptError::PassphraseRequired)));
+   let result = recrypt_keys_with_passphrase(sealed.clone(), receiver.public_key(), b"wrong");
+   assert!(matches!(result, Err(PrecryptError::WrongPassphrase)));
+
+   let decryption_keys =
+      recrypt_keys_with_passphrase(sealed.clone(), receiver.public_key(), b"correct horse")
+         .unwrap();
+   let mut decrypted: Vec<u8> = Vec::new();
+   decrypt_stream(Cursor::new(&encrypted), &mut decrypted, receiver, &decryption_keys, 2)
+      .unwrap();
+   assert_eq!(test_data, decrypted);
+
+   let unlocked = sealed.unlock(b"correct horse").unwrap();
+   assert!(!unlocked.is_sealed());
+   assert!(unlocked.unlock(b"correct horse").is_err());
+}
+
+#[test]
+fn test_debug_redacts_secrets() {
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(b"secret"), SecretKey::random(), &mut encrypted, 1, 128)
+         .unwrap();
+   let proxy_keys =
+      split_recryption_keys(&recryption_keys, SecretKey::random().public_key(), 1, 1).unwrap();
+
+   let json = encode_keys(&recryption_keys, KeyEncoding::Json).unwrap();
+   let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+   let owner_secret = value["owner_secret"].as_str().unwrap();
+   let debug = format!("{:?}", recryption_keys);
+   assert!(debug.contains("[redacted]"));
+   assert!(!debug.contains(owner_secret));
+
+   let json = encode_keys(&proxy_keys[0], KeyEncoding::Json).unwrap();
+   let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+   let translation_key = value["translation_key"].as_str().unwrap();
+   let debug = format!("{:?}", proxy_keys[0]);
+   assert!(debug.contains("[redacted]"));
+   assert!(!debug.contains(translation_key));
+}
+
+#[test]
+fn test_archive() {
+   let input = temp_path("archive_input");
+   let encrypted = temp_path("archive_encrypted.bin");
+   let output = temp_path("archive_output");
+   let big: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   fs::create_dir_all(input.join("data/nested")).unwrap();
+   fs::create_dir_all(input.join("empty")).unwrap();
+   fs::write(input.join("readme.txt"), b"a dataset").unwrap();
+   fs::write(input.join("data/big.bin"), &big).unwrap();
+   fs::write(input.join("data/nested/empty.txt"), b"").unwrap();
+   #[cfg(unix)]
+   {
+      use std::os::unix::fs::PermissionsExt;
+      fs::set_permissions(input.join("readme.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+      fs::set_permissions(input.join("data/big.bin"), fs::Permissions::from_mode(0o4755)).unwrap();
+   }
+
+   let receiver = SecretKey::random();
+   let mut encryptor = Encryptor::builder().chunk_size(128).workers(2).build().unwrap();
+   let recryption_keys = encryptor
+      .encrypt_archive(input.to_str().unwrap(), SecretKey::random(), encrypted.to_str().unwrap())
+      .unwrap();
+   // One grant covers every file
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+
+   let mut archive =
+      Archive::open(encrypted.to_str().unwrap(), receiver.clone(), decryption_keys.clone())
+         .unwrap();
+   let paths: Vec<&str> = archive.entries().iter().map(|entry| entry.path.as_str()).collect();
+   assert_eq!(
+      vec!["data", "data/big.bin", "data/nested", "data/nested/empty.txt", "empty", "readme.txt"],
+      paths
+   );
+   let entry = &archive.entries()[1];
+   assert_eq!((1000, 0, 0, 8), (entry.size, entry.offset, entry.first_chunk, entry.chunk_count));
+   let entry = &archive.entries()[5];
+   assert_eq!((9, 1000, 7, 1), (entry.size, entry.offset, entry.first_chunk, entry.chunk_count));
+
+   // A single entry
+   let mut extracted = Vec::new();
+   archive.extract_entry("data/big.bin", &mut extracted).unwrap();
+   assert_eq!(big, extracted);
+   let result = archive.extract_entry("data", &mut extracted);
+   assert!(matches!(result, Err(PrecryptError::InvalidArchive(_))));
+
+   // Everything
+   archive.extract_all(output.to_str().unwrap(), Overwrite::Refuse).unwrap();
+   assert_eq!(big, fs::read(output.join("data/big.bin")).unwrap());
+   assert_eq!(b"a dataset".to_vec(), fs::read(output.join("readme.txt")).unwrap());
+   assert!(fs::read(output.join("data/nested/empty.txt")).unwrap().is_empty());
+   assert!(output.join("empty").is_dir());
+   #[cfg(unix)]
+   {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = fs::metadata(output.join("readme.txt")).unwrap().permissions().mode();
+      assert_eq!(0o600, mode & 0o777);
+      // No setuid bit
+      let mode = fs::metadata(output.join("data/big.bin")).unwrap().permissions().mode();
+      assert_eq!(0o755, mode & 0o7777);
+   }
+   let result = archive.extract_all(output.to_str().unwrap(), Overwrite::Refuse);
+   assert!(matches!(result, Err(PrecryptError::OutputExists(_))));
+
+   // A ciphertext of a single file is not an archive
+   let mut single: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(&big), SecretKey::random(), &mut single, 1, 128).unwrap();
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+   let result = Archive::new(Cursor::new(single), receiver, decryption_keys);
+   assert!(matches!(result, Err(PrecryptError::InvalidArchive(_))));
+
+   fs::remove_dir_all(input).unwrap();
+   fs::remove_dir_all(output).unwrap();
+   fs::remove_file(encrypted).unwrap();
+}
+
+#[test]
+fn test_encrypt_to_public_key() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let input = temp_path("public_secret.bin");
+   let encrypted = temp_path("public_encrypted.bin");
+   fs::write(&input, &test_data).unwrap();
+   let owner = SecretKey::random();
+   let receiver = SecretKey::random();
+
+   // The producer only knows the owner's public key
+   let mut encryptor = Encryptor::builder().chunk_size(128).workers(2).build().unwrap();
+   let manifest = encryptor
+      .encrypt_file_for(input.to_str().unwrap(), owner.public_key(), encrypted.to_str().unwrap())
+      .unwrap();
+   assert_eq!(8, manifest.chunk_count());
+   assert_eq!(&owner.public_key().to_array()[..], manifest.owner_pubkey());
+   let json = encode_keys(&manifest, KeyEncoding::Json).unwrap();
+   let manifest: CapsuleManifest = decode_keys(&json).unwrap();
+
+   // The owner grants access with their secret
+   let decryption_keys = recrypt_manifest(&manifest, &owner, receiver.public_key()).unwrap();
+   assert!(decryption_keys.manifest().is_some());
+   let decrypted = temp_path("public_decrypted.bin");
+   let mut decryptor = Decryptor::builder().build().unwrap();
+   decryptor
+      .decrypt_file(
+         encrypted.to_str().unwrap(),
+         decrypted.to_str().unwrap(),
+         receiver.clone(),
+         &decryption_keys,
+      )
+      .unwrap();
+   assert_eq!(test_data, fs::read(&decrypted).unwrap());
+
+   let result = recrypt_manifest(&manifest, &receiver, receiver.public_key());
+   assert!(matches!(result, Err(PrecryptError::InvalidManifest(_))));
+
+   // A stream with a single capsule, with the header built from the manifest
+   let mut encryptor =
+      Encryptor::builder().chunk_size(100).mode(CapsuleMode::PerFile).build().unwrap();
+   let mut chunks: Vec<u8> = Vec::new();
+   let manifest = encryptor
+      .encrypt_stream_for(Cursor::new(&test_data), owner.public_key(), &mut chunks)
+      .unwrap();
+   assert_eq!(CapsuleMode::PerFile, manifest.mode());
+   let mut ciphertext = Header::from(&manifest).to_bytes();
+   ciphertext.extend_from_slice(&chunks);
+   let recryption_keys = RecryptionKeys::from_manifest(&manifest, &owner).unwrap();
+   let decryption_keys = recrypt_keys(recryption_keys, receiver.public_key()).unwrap();
+   let mut decrypted_stream: Vec<u8> = Vec::new();
+   let keys = &decryption_keys;
+   decrypt_stream(Cursor::new(&ciphertext), &mut decrypted_stream, receiver, keys, 1).unwrap();
+   assert_eq!(test_data, decrypted_stream);
+
+   fs::remove_file(input).unwrap();
+   fs::remove_file(encrypted).unwrap();
+   fs::remove_file(decrypted).unwrap();
+}
+
+#[test]
+fn test_shared_decryption_keys() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let receiver = SecretKey::random();
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted, 1, 128)
+         .unwrap();
+   let decryption_keys =
+      Arc::new(recrypt_keys(recryption_keys, receiver.public_key()).unwrap());
+
+   // The same keys decrypt any number of times
+   for threads in [1, 3] {
+      let mut decrypted: Vec<u8> = Vec::new();
+      let keys = &decryption_keys;
+      decrypt_stream(Cursor::new(&encrypted), &mut decrypted, receiver.clone(), keys, threads)
+         .unwrap();
+      assert_eq!(test_data, decrypted);
+   }
+
+   // Ranges decrypted in parallel from one set of keys
+   let ranges = [(0, 300), (300, 650), (650, 1000)];
+   let parts: Vec<Vec<u8>> = std::thread::scope(|scope| {
+      let handles: Vec<_> = ranges
+         .iter()
+         .map(|&(start, end)| {
+            let keys = Arc::clone(&decryption_keys);
+            let (receiver, encrypted) = (receiver.clone(), &encrypted);
+            scope.spawn(move || {
+               let mut reader = DecryptingReader::new(Cursor::new(encrypted), receiver, keys)
+                  .unwrap();
+               let mut part = vec![0u8; end - start];
+               reader.seek(SeekFrom::Start(start as u64)).unwrap();
+               reader.read_exact(&mut part).unwrap();
+               part
+            })
+         })
+         .collect();
+      handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+   });
+   assert_eq!(test_data, parts.concat());
+}
+
+#[test]
+fn test_collection() {
+   let owner = SecretKey::random();
+   let receiver = SecretKey::random();
+   let files: Vec<Vec<u8>> =
+      (0..3u32).map(|n| (0..500 + n * 300).map(|i| ((i + n) % 251) as u8).collect()).collect();
+
+   // Two files encrypted with the owner secret and one deposited to the owner's public key
+   let mut collection = Collection::new(&owner);
+   let mut ciphertexts: Vec<Vec<u8>> = Vec::new();
+   let mut file_ids: Vec<String> = Vec::new();
+   // A capsule per chunk, and a single one for the deposited file
+   let mut capsules = 1;
+   for data in &files[..2] {
+      let mut encrypted: Vec<u8> = Vec::new();
+      let recryption_keys =
+         precrypt_stream(Cursor::new(data), owner.clone(), &mut encrypted, 1, 200).unwrap();
+      file_ids.push(collection.add(&recryption_keys).unwrap());
+      capsules += recryption_keys.manifest().unwrap().chunk_count();
+      ciphertexts.push(encrypted);
+   }
+   let mut encryptor =
+      Encryptor::builder().chunk_size(100).mode(CapsuleMode::PerFile).build().unwrap();
+   let mut chunks: Vec<u8> = Vec::new();
+   let manifest = encryptor
+      .encrypt_stream_for(Cursor::new(&files[2]), owner.public_key(), &mut chunks)
+      .unwrap();
+   let mut encrypted = Header::from(&manifest).to_bytes();
+   encrypted.extend_from_slice(&chunks);
+   file_ids.push(collection.add_manifest(&manifest).unwrap());
+   ciphertexts.push(encrypted);
+   assert_eq!(3, collection.len());
+   assert!(format!("{:?}", collection).contains("[redacted]"));
+
+   // Keys of another owner don't belong in the collection
+   let other_keys =
+      precrypt_stream(Cursor::new(&files[0]), SecretKey::random(), Vec::new(), 1, 200).unwrap();
+   assert!(matches!(collection.add(&other_keys), Err(PrecryptError::InvalidConfig(_))));
+
+   // A sealed collection is saved without the owner secret and only grants once unlocked
+   let mut sealed = collection.clone();
+   sealed.seal(b"collection passphrase").unwrap();
+   let json = encode_keys(&sealed, KeyEncoding::Json).unwrap();
+   let sealed: Collection = decode_keys(&json).unwrap();
+   assert!(sealed.is_sealed());
+   let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+   assert!(value.get("owner_secret").is_none());
+   let result = recrypt_collection(&sealed, receiver.public_key());
+   assert!(matches!(result, Err(PrecryptError::PassphraseRequired)));
+   assert!(matches!(sealed.unlock(b"wrong"), Err(PrecryptError::WrongPassphrase)));
+   let collection = sealed.unlock(b"collection passphrase").unwrap();
+   assert!(!collection.is_sealed());
+
+   let json = encode_keys(&collection, KeyEncoding::Json).unwrap();
+   let collection: Collection = decode_keys(&json).unwrap();
+   let mut sorted_ids = file_ids.clone();
+   sorted_ids.sort();
+   assert_eq!(sorted_ids, collection.file_ids().collect::<Vec<_>>());
+
+   // A single grant covers every file, the capsules of all of them counted as one run
+   let mut progress = RecordedProgress::default();
+   let collection_keys = Recryptor::builder()
+      .workers(3)
+      .progress(&mut progress)
+      .build()
+      .unwrap()
+      .recrypt_collection(&collection, receiver.public_key())
+      .unwrap();
+   assert_eq!(Some((capsules, 0)), progress.started);
+   assert_eq!((0..capsules).collect::<Vec<_>>(), progress.chunks);
+   assert!(progress.finished);
+
+   let cbor = encode_keys(&collection_keys, KeyEncoding::Cbor).unwrap();
+   let collection_keys: CollectionKeys = decode_keys(&cbor).unwrap();
+   assert_eq!(3, collection_keys.len());
+   for ((file_id, data), encrypted) in file_ids.iter().zip(&files).zip(&ciphertexts) {
+      let keys = collection_keys.file(file_id).unwrap();
+      let mut decrypted: Vec<u8> = Vec::new();
+      decrypt_stream(Cursor::new(encrypted), &mut decrypted, receiver.clone(), &keys, 2).unwrap();
+      assert_eq!(data, &decrypted);
+   }
+   assert!(collection_keys.file("missing").is_none());
+
+   // The keys of one file don't decrypt another, nor do they work for another receiver
+   let keys = collection_keys.file(&file_ids[0]).unwrap();
+   let result = decrypt_stream(Cursor::new(&ciphertexts[1]), Vec::new(), receiver, &keys, 1);
+   assert!(result.is_err());
+   let other_keys = recrypt_collection(&collection, SecretKey::random().public_key()).unwrap();
+   let keys = other_keys.file(&file_ids[0]).unwrap();
+   let result =
+      decrypt_stream(Cursor::new(&ciphertexts[0]), Vec::new(), SecretKey::random(), &keys, 1);
+   assert!(result.is_err());
+}
+
+#[test]
+fn test_append() {
+   let test_data: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+   let more_data: Vec<u8> = (0..1700u32).map(|i| (i % 241) as u8).collect();
+   let input = temp_path("append_secret.bin");
+   let more = temp_path("append_more.bin");
+   let encrypted = temp_path("append_encrypted.bin");
+   fs::write(&input, &test_data).unwrap();
+   fs::write(&more, &more_data).unwrap();
+   let receiver = SecretKey::random();
+
+   let mut encryptor = Encryptor::builder().chunk_size(1000).workers(2).build().unwrap();
+   let mut recryption_keys = encryptor
+      .encrypt_file(input.to_str().unwrap(), SecretKey::random(), encrypted.to_str().unwrap())
+      .unwrap();
+   let file_id = recryption_keys.manifest().unwrap().file_id().to_vec();
+   let old_keys = recrypt_keys(recryption_keys.clone(), receiver.public_key()).unwrap();
+   let old_container = fs::read(&encrypted).unwrap();
+   let old_body = Header::from(&recryption_keys).encoded_len();
+
+   // The last chunk isn't full, so it's padded and the new data starts at the next one
+   let mut progress = RecordedProgress::default();
+   let chunks = Encryptor::builder()
+      .workers(2)
+      .progress(&mut progress)
+      .build()
+      .unwrap()
+      .append_file(more.to_str().unwrap(), encrypted.to_str().unwrap(), &mut recryption_keys)
+      .unwrap();
+   assert_eq!(3..5, chunks);
+   assert_eq!(Some((5, 3)), progress.started);
+   assert_eq!(vec![3, 4], progress.chunks);
+   assert_eq!(file_id, recryption_keys.manifest().unwrap().file_id());
+   assert!(!fs::exists(temp_path("append_encrypted.bin.append")).unwrap());
+   let whole_data = [test_data.clone(), more_data.clone()].concat();
+   let container = fs::read(&encrypted).unwrap();
+   let body_start = Header::from(&recryption_keys).encoded_len();
+
+   let keys = recrypt_keys(recryption_keys.clone(), receiver.public_key()).unwrap();
+   let mut plaintext: Vec<u8> = Vec::new();
+   decrypt_stream(Cursor::new(&container), &mut plaintext, receiver.clone(), &keys, 2).unwrap();
+   assert_eq!(whole_data, plaintext);
+   let mut reader =
+      DecryptingReader::new(Cursor::new(&container), receiver.clone(), keys.clone()).unwrap();
+   assert_eq!(whole_data.len() as u64, reader.plaintext_len());
+   reader.seek(SeekFrom::Start(2400)).unwrap();
+   let mut read = vec![0u8; 300];
+   reader.read_exact(&mut read).unwrap();
+   assert_eq!(&whole_data[2400..2700], &read[..]);
+   assert_eq!(3..4, recryption_keys.chunks_covering(2500..2600).unwrap());
+   assert_eq!(2..5, recryption_keys.chunks_covering(2499..4200).unwrap());
+
+   // The chunks already there are untouched, so keys granted before still decrypt them
+   // from the new container, up to the padding of their last chunk
+   let old_chunks = &container[body_start..body_start + 2 * (1000 + 40) + 540];
+   assert_eq!(&old_container[old_body..], old_chunks);
+   let mut plaintext: Vec<u8> = Vec::new();
+   decrypt_stream(Cursor::new(&container), &mut plaintext, receiver.clone(), &old_keys, 1)
+      .unwrap();
+   assert_eq!(test_data, plaintext);
+   let decrypted = temp_path("append_decrypted.bin");
+   let decrypted_checkpoint = temp_path("append_decrypted.bin.checkpoint");
+   decrypt_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver.clone(),
+      &old_keys,
+      2,
+   )
+   .unwrap();
+   assert_eq!(test_data, fs::read(&decrypted).unwrap());
+   // Also when resuming after the checkpoint of the padded chunk
+   interrupt(|| {
+      let mut progress = InterruptingProgress { at_chunk: None };
+      let mut decryptor = Decryptor::builder().workers(3).progress(&mut progress).build().unwrap();
+      decryptor
+         .decrypt_file(
+            encrypted.to_str().unwrap(),
+            decrypted.to_str().unwrap(),
+            receiver.clone(),
+            &old_keys,
+         )
+         .unwrap();
+   });
+   assert!(decrypted_checkpoint.exists());
+   Decryptor::builder().build().unwrap().resume_file(
+      encrypted.to_str().unwrap(),
+      decrypted.to_str().unwrap(),
+      receiver.clone(),
+      &old_keys,
+   )
+   .unwrap();
+   assert_eq!(test_data, fs::read(&decrypted).unwrap());
+   fs::remove_file(decrypted).unwrap();
+   let mut reader =
+      DecryptingReader::new(Cursor::new(&container), receiver.clone(), old_keys.clone()).unwrap();
+   assert_eq!(test_data.len() as u64, reader.plaintext_len());
+   let mut plaintext: Vec<u8> = Vec::new();
+   reader.seek(SeekFrom::Start(1900)).unwrap();
+   reader.read_to_end(&mut plaintext).unwrap();
+   assert_eq!(&test_data[1900..], &plaintext[..]);
+
+   // Receivers with keys of the start only need the chunks written
+   let mut recryptor = Recryptor::builder().build().unwrap();
+   let new_keys = recryptor.recrypt_range(&recryption_keys, receiver.public_key(), chunks).unwrap();
+   let range = new_keys.ciphertext_range().unwrap();
+   let download = &container[range.start as usize..container.len().min(range.end as usize)];
+   let mut plaintext: Vec<u8> = Vec::new();
+   decrypt_stream(Cursor::new(download), &mut plaintext, receiver.clone(), &new_keys, 2).unwrap();
+   assert_eq!(more_data, plaintext);
+
+   // Appended chunks are checked like the others
+   let mut tampered = container.clone();
+   tampered[body_start + 3 * (1000 + 40) + 100] ^= 1;
+   let result = decrypt_stream(Cursor::new(&tampered), Vec::new(), receiver.clone(), &keys, 2);
+   assert!(matches!(result, Err(PrecryptError::ChunkMismatch { index: 3 })));
+   // The digest of the appended plaintext is signed with the rest of the manifest
+   let json = encode_keys(&keys, KeyEncoding::Json).unwrap();
+   let mut value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+   value["manifest"]["appended"][0]["plaintext_hash"] = value["manifest"]["plaintext_hash"].clone();
+   let forged: DecryptionKeys = decode_keys(&serde_json::to_vec(&value).unwrap()).unwrap();
+   let result = decrypt_stream(Cursor::new(&container), Vec::new(), receiver.clone(), &forged, 2);
+   assert!(matches!(result, Err(PrecryptError::InvalidManifest(_))));
+
+   // Streams hand over the last chunk and get its padding and the new chunks
+   let last_chunk = &container[body_start + 4 * (1000 + 40)..];
+   let mut appended: Vec<u8> = Vec::new();
+   let chunks = encryptor
+      .append_stream(last_chunk, Cursor::new(&test_data), &mut appended, &mut recryption_keys)
+      .unwrap();
+   assert_eq!(5..8, chunks);
+   assert_eq!(&appended[..300], &[0u8; 300][..]);
+   let mut ciphertext = Header::from(&recryption_keys).to_bytes();
+   ciphertext.extend_from_slice(&container[body_start..]);
+   ciphertext.extend_from_slice(&appended);
+   let keys = recrypt_keys(recryption_keys.clone(), receiver.public_key()).unwrap();
+   let mut plaintext: Vec<u8> = Vec::new();
+   decrypt_stream(Cursor::new(&ciphertext), &mut plaintext, receiver, &keys, 2).unwrap();
+   assert_eq!([whole_data, test_data.clone()].concat(), plaintext);
+
+   // The last chunk must be the one the owner signed
+   let mut keys_copy = recryption_keys.clone();
+   let result =
+      encryptor.append_stream(last_chunk, Cursor::new(&test_data), Vec::new(), &mut keys_copy);
+   assert!(matches!(result, Err(PrecryptError::ChunkMismatch { index: 7 })));
+
+   // A file with a single capsule can't be appended to
+   let mut single_keys = Encryptor::builder()
+      .mode(CapsuleMode::PerFile)
+      .build()
+      .unwrap()
+      .encrypt_stream(Cursor::new(&test_data), SecretKey::random(), Vec::new())
+      .unwrap();
+   let result =
+      encryptor.append_stream(&[], Cursor::new(&test_data), Vec::new(), &mut single_keys);
+   assert!(matches!(result, Err(PrecryptError::InvalidConfig(_))));
+
+   fs::remove_file(input).unwrap();
+   fs::remove_file(more).unwrap();
+   fs::remove_file(encrypted).unwrap();
+}
@@ -0,0 +1,114 @@
+// Run with `wasm-pack test --node --features wasm -- --test wasm`
+#![cfg(all(target_arch = "wasm32", feature = "wasm"))]
+
+use precrypt::wasm::{keygen, public_key, StreamDecryptor};
+use precrypt::{
+   encode_keys, precrypt_stream, recrypt_keys, Encryptor, Header, KeyEncoding, RecryptionKeys,
+};
+use std::io::Cursor;
+use umbral_pre::*;
+use wasm_bindgen_test::*;
+
+fn receiver() -> (Vec<u8>, PublicKey) {
+   let keypair: serde_json::Value = serde_json::from_str(&keygen()).unwrap();
+   let secret_key: Vec<u8> = serde_json::from_value(keypair["secret_key"].clone()).unwrap();
+   let public = PublicKey::from_bytes(public_key(&secret_key).unwrap()).unwrap();
+   (secret_key, public)
+}
+
+fn keys_json(recryption_keys: RecryptionKeys, receiver_public: PublicKey) -> String {
+   let decryption_keys = recrypt_keys(recryption_keys, receiver_public).unwrap();
+   String::from_utf8(encode_keys(&decryption_keys, KeyEncoding::Json).unwrap().to_vec()).unwrap()
+}
+
+// Feeds `ciphertext` in pieces of `piece` bytes
+fn decrypt(decryptor: &mut StreamDecryptor, ciphertext: &[u8], piece: usize) -> Vec<u8> {
+   let mut plaintext = Vec::new();
+   for bytes in ciphertext.chunks(piece) {
+      plaintext.extend(decryptor.push(bytes).unwrap().to_vec());
+   }
+   plaintext.extend(decryptor.finish().unwrap().to_vec());
+   plaintext
+}
+
+#[wasm_bindgen_test]
+fn test_stream_decryptor() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let (secret_key, public) = receiver();
+
+   // A container with its header, fed in pieces that don't line up with the chunks
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted, 1, 128)
+         .unwrap();
+   let mut ciphertext = Header::from(&recryption_keys).to_bytes();
+   ciphertext.extend_from_slice(&encrypted);
+   let keys = keys_json(recryption_keys.clone(), public);
+   for piece in [1, 7, 128, ciphertext.len()] {
+      let mut decryptor = StreamDecryptor::new(&keys, &secret_key).unwrap();
+      // Encrypted chunks are larger than the plaintext chunks
+      assert_eq!(128 + 40, decryptor.chunk_size());
+      assert_eq!(test_data, decrypt(&mut decryptor, &ciphertext, piece));
+   }
+
+   // Keys from before an append decrypt what was there then
+   let mut appended_keys = recryption_keys;
+   let last_chunk = &encrypted[7 * (128 + 40)..];
+   let mut appended: Vec<u8> = Vec::new();
+   Encryptor::builder()
+      .build()
+      .unwrap()
+      .append_stream(last_chunk, Cursor::new(&test_data), &mut appended, &mut appended_keys)
+      .unwrap();
+   let mut ciphertext = Header::from(&appended_keys).to_bytes();
+   ciphertext.extend_from_slice(&encrypted);
+   ciphertext.extend_from_slice(&appended);
+   for piece in [7, 168, ciphertext.len()] {
+      let mut decryptor = StreamDecryptor::new(&keys, &secret_key).unwrap();
+      assert_eq!(test_data, decrypt(&mut decryptor, &ciphertext, piece));
+   }
+
+   // A file with a single capsule, without a header
+   let mut encryptor = Encryptor::builder()
+      .chunk_size(100)
+      .mode(precrypt::CapsuleMode::PerFile)
+      .build()
+      .unwrap();
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys = encryptor
+      .encrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted)
+      .unwrap();
+   let keys = keys_json(recryption_keys, public);
+   let mut decryptor = StreamDecryptor::new(&keys, &secret_key).unwrap();
+   assert_eq!(test_data, decrypt(&mut decryptor, &encrypted, 33));
+}
+
+#[wasm_bindgen_test]
+fn test_stream_decryptor_errors() {
+   let test_data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+   let (secret_key, public) = receiver();
+   let mut encrypted: Vec<u8> = Vec::new();
+   let recryption_keys =
+      precrypt_stream(Cursor::new(&test_data), SecretKey::random(), &mut encrypted, 1, 128)
+         .unwrap();
+   let keys = keys_json(recryption_keys, public);
+
+   // A modified chunk is caught before its plaintext is returned
+   let mut tampered = encrypted.clone();
+   tampered[10] ^= 1;
+   let mut decryptor = StreamDecryptor::new(&keys, &secret_key).unwrap();
+   assert!(decryptor.push(&tampered).is_err());
+
+   // A missing final chunk is caught by finish
+   let mut decryptor = StreamDecryptor::new(&keys, &secret_key).unwrap();
+   decryptor.push(&encrypted[..decryptor.chunk_size() * 7]).unwrap();
+   assert!(decryptor.finish().is_err());
+   assert!(decryptor.finish().is_err());
+
+   // Another receiver's key can't decrypt
+   let (other_key, _) = receiver();
+   let result = StreamDecryptor::new(&keys, &other_key)
+      .and_then(|mut decryptor| decryptor.push(&encrypted));
+   assert!(result.is_err());
+   assert!(StreamDecryptor::new("not keys", &secret_key).is_err());
+}